@@ -1,30 +1,104 @@
+use k8s_openapi::api::core::v1::{ConfigMap, Secret};
 use kube::api::{Patch, PatchParams};
 use kube::{Api, Client};
 use log::debug;
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use crate::models::application::Application;
-use crate::models::assignment::ApplicationAssignment;
+use crate::models::assignment::{
+    ApplicationAssignment, ApplicationAssignmentStatus, CONDITION_PUSHED, CONDITION_READY,
+    CONDITION_RENDERED,
+};
+use crate::models::cluster::Cluster;
 use crate::models::environment::ApplicationEnvironment;
 use crate::models::template::ApplicationTemplate;
+use crate::models::values::{ValuesFromKind, ValuesFromSource};
 
+use crate::utils::config::Config;
 use crate::utils::error::Error;
+use crate::workflows::apply::ApplyWorkflow;
+use crate::workflows::argocd::ArgoCdLinker;
+use crate::workflows::cache::RepoCache;
+use crate::workflows::encryption::Encryption;
+use crate::workflows::flux::FluxLinker;
 use crate::workflows::gitops::GitopsWorkflow;
+use crate::workflows::review::{BranchChangeRequestProvider, PendingReview};
+use crate::workflows::template::{add_values_from, ResolvedValues, DEFAULT_VALUES_KEY};
+use crate::workflows::workflow::{
+    Deployment, DeploymentResult, DeploymentStatus, Workflow, DEFAULT_RECONCILER,
+};
+
+/// Finalizer that keeps an `ApplicationAssignment` around until its rendered output has been removed.
+pub const FINALIZER: &str = "application-assignment.microsoft.com";
 
 pub struct ApplicationAssignmentController {
     client: Client,
-    workflow: GitopsWorkflow,
+    /// Workflow backends, keyed by the name applications refer to them with in `reconciler`.
+    workflows: HashMap<String, Box<dyn Workflow>>,
 }
 
 impl ApplicationAssignmentController {
-    pub fn new(client: Client) -> Self {
-        // TODO: need mechanism to configure downstream cluster gitops repo
-        let workflow =
-            GitopsWorkflow::new("git@github.com:timfpark/workload-cluster-gitops").unwrap();
-
-        ApplicationAssignmentController {
-            client: client.clone(),
-            workflow,
+    pub fn new(client: Client, config: &Config) -> Result<Self, Error> {
+        let cache = Arc::new(RepoCache::new(
+            PathBuf::from(&config.cache.path),
+            config.cache.max_entries,
+        )?);
+
+        let encryption = Arc::new(Encryption::from_config(&config.encryption)?);
+
+        let mut workflows: HashMap<String, Box<dyn Workflow>> = HashMap::new();
+        workflows.insert(
+            "gitops".to_string(),
+            Box::new(GitopsWorkflow::new(
+                &config.gitops,
+                cache.clone(),
+                Box::new(BranchChangeRequestProvider::new()),
+                Box::new(FluxLinker::new(&config.encryption)),
+                encryption.clone(),
+                &config.validation,
+            )?),
+        );
+        workflows.insert(
+            "argocd".to_string(),
+            Box::new(GitopsWorkflow::new(
+                &config.gitops,
+                cache.clone(),
+                Box::new(BranchChangeRequestProvider::new()),
+                Box::new(ArgoCdLinker),
+                encryption,
+                &config.validation,
+            )?),
+        );
+        workflows.insert(
+            "apply".to_string(),
+            Box::new(ApplyWorkflow::new(
+                client.clone(),
+                cache,
+                &config.validation,
+            )),
+        );
+
+        Ok(ApplicationAssignmentController { client, workflows })
+    }
+
+    /// Returns the workflow backend named by an `Application`'s `reconciler`.
+    fn workflow(&self, application: &Application) -> Result<&dyn Workflow, Error> {
+        let reconciler = application
+            .spec
+            .reconciler
+            .as_deref()
+            .unwrap_or(DEFAULT_RECONCILER);
+
+        match self.workflows.get(reconciler) {
+            Some(workflow) => Ok(workflow.as_ref()),
+            None => Err(Error::UserInputError(format!(
+                "Application {} uses unknown reconciler '{}'",
+                application.metadata.name.as_deref().unwrap_or_default(),
+                reconciler
+            ))),
         }
     }
 
@@ -47,7 +121,7 @@ impl ApplicationAssignmentController {
         let api: Api<ApplicationAssignment> = Api::namespaced(self.client.clone(), namespace);
         let finalizer: Value = json!({
             "metadata": {
-                "finalizers": ["application-assignment.microsoft.com"]
+                "finalizers": [FINALIZER]
             }
         });
 
@@ -55,18 +129,81 @@ impl ApplicationAssignmentController {
         Ok(api.patch(name, &PatchParams::default(), &patch).await?)
     }
 
-    /// Deploy the Application on the Cluster specified by the ApplicationAssignment.
+    /// Deploy the Application on the Cluster specified by the ApplicationAssignment, with the
+    /// workflow backend chosen by the Application.
     ///
     /// # Arguments
-    /// - `client` - A Kubernetes client to create the deployment with.
-    /// - `name` - Name of the deployment to be created
-    /// - `replicas` - Number of pod replicas for the Deployment to contain
-    /// - `namespace` - Namespace to create the Kubernetes Deployment in.
-    ///
-    /// Note: It is assumed the resource does not already exists for simplicity. Returns an `Error` if it does.
-    pub async fn create_deployment(&self, name: &str, namespace: &str) -> Result<(), Error> {
+    /// - `name` - Name of the `ApplicationAssignment` to deploy.
+    /// - `namespace` - Namespace the `ApplicationAssignment` and the resources it refers to reside in.
+    pub async fn create_deployment(
+        &self,
+        name: &str,
+        namespace: &str,
+    ) -> Result<DeploymentResult, Error> {
         debug!("Application create_deployment");
 
+        let mut deployment = self.get_deployment(name, namespace).await?;
+        let workflow = self.workflow(&deployment.application)?;
+
+        deployment.resolved_values = self.resolve_values(&deployment, namespace).await?;
+
+        let deployed_before = deployment
+            .assignment
+            .status
+            .as_ref()
+            .and_then(|status| status.template_commit.as_ref())
+            .is_some();
+
+        if deployed_before {
+            workflow.update(&deployment).await
+        } else {
+            workflow.create(&deployment).await
+        }
+    }
+
+    /// Deletes an existing deployment.
+    ///
+    /// # Arguments:
+    /// - `name` - Name of the `ApplicationAssignment` whose deployment to delete.
+    /// - `namespace` - Namespace the `ApplicationAssignment` and the resources it refers to reside in.
+    ///
+    /// Returns the pending review of the removal when the GitOps target requires review.
+    pub async fn delete_deployment(
+        &self,
+        name: &str,
+        namespace: &str,
+    ) -> Result<Option<PendingReview>, Error> {
+        debug!("Application delete_deployment");
+
+        let deployment = self.get_deployment(name, namespace).await?;
+
+        self.workflow(&deployment.application)?
+            .delete(&deployment)
+            .await
+    }
+
+    /// Checks with the workflow backend whether a deployment is still in place.
+    ///
+    /// # Arguments:
+    /// - `name` - Name of the `ApplicationAssignment` whose deployment to check.
+    /// - `namespace` - Namespace the `ApplicationAssignment` and the resources it refers to reside in.
+    pub async fn deployment_status(
+        &self,
+        name: &str,
+        namespace: &str,
+    ) -> Result<DeploymentStatus, Error> {
+        debug!("Application deployment_status");
+
+        let deployment = self.get_deployment(name, namespace).await?;
+
+        self.workflow(&deployment.application)?
+            .status(&deployment)
+            .await
+    }
+
+    /// Fetches an `ApplicationAssignment` along with the environment, application, template and
+    /// cluster it is deployed from.
+    async fn get_deployment(&self, name: &str, namespace: &str) -> Result<Deployment, Error> {
         let application_api: Api<Application> = Api::namespaced(self.client.clone(), namespace);
         let application_assignment_api: Api<ApplicationAssignment> =
             Api::namespaced(self.client.clone(), namespace);
@@ -75,74 +212,185 @@ impl ApplicationAssignmentController {
         let application_template_api: Api<ApplicationTemplate> =
             Api::namespaced(self.client.clone(), namespace);
 
-        let application_assignment = application_assignment_api.get(name).await?;
-        debug!("{:?}", application_assignment);
-
-        let application_environment = application_environment_api
-            .get(&application_assignment.spec.environment)
-            .await?;
-
-        debug!("{:?}", application_environment);
+        let assignment = application_assignment_api.get(name).await?;
+        debug!("{:?}", assignment);
 
-        let application = application_api
-            .get(&application_environment.spec.application)
+        let environment = application_environment_api
+            .get(&assignment.spec.environment)
             .await?;
+        debug!("{:?}", environment);
 
+        let application = application_api.get(&environment.spec.application).await?;
         debug!("{:?}", application);
 
-        let application_template = application_template_api
+        let template = application_template_api
             .get(&application.spec.template)
             .await?;
+        debug!("{:?}", template);
 
-        debug!("{:?}", application);
-
-        let _oid = self.workflow.create_deployment(
-            &application,
-            &application_template,
-            &application_environment,
-            &application_assignment,
-        )?;
+        let cluster = self
+            .get_cluster(&assignment.spec.cluster, namespace)
+            .await?;
 
-        Ok(())
+        Ok(Deployment {
+            application,
+            template,
+            environment,
+            assignment,
+            cluster,
+            resolved_values: ResolvedValues::default(),
+        })
     }
 
-    /// Deletes an existing deployment.
+    /// Resolves the `valuesFrom` ConfigMaps and Secrets of a deployment's application,
+    /// environment and assignment.
     ///
     /// # Arguments:
-    /// - `client` - A Kubernetes client to delete the Deployment with
-    /// - `name` - Name of the deployment to delete
-    /// - `namespace` - Namespace the existing deployment resides in
-    ///
-    /// Note: It is assumed the deployment exists for simplicity. Otherwise returns an Error.
-    pub async fn delete_deployment(&self, name: &str, namespace: &str) -> Result<(), Error> {
-        debug!("Application delete_deployment");
-
-        let application_api: Api<Application> = Api::namespaced(self.client.clone(), namespace);
+    /// - `deployment` - The deployment to resolve values for.
+    /// - `namespace` - Namespace the ConfigMaps and Secrets reside in.
+    async fn resolve_values(
+        &self,
+        deployment: &Deployment,
+        namespace: &str,
+    ) -> Result<ResolvedValues, Error> {
+        let mut secrets = Vec::new();
+
+        let application = self
+            .values_from(
+                &deployment.application.spec.values_from,
+                namespace,
+                &mut secrets,
+            )
+            .await?;
+        let environment = self
+            .values_from(
+                &deployment.environment.spec.values_from,
+                namespace,
+                &mut secrets,
+            )
+            .await?;
+        let assignment = self
+            .values_from(
+                &deployment.assignment.spec.values_from,
+                namespace,
+                &mut secrets,
+            )
+            .await?;
 
-        let application_assignment_api: Api<ApplicationAssignment> =
-            Api::namespaced(self.client.clone(), namespace);
+        Ok(ResolvedValues {
+            application,
+            environment,
+            assignment,
+            secrets,
+        })
+    }
 
-        let application_environment_api: Api<ApplicationEnvironment> =
-            Api::namespaced(self.client.clone(), namespace);
+    /// Merges the data of `sources`, in order, into one map of values. Values taken from Secrets
+    /// are added to `secrets`.
+    async fn values_from(
+        &self,
+        sources: &Option<Vec<ValuesFromSource>>,
+        namespace: &str,
+        secrets: &mut Vec<Value>,
+    ) -> Result<Map<String, Value>, Error> {
+        let mut values = Map::new();
+
+        for source in sources.iter().flatten() {
+            let key = source.values_key.as_deref().unwrap_or(DEFAULT_VALUES_KEY);
+
+            match self.get_values_data(source, key, namespace).await? {
+                Some(data) => add_values_from(&mut values, source, &data, secrets)?,
+                None if source.optional => {
+                    debug!("skipping optional {:?} {}", source.kind, source.name)
+                }
+                None => {
+                    return Err(Error::UserInputError(format!(
+                        "{:?} {} with key {} does not exist",
+                        source.kind, source.name, key
+                    )))
+                }
+            }
+        }
 
-        let application_assignment = application_assignment_api.get(name).await?;
-        debug!("{:?}", application_assignment);
+        Ok(values)
+    }
 
-        let application_environment = application_environment_api
-            .get(&application_assignment.spec.environment)
-            .await?;
+    /// Fetches the data under `key` of the ConfigMap or Secret a `valuesFrom` source refers to, or
+    /// `None` if either doesn't exist.
+    async fn get_values_data(
+        &self,
+        source: &ValuesFromSource,
+        key: &str,
+        namespace: &str,
+    ) -> Result<Option<String>, Error> {
+        match source.kind {
+            ValuesFromKind::ConfigMap => {
+                let config_map_api: Api<ConfigMap> =
+                    Api::namespaced(self.client.clone(), namespace);
+
+                Ok(get_optional(config_map_api.get(&source.name).await)?
+                    .and_then(|config_map| config_map.data)
+                    .and_then(|mut data| data.remove(key)))
+            }
+            ValuesFromKind::Secret => {
+                let secret_api: Api<Secret> = Api::namespaced(self.client.clone(), namespace);
+
+                let data = get_optional(secret_api.get(&source.name).await)?
+                    .and_then(|secret| secret.data)
+                    .and_then(|mut data| data.remove(key));
+
+                match data {
+                    Some(data) => String::from_utf8(data.0).map(Some).map_err(|_| {
+                        Error::UserInputError(format!(
+                            "key {} of Secret {} is not UTF-8 text",
+                            key, source.name
+                        ))
+                    }),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
 
-        debug!("{:?}", application_environment);
+    /// Fetches the `Cluster` resource an `ApplicationAssignment` is assigned to. Templates can't be
+    /// rendered without it, but the output of an assignment whose `Cluster` was removed can still
+    /// be deleted from the default GitOps target.
+    ///
+    /// # Arguments:
+    /// - `name` - Name of the `Cluster` resource, as given by the assignment's `spec.cluster`.
+    /// - `namespace` - Namespace where the `Cluster` resource resides.
+    async fn get_cluster(&self, name: &str, namespace: &str) -> Result<Option<Cluster>, Error> {
+        let cluster_api: Api<Cluster> = Api::namespaced(self.client.clone(), namespace);
+
+        let cluster = get_optional(cluster_api.get(name).await)?;
+        if cluster.is_none() {
+            debug!("no Cluster resource for {}", name);
+        }
 
-        let application = application_api
-            .get(&application_environment.spec.application)
-            .await?;
+        Ok(cluster)
+    }
 
-        debug!("{:?}", application);
+    /// Replaces the status of an `ApplicationAssignment` through its status subresource.
+    ///
+    /// # Arguments:
+    /// - `name` - Name of the `ApplicationAssignment` resource to update.
+    /// - `namespace` - Namespace where the `ApplicationAssignment` resource with given `name` resides.
+    /// - `status` - The new status.
+    pub async fn patch_status(
+        &self,
+        name: &str,
+        namespace: &str,
+        status: &ApplicationAssignmentStatus,
+    ) -> Result<ApplicationAssignment, Error> {
+        debug!("Application patch_status");
 
-        self.workflow.delete_deployment(&application_assignment)?;
+        let api: Api<ApplicationAssignment> = Api::namespaced(self.client.clone(), namespace);
+        let status: Value = json!({ "status": status });
 
-        Ok(())
+        let patch: Patch<&Value> = Patch::Merge(&status);
+        Ok(api
+            .patch_status(name, &PatchParams::default(), &patch)
+            .await?)
     }
 
     /// Removes all finalizers from an `ApplicationAssignment` resource. If there are no finalizers already, this
@@ -172,3 +420,133 @@ impl ApplicationAssignmentController {
         Ok(api.patch(name, &PatchParams::default(), &patch).await?)
     }
 }
+
+/// Maps the result of getting a resource to `None` if it doesn't exist.
+fn get_optional<K>(result: Result<K, kube::Error>) -> Result<Option<K>, Error> {
+    match result {
+        Ok(resource) => Ok(Some(resource)),
+        Err(kube::Error::Api(response)) if response.code == 404 => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Builds the status of an `ApplicationAssignment` that was successfully deployed.
+///
+/// # Arguments
+/// - `application_assignment` - The assignment that was deployed.
+/// - `deployment` - Commits produced by the workflow.
+/// - `dependencies` - Generations of the resources the assignment was rendered from, if known.
+pub fn deployed_status(
+    application_assignment: &ApplicationAssignment,
+    deployment: &DeploymentResult,
+    dependencies: Option<BTreeMap<String, i64>>,
+) -> ApplicationAssignmentStatus {
+    let mut status = application_assignment.status.clone().unwrap_or_default();
+
+    status.observed_generation = application_assignment.metadata.generation;
+    status.observed_dependencies = dependencies;
+    status.template_commit = Some(deployment.template_commit.to_string());
+    status.gitops_commit = deployment.commit.map(|commit| commit.to_string());
+    status.inventory = deployment.inventory.clone();
+    status.review_branch = deployment
+        .review
+        .as_ref()
+        .map(|review| review.branch.clone());
+    status.change_request = deployment
+        .review
+        .as_ref()
+        .map(|review| review.change_request.clone());
+    status.last_error = None;
+
+    let message = format!(
+        "Rendered from template commit {}",
+        deployment.template_commit
+    );
+    status.set_condition(CONDITION_RENDERED, true, "Rendered", &message);
+
+    match (deployment.commit, &deployment.review) {
+        (Some(commit), Some(review)) => {
+            let message = format!(
+                "Pushed GitOps commit {} to {} for review: {}",
+                commit, review.branch, review.change_request
+            );
+            status.set_condition(CONDITION_PUSHED, true, "PendingReview", &message);
+            status.set_condition(CONDITION_READY, false, "PendingReview", &message);
+        }
+        (Some(commit), None) if deployment.changed => {
+            let message = format!("Pushed GitOps commit {}", commit);
+            status.set_condition(CONDITION_PUSHED, true, "Pushed", &message);
+            status.set_condition(CONDITION_READY, true, "Deployed", &message);
+        }
+        (Some(commit), None) => {
+            let message = format!("GitOps commit {} is up to date", commit);
+            status.set_condition(CONDITION_PUSHED, true, "Unchanged", &message);
+            status.set_condition(CONDITION_READY, true, "Deployed", &message);
+        }
+        // backends without a GitOps repo apply the rendered objects to the cluster themselves
+        (None, _) => {
+            let applied = deployment.inventory.as_ref().map_or(0, Vec::len);
+            let message = format!("Applied {} objects to the cluster", applied);
+            status.set_condition(CONDITION_PUSHED, true, "Applied", &message);
+            status.set_condition(CONDITION_READY, true, "Deployed", &message);
+        }
+    }
+
+    status
+}
+
+/// Builds the status of a deployed `ApplicationAssignment` whose deployment has since gone missing,
+/// so that it is deployed again.
+///
+/// # Arguments
+/// - `application_assignment` - The assignment whose deployment is missing.
+pub fn missing_status(
+    application_assignment: &ApplicationAssignment,
+) -> ApplicationAssignmentStatus {
+    let mut status = application_assignment.status.clone().unwrap_or_default();
+
+    status.set_condition(
+        CONDITION_READY,
+        false,
+        "DeploymentMissing",
+        "Deployed output is missing and will be deployed again",
+    );
+
+    status
+}
+
+/// Builds the status of an `ApplicationAssignment` whose deployment failed with `error`.
+///
+/// # Arguments
+/// - `application_assignment` - The assignment that failed to deploy.
+/// - `error` - The error the deployment failed with.
+pub fn failed_status(
+    application_assignment: &ApplicationAssignment,
+    error: &Error,
+) -> ApplicationAssignmentStatus {
+    let mut status = application_assignment.status.clone().unwrap_or_default();
+    let message = error.to_string();
+
+    status.observed_generation = application_assignment.metadata.generation;
+    status.last_error = Some(message.clone());
+
+    match error {
+        // push errors are the only ones raised after rendering succeeded
+        Error::PushRejected { .. } | Error::PushRetriesExhausted { .. } => {
+            status.set_condition(CONDITION_RENDERED, true, "Rendered", "");
+            status.set_condition(CONDITION_PUSHED, false, "PushRejected", &message);
+        }
+        Error::PushError { .. } => {
+            status.set_condition(CONDITION_RENDERED, true, "Rendered", "");
+            status.set_condition(CONDITION_PUSHED, false, "PushFailed", &message);
+        }
+        _ => {
+            status.set_condition(CONDITION_RENDERED, false, "RenderFailed", &message);
+            status.set_condition(CONDITION_PUSHED, false, "NotRendered", "");
+        }
+    }
+
+    status.set_condition(CONDITION_READY, false, "DeploymentFailed", &message);
+
+    status
+}
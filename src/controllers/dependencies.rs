@@ -0,0 +1,310 @@
+use futures::stream::StreamExt;
+use kube::{api::ListParams, Api, Client, Resource, ResourceExt};
+use kube_runtime::reflector::{reflector, store::Writer, ObjectRef, Store};
+use kube_runtime::watcher;
+use log::error;
+use std::collections::BTreeMap;
+
+use crate::models::application::Application;
+use crate::models::assignment::ApplicationAssignment;
+use crate::models::cluster::Cluster;
+use crate::models::environment::ApplicationEnvironment;
+use crate::models::template::ApplicationTemplate;
+
+/// Caches of the resources an `ApplicationAssignment` is rendered from, following the
+/// assignment -> environment -> application -> template chain, and the `Cluster` whose labels and
+/// facts it is rendered with. Used to map a change to any of them back to the assignments that
+/// need to be re-rendered.
+#[derive(Clone)]
+pub struct Dependencies {
+    pub applications: Store<Application>,
+    pub environments: Store<ApplicationEnvironment>,
+    pub templates: Store<ApplicationTemplate>,
+    pub clusters: Store<Cluster>,
+}
+
+impl Dependencies {
+    /// Starts reflectors for `Application`, `ApplicationEnvironment`, `ApplicationTemplate` and
+    /// `Cluster` resources in the background and returns their stores.
+    ///
+    /// # Arguments
+    /// - `client` - Kubernetes client to watch the resources with.
+    pub fn watch(client: Client) -> Self {
+        Dependencies {
+            applications: spawn_reflector(Api::all(client.clone())),
+            environments: spawn_reflector(Api::all(client.clone())),
+            templates: spawn_reflector(Api::all(client.clone())),
+            clusters: spawn_reflector(Api::all(client)),
+        }
+    }
+
+    /// Returns the generation of each resource `application_assignment` is rendered from, keyed by
+    /// `Kind/name`, or `None` if any of them is not (yet) known.
+    pub fn generations(
+        &self,
+        application_assignment: &ApplicationAssignment,
+    ) -> Option<BTreeMap<String, i64>> {
+        let namespace = application_assignment.namespace()?;
+
+        let environment = self
+            .environments
+            .get(&ObjectRef::new(&application_assignment.spec.environment).within(&namespace))?;
+        let application = self
+            .applications
+            .get(&ObjectRef::new(&environment.spec.application).within(&namespace))?;
+        let template = self
+            .templates
+            .get(&ObjectRef::new(&application.spec.template).within(&namespace))?;
+        let cluster = self
+            .clusters
+            .get(&ObjectRef::new(&application_assignment.spec.cluster).within(&namespace))?;
+
+        let mut generations = BTreeMap::new();
+        generations.insert(dependency_key(&environment), environment.meta().generation?);
+        generations.insert(dependency_key(&application), application.meta().generation?);
+        generations.insert(dependency_key(&template), template.meta().generation?);
+        generations.insert(dependency_key(&cluster), cluster.meta().generation?);
+
+        Some(generations)
+    }
+
+    /// Maps a changed `Cluster` to the assignments deployed to it.
+    pub fn assignments_for_cluster(
+        &self,
+        cluster: &Cluster,
+        assignments: &Store<ApplicationAssignment>,
+    ) -> Vec<ObjectRef<ApplicationAssignment>> {
+        assignments_for_cluster(cluster, &assignments.state())
+    }
+
+    /// Maps a changed `ApplicationEnvironment` to the assignments that deploy it.
+    pub fn assignments_for_environment(
+        &self,
+        environment: &ApplicationEnvironment,
+        assignments: &Store<ApplicationAssignment>,
+    ) -> Vec<ObjectRef<ApplicationAssignment>> {
+        assignments_for_environments(&[environment], &assignments.state())
+    }
+
+    /// Maps a changed `Application` to the assignments of all of its environments.
+    pub fn assignments_for_application(
+        &self,
+        application: &Application,
+        assignments: &Store<ApplicationAssignment>,
+    ) -> Vec<ObjectRef<ApplicationAssignment>> {
+        let environments = self.environments.state();
+        let environments = environments_for_applications(&[application], &environments);
+
+        assignments_for_environments(&environments, &assignments.state())
+    }
+
+    /// Maps a changed `ApplicationTemplate` to the assignments of every application rendered from it.
+    pub fn assignments_for_template(
+        &self,
+        template: &ApplicationTemplate,
+        assignments: &Store<ApplicationAssignment>,
+    ) -> Vec<ObjectRef<ApplicationAssignment>> {
+        let applications = self.applications.state();
+        let applications: Vec<&Application> = applications
+            .iter()
+            .filter(|application| {
+                application.namespace() == template.namespace()
+                    && application.spec.template == template.name()
+            })
+            .collect();
+
+        let environments = self.environments.state();
+        let environments = environments_for_applications(&applications, &environments);
+
+        assignments_for_environments(&environments, &assignments.state())
+    }
+}
+
+/// Key of a resource in `Dependencies::generations`.
+fn dependency_key<K: Resource<DynamicType = ()>>(resource: &K) -> String {
+    format!("{}/{}", K::kind(&()), resource.name())
+}
+
+fn environments_for_applications<'a>(
+    applications: &[&Application],
+    environments: &'a [ApplicationEnvironment],
+) -> Vec<&'a ApplicationEnvironment> {
+    environments
+        .iter()
+        .filter(|environment| {
+            applications.iter().any(|application| {
+                application.namespace() == environment.namespace()
+                    && application.name() == environment.spec.application
+            })
+        })
+        .collect()
+}
+
+fn assignments_for_environments(
+    environments: &[&ApplicationEnvironment],
+    assignments: &[ApplicationAssignment],
+) -> Vec<ObjectRef<ApplicationAssignment>> {
+    assignments
+        .iter()
+        .filter(|assignment| {
+            environments.iter().any(|environment| {
+                environment.namespace() == assignment.namespace()
+                    && environment.name() == assignment.spec.environment
+            })
+        })
+        .map(ObjectRef::from_obj)
+        .collect()
+}
+
+fn assignments_for_cluster(
+    cluster: &Cluster,
+    assignments: &[ApplicationAssignment],
+) -> Vec<ObjectRef<ApplicationAssignment>> {
+    assignments
+        .iter()
+        .filter(|assignment| {
+            cluster.namespace() == assignment.namespace()
+                && cluster.name() == assignment.spec.cluster
+        })
+        .map(ObjectRef::from_obj)
+        .collect()
+}
+
+fn spawn_reflector<K>(api: Api<K>) -> Store<K>
+where
+    K: Resource<DynamicType = ()> + Clone + serde::de::DeserializeOwned + std::fmt::Debug,
+    K: Send + Sync + 'static,
+{
+    let writer = Writer::<K>::default();
+    let store = writer.as_reader();
+
+    tokio::spawn(
+        reflector(writer, watcher(api, ListParams::default())).for_each(|event| async move {
+            if let Err(err) = event {
+                error!("Dependency watch error: {:?}", err);
+            }
+        }),
+    );
+
+    store
+}
+
+#[cfg(test)]
+mod tests {
+    use kube_runtime::reflector::ObjectRef;
+    use serde_json::Map;
+    use std::collections::HashMap;
+
+    use crate::models::application::{Application, ApplicationSpec};
+    use crate::models::assignment::{ApplicationAssignment, ApplicationAssignmentSpec};
+    use crate::models::cluster::{Cluster, ClusterSpec};
+    use crate::models::environment::{ApplicationEnvironment, ApplicationEnvironmentSpec};
+
+    use super::{
+        assignments_for_cluster, assignments_for_environments, environments_for_applications,
+    };
+
+    fn application(name: &str, template: &str) -> Application {
+        let mut application = Application::new(
+            name,
+            ApplicationSpec {
+                template: template.to_string(),
+                values: None,
+                values_from: None,
+                reconciler: None,
+                flux: None,
+                argocd: None,
+            },
+        );
+        application.metadata.namespace = Some("default".to_string());
+        application
+    }
+
+    fn environment(name: &str, application: &str) -> ApplicationEnvironment {
+        let mut environment = ApplicationEnvironment::new(
+            name,
+            ApplicationEnvironmentSpec {
+                application: application.to_string(),
+                environment: name.to_string(),
+                selector: None,
+                clusters: None,
+                spread_by: None,
+                values: None,
+                values_from: None,
+                flux: None,
+                argocd: None,
+            },
+        );
+        environment.metadata.namespace = Some("default".to_string());
+        environment
+    }
+
+    fn assignment(name: &str, environment: &str) -> ApplicationAssignment {
+        let mut assignment = ApplicationAssignment::new(
+            name,
+            ApplicationAssignmentSpec {
+                cluster: name.split('-').next().unwrap().to_string(),
+                environment: environment.to_string(),
+                values: Some(Map::new()),
+                values_from: None,
+            },
+        );
+        assignment.metadata.namespace = Some("default".to_string());
+        assignment
+    }
+
+    #[test]
+    fn maps_application_to_dependent_assignments_only() {
+        let agent = application("cluster-agent", "external-service");
+        let environments = vec![
+            environment("cluster-agent-dev", "cluster-agent"),
+            environment("cluster-agent-prod", "cluster-agent"),
+            environment("other-dev", "other"),
+        ];
+        let assignments = vec![
+            assignment("eastus2-cluster-agent-dev", "cluster-agent-dev"),
+            assignment("westus2-cluster-agent-prod", "cluster-agent-prod"),
+            assignment("eastus2-other-dev", "other-dev"),
+        ];
+
+        let environments = environments_for_applications(&[&agent], &environments);
+        let affected = assignments_for_environments(&environments, &assignments);
+
+        assert_eq!(
+            affected,
+            vec![
+                ObjectRef::new("eastus2-cluster-agent-dev").within("default"),
+                ObjectRef::new("westus2-cluster-agent-prod").within("default"),
+            ]
+        );
+    }
+
+    #[test]
+    fn maps_cluster_to_its_assignments() {
+        let mut cluster = Cluster::new(
+            "eastus2",
+            ClusterSpec {
+                name: "eastus2".to_string(),
+                labels: HashMap::new(),
+                environments: vec![],
+                facts: None,
+                gitops: None,
+                kubeconfig: None,
+            },
+        );
+        cluster.metadata.namespace = Some("default".to_string());
+        let assignments = vec![
+            assignment("eastus2-cluster-agent-dev", "cluster-agent-dev"),
+            assignment("westus2-cluster-agent-prod", "cluster-agent-prod"),
+            assignment("eastus2-other-dev", "other-dev"),
+        ];
+
+        assert_eq!(
+            assignments_for_cluster(&cluster, &assignments),
+            vec![
+                ObjectRef::new("eastus2-cluster-agent-dev").within("default"),
+                ObjectRef::new("eastus2-other-dev").within("default"),
+            ]
+        );
+    }
+}
@@ -1 +1,3 @@
 pub mod assignment;
+pub mod dependencies;
+pub mod scheduler;
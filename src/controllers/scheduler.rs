@@ -0,0 +1,481 @@
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+use kube::api::{DeleteParams, ListParams, PostParams};
+use kube::{Api, Client, Resource, ResourceExt};
+use kube_runtime::reflector::ObjectRef;
+use log::debug;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::models::assignment::{ApplicationAssignment, ApplicationAssignmentSpec};
+use crate::models::cluster::Cluster;
+use crate::models::environment::{ApplicationEnvironment, ClustersSpec};
+use crate::utils::error::Error;
+
+/// Label of the assignments created for an `ApplicationEnvironment`, naming the environment.
+/// Assignments without it are written by hand and never touched by the scheduler.
+pub const ENVIRONMENT_LABEL: &str = "application-api.microsoft.com/environment";
+
+/// Assignments to create and delete to bring an environment onto the clusters it is scheduled to.
+#[derive(Debug, PartialEq, Default)]
+pub struct Schedule {
+    /// Clusters the environment is to be assigned to, and is not yet.
+    pub create: Vec<String>,
+    /// Names of the assignments to clusters the environment is no longer scheduled to.
+    pub delete: Vec<String>,
+}
+
+/// Materializes `ApplicationAssignment`s for `ApplicationEnvironment`s with a cluster `selector`.
+pub struct EnvironmentScheduler {
+    client: Client,
+}
+
+impl EnvironmentScheduler {
+    pub fn new(client: Client) -> Self {
+        EnvironmentScheduler { client }
+    }
+
+    /// Creates and deletes the assignments of an environment, so that it is assigned to the
+    /// clusters its `selector` and `clusters` schedule it to.
+    ///
+    /// # Arguments
+    /// - `environment` - The `ApplicationEnvironment` to schedule.
+    pub async fn schedule_environment(
+        &self,
+        environment: &ApplicationEnvironment,
+    ) -> Result<Schedule, Error> {
+        let namespace = environment.namespace().ok_or_else(|| {
+            Error::UserInputError(format!(
+                "ApplicationEnvironment {} is expected to be namespaced",
+                environment.name()
+            ))
+        })?;
+
+        let cluster_api: Api<Cluster> = Api::namespaced(self.client.clone(), &namespace);
+        let assignment_api: Api<ApplicationAssignment> =
+            Api::namespaced(self.client.clone(), &namespace);
+
+        let clusters = cluster_api.list(&ListParams::default()).await?.items;
+        let assignments = assignment_api
+            .list(&ListParams::default().labels(&format!(
+                "{}={}",
+                ENVIRONMENT_LABEL,
+                environment.name()
+            )))
+            .await?
+            .items;
+
+        let schedule = schedule(environment, &clusters, &assignments);
+        let mut conflicts = Vec::new();
+
+        for cluster in schedule.create.iter() {
+            debug!("assigning {} to Cluster {}", environment.name(), cluster);
+
+            let assignment = assignment(environment, cluster);
+            match assignment_api
+                .create(&PostParams::default(), &assignment)
+                .await
+            {
+                Ok(_) => {}
+                Err(kube::Error::Api(response)) if response.code == 409 => {
+                    // created by a previous reconcile that the list didn't see yet, unless an
+                    // assignment of the same name was written by hand
+                    let existing = assignment_api.get(&assignment.name()).await?;
+                    if !is_scheduled_by(&existing, environment) {
+                        conflicts.push(existing.name());
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        for name in schedule.delete.iter() {
+            debug!("unassigning {} with {}", environment.name(), name);
+
+            match assignment_api.delete(name, &DeleteParams::default()).await {
+                Ok(_) => {}
+                Err(kube::Error::Api(response)) if response.code == 404 => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        if !conflicts.is_empty() {
+            return Err(Error::UserInputError(format!(
+                "ApplicationEnvironment {} can't be assigned, ApplicationAssignments {} exist without the {} label",
+                environment.name(),
+                conflicts.join(", "),
+                ENVIRONMENT_LABEL
+            )));
+        }
+
+        Ok(schedule)
+    }
+}
+
+/// Computes the assignments to create and delete for an environment.
+///
+/// With `clusters: all` the environment is assigned to every matching cluster. With a count, the
+/// clusters it is already assigned to are kept where possible, and the rest are picked so that
+/// the number of assignments per value of the `spreadBy` label differs by at most one. When
+/// clusters join or leave, assignments are moved until that holds again.
+///
+/// # Arguments
+/// - `environment` - The `ApplicationEnvironment` to schedule.
+/// - `clusters` - Clusters in the namespace of the environment.
+/// - `assignments` - Assignments previously created for the environment.
+pub fn schedule(
+    environment: &ApplicationEnvironment,
+    clusters: &[Cluster],
+    assignments: &[ApplicationAssignment],
+) -> Schedule {
+    let matching: Vec<&Cluster> = clusters
+        .iter()
+        .filter(|cluster| {
+            cluster.meta().deletion_timestamp.is_none()
+                && matches_selector(environment.spec.selector.as_ref(), cluster)
+        })
+        .collect();
+
+    let assigned: BTreeSet<&str> = assignments
+        .iter()
+        .filter(|assignment| assignment.meta().deletion_timestamp.is_none())
+        .map(|assignment| assignment.spec.cluster.as_str())
+        .collect();
+
+    let desired: BTreeSet<String> = match environment.spec.clusters {
+        Some(ClustersSpec::Count(count)) => pick_spread(
+            &matching,
+            &assigned,
+            environment.spec.spread_by.as_deref(),
+            count as usize,
+        ),
+        Some(ClustersSpec::All) | None => matching.iter().map(|cluster| cluster.name()).collect(),
+    };
+
+    let mut schedule = Schedule::default();
+    for cluster in desired.iter() {
+        if !assigned.contains(cluster.as_str()) {
+            schedule.create.push(cluster.clone());
+        }
+    }
+    for assignment in assignments {
+        if assignment.meta().deletion_timestamp.is_none()
+            && !desired.contains(&assignment.spec.cluster)
+        {
+            schedule.delete.push(assignment.name());
+        }
+    }
+
+    schedule
+}
+
+/// Whether a cluster has every label of the selector. Without a selector, an environment isn't
+/// scheduled to any cluster.
+fn matches_selector(selector: Option<&HashMap<String, String>>, cluster: &Cluster) -> bool {
+    match selector {
+        Some(selector) => selector
+            .iter()
+            .all(|(label, value)| cluster.spec.labels.get(label) == Some(value)),
+        None => false,
+    }
+}
+
+/// Picks `count` of the `matching` clusters, keeping those in `assigned` where the spread allows.
+fn pick_spread(
+    matching: &[&Cluster],
+    assigned: &BTreeSet<&str>,
+    spread_by: Option<&str>,
+    count: usize,
+) -> BTreeSet<String> {
+    // clusters by the value of their spread label, each sorted by name
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for cluster in matching {
+        let group = spread_by
+            .and_then(|label| cluster.spec.labels.get(label))
+            .cloned()
+            .unwrap_or_default();
+        groups.entry(group).or_default().push(cluster.name());
+    }
+    for clusters in groups.values_mut() {
+        clusters.sort();
+    }
+
+    let mut picked: BTreeMap<String, Vec<String>> = groups
+        .iter()
+        .map(|(group, clusters)| {
+            let kept = clusters
+                .iter()
+                .filter(|cluster| assigned.contains(cluster.as_str()))
+                .cloned()
+                .collect();
+            (group.clone(), kept)
+        })
+        .collect();
+
+    let total =
+        |picked: &BTreeMap<String, Vec<String>>| picked.values().map(Vec::len).sum::<usize>();
+
+    // the most assigned group, preferring the last one, gives up clusters first
+    let fullest = |picked: &BTreeMap<String, Vec<String>>| {
+        picked
+            .iter()
+            .filter(|(_, clusters)| !clusters.is_empty())
+            .max_by_key(|(_, clusters)| clusters.len())
+            .map(|(group, _)| group.clone())
+    };
+    // the least assigned group with a cluster left, preferring the first one, takes clusters first
+    let emptiest = |picked: &BTreeMap<String, Vec<String>>| {
+        picked
+            .iter()
+            .filter(|(group, clusters)| clusters.len() < groups[group.as_str()].len())
+            .min_by_key(|(_, clusters)| clusters.len())
+            .map(|(group, _)| group.clone())
+    };
+    let unpicked = |picked: &BTreeMap<String, Vec<String>>, group: &str| {
+        groups[group]
+            .iter()
+            .find(|cluster| !picked[group].contains(cluster))
+            .cloned()
+            .unwrap()
+    };
+
+    while total(&picked) > count {
+        let group = fullest(&picked).unwrap();
+        picked.get_mut(&group).unwrap().pop();
+    }
+
+    while total(&picked) < count {
+        let group = match emptiest(&picked) {
+            Some(group) => group,
+            None => break,
+        };
+        let cluster = unpicked(&picked, &group);
+        picked.get_mut(&group).unwrap().push(cluster);
+    }
+
+    // move clusters, one at a time, until no group has two more than another that could take one
+    while let (Some(from), Some(to)) = (fullest(&picked), emptiest(&picked)) {
+        if picked[&from].len() < picked[&to].len() + 2 {
+            break;
+        }
+        picked.get_mut(&from).unwrap().pop();
+        let cluster = unpicked(&picked, &to);
+        picked.get_mut(&to).unwrap().push(cluster);
+    }
+
+    picked.into_values().flatten().collect()
+}
+
+/// The assignment of an environment to a cluster, owned by the environment so that it is
+/// removed along with it.
+fn assignment(environment: &ApplicationEnvironment, cluster: &str) -> ApplicationAssignment {
+    let mut assignment = ApplicationAssignment::new(
+        &format!("{}-{}", cluster, environment.name()),
+        ApplicationAssignmentSpec {
+            cluster: cluster.to_string(),
+            environment: environment.name(),
+            values: None,
+            values_from: None,
+        },
+    );
+
+    assignment.metadata.namespace = environment.namespace();
+    assignment.metadata.labels = Some(
+        vec![(ENVIRONMENT_LABEL.to_string(), environment.name())]
+            .into_iter()
+            .collect(),
+    );
+    assignment.metadata.owner_references = Some(vec![OwnerReference {
+        api_version: ApplicationEnvironment::api_version(&()).to_string(),
+        kind: ApplicationEnvironment::kind(&()).to_string(),
+        name: environment.name(),
+        uid: environment.meta().uid.clone().unwrap_or_default(),
+        controller: Some(true),
+        block_owner_deletion: Some(true),
+    }]);
+
+    assignment
+}
+
+/// Whether an assignment was created by the scheduler for `environment`.
+fn is_scheduled_by(
+    assignment: &ApplicationAssignment,
+    environment: &ApplicationEnvironment,
+) -> bool {
+    assignment.labels().get(ENVIRONMENT_LABEL) == Some(&environment.name())
+}
+
+/// Maps a changed `Cluster` to the scheduled environments of its namespace, which may have to
+/// be assigned to it, or moved off it.
+pub fn environments_for_cluster(
+    cluster: &Cluster,
+    environments: &[ApplicationEnvironment],
+) -> Vec<ObjectRef<ApplicationEnvironment>> {
+    environments
+        .iter()
+        .filter(|environment| {
+            environment.namespace() == cluster.namespace() && environment.spec.selector.is_some()
+        })
+        .map(ObjectRef::from_obj)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::models::assignment::{ApplicationAssignment, ApplicationAssignmentSpec};
+    use crate::models::cluster::{Cluster, ClusterSpec};
+    use crate::models::environment::{
+        ApplicationEnvironment, ApplicationEnvironmentSpec, ClustersSpec,
+    };
+
+    use super::{assignment, is_scheduled_by, schedule, Schedule};
+
+    fn environment(
+        clusters: Option<ClustersSpec>,
+        spread_by: Option<&str>,
+    ) -> ApplicationEnvironment {
+        let mut selector = HashMap::new();
+        selector.insert("cloud".to_string(), "azure".to_string());
+
+        let mut environment = ApplicationEnvironment::new(
+            "cluster-agent-dev",
+            ApplicationEnvironmentSpec {
+                application: "cluster-agent".to_string(),
+                environment: "dev".to_string(),
+                selector: Some(selector),
+                clusters,
+                spread_by: spread_by.map(str::to_string),
+                values: None,
+                values_from: None,
+                flux: None,
+                argocd: None,
+            },
+        );
+        environment.metadata.namespace = Some("default".to_string());
+        environment.metadata.uid = Some("uid".to_string());
+        environment
+    }
+
+    fn cluster(name: &str, cloud: &str, region: &str) -> Cluster {
+        let mut labels = HashMap::new();
+        labels.insert("cloud".to_string(), cloud.to_string());
+        labels.insert("region".to_string(), region.to_string());
+
+        Cluster::new(
+            name,
+            ClusterSpec {
+                name: name.to_string(),
+                labels,
+                environments: vec![],
+                facts: None,
+                gitops: None,
+                kubeconfig: None,
+            },
+        )
+    }
+
+    fn assigned(
+        environment: &ApplicationEnvironment,
+        clusters: &[&str],
+    ) -> Vec<ApplicationAssignment> {
+        clusters
+            .iter()
+            .map(|cluster| assignment(environment, cluster))
+            .collect()
+    }
+
+    fn schedule_of(create: &[&str], delete: &[&str]) -> Schedule {
+        Schedule {
+            create: create.iter().map(|name| name.to_string()).collect(),
+            delete: delete.iter().map(|name| name.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn assigns_all_matching_clusters() {
+        let environment = environment(None, None);
+        let clusters = vec![
+            cluster("azure-eastus2-1", "azure", "eastus2"),
+            cluster("azure-westus2-1", "azure", "westus2"),
+            cluster("aws-useast-1", "aws", "us-east-1"),
+        ];
+
+        assert_eq!(
+            schedule(&environment, &clusters, &[]),
+            schedule_of(&["azure-eastus2-1", "azure-westus2-1"], &[])
+        );
+
+        // a cluster that stops matching is unassigned
+        let assignments = assigned(&environment, &["azure-eastus2-1", "aws-useast-1"]);
+        assert_eq!(
+            schedule(&environment, &clusters, &assignments),
+            schedule_of(&["azure-westus2-1"], &["aws-useast-1-cluster-agent-dev"])
+        );
+
+        let assignment = &assignments[0];
+        assert_eq!(
+            assignment.metadata.name.as_deref(),
+            Some("azure-eastus2-1-cluster-agent-dev")
+        );
+        assert_eq!(
+            assignment.spec,
+            ApplicationAssignmentSpec {
+                cluster: "azure-eastus2-1".to_string(),
+                environment: "cluster-agent-dev".to_string(),
+                values: None,
+                values_from: None,
+            }
+        );
+        assert_eq!(
+            assignment.metadata.owner_references.as_ref().unwrap()[0].name,
+            "cluster-agent-dev"
+        );
+    }
+
+    #[test]
+    fn recognizes_assignments_written_by_hand() {
+        let environment = environment(None, None);
+        let mut assignment = assignment(&environment, "azure-eastus2-1");
+        assert!(is_scheduled_by(&assignment, &environment));
+
+        assignment.metadata.labels = None;
+        assert!(!is_scheduled_by(&assignment, &environment));
+    }
+
+    #[test]
+    fn spreads_count_of_clusters_by_label() {
+        let environment = environment(Some(ClustersSpec::Count(2)), Some("region"));
+        let mut clusters = vec![
+            cluster("azure-eastus2-1", "azure", "eastus2"),
+            cluster("azure-eastus2-2", "azure", "eastus2"),
+            cluster("azure-westus2-1", "azure", "westus2"),
+        ];
+
+        assert_eq!(
+            schedule(&environment, &clusters, &[]),
+            schedule_of(&["azure-eastus2-1", "azure-westus2-1"], &[])
+        );
+
+        // assignments are kept as long as the spread allows
+        let assignments = assigned(&environment, &["azure-eastus2-2", "azure-westus2-1"]);
+        assert_eq!(
+            schedule(&environment, &clusters, &assignments),
+            schedule_of(&[], &[])
+        );
+
+        // both in one region are rebalanced once another region joins
+        let assignments = assigned(&environment, &["azure-eastus2-1", "azure-eastus2-2"]);
+        assert_eq!(
+            schedule(&environment, &clusters, &assignments),
+            schedule_of(&["azure-westus2-1"], &["azure-eastus2-2-cluster-agent-dev"])
+        );
+
+        // a cluster that leaves is replaced
+        clusters.remove(2);
+        let assignments = assigned(&environment, &["azure-eastus2-1", "azure-westus2-1"]);
+        assert_eq!(
+            schedule(&environment, &clusters, &assignments),
+            schedule_of(&["azure-eastus2-2"], &["azure-westus2-1-cluster-agent-dev"])
+        );
+    }
+}
@@ -1,11 +1,15 @@
-use controllers::assignment::ApplicationAssignmentController;
+use controllers::assignment::{
+    deployed_status, failed_status, missing_status, ApplicationAssignmentController, FINALIZER,
+};
+use controllers::dependencies::Dependencies;
+use controllers::scheduler::{environments_for_cluster, EnvironmentScheduler};
 use futures::stream::StreamExt;
 use kube::Resource;
 use kube::ResourceExt;
 use kube::{api::ListParams, client::Client, Api};
 use kube_runtime::controller::{Context, ReconcilerAction};
 use kube_runtime::Controller;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use tokio::time::Duration;
 
 mod controllers;
@@ -13,13 +17,30 @@ mod models;
 mod utils;
 mod workflows;
 
-use models::assignment::ApplicationAssignment;
+use models::application::Application;
+use models::assignment::{ApplicationAssignment, CONDITION_READY};
+use models::cluster::Cluster;
+use models::environment::ApplicationEnvironment;
+use models::template::ApplicationTemplate;
+use std::collections::BTreeMap;
+use utils::config::Config;
 use utils::error::Error;
+use workflows::workflow::DeploymentStatus;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
+    // Configuration is validated before anything else so that a misconfigured operator fails fast
+    // with a clear message instead of erroring on every reconciliation.
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
     println!("starting");
 
     // First, a Kubernetes client must be obtained using the `kube` crate
@@ -30,7 +51,48 @@ async fn main() {
 
     // Preparation of resources used by the `kube_runtime::Controller`
     let assignment_api: Api<ApplicationAssignment> = Api::all(kubernetes_client.clone());
-    let context: Context<ContextData> = Context::new(ContextData::new(kubernetes_client.clone()));
+    let application_api: Api<Application> = Api::all(kubernetes_client.clone());
+    let environment_api: Api<ApplicationEnvironment> = Api::all(kubernetes_client.clone());
+    let template_api: Api<ApplicationTemplate> = Api::all(kubernetes_client.clone());
+    let cluster_api: Api<Cluster> = Api::all(kubernetes_client.clone());
+
+    let dependencies = Dependencies::watch(kubernetes_client.clone());
+
+    let context_data =
+        match ContextData::new(kubernetes_client.clone(), &config, dependencies.clone()) {
+            Ok(context_data) => context_data,
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        };
+    let context: Context<ContextData> = Context::new(context_data);
+
+    // Environments with a cluster selector are scheduled by a second controller, which creates and
+    // deletes their assignments as the environments change and as clusters join, leave or are
+    // relabeled. Assignments it creates are owned by their environment, so changes to them, such
+    // as being deleted by hand, schedule the environment again.
+    let scheduler = Controller::new(environment_api.clone(), ListParams::default());
+    let scheduled_environments = scheduler.store();
+    let scheduler_context = Context::new(EnvironmentScheduler::new(kubernetes_client.clone()));
+
+    tokio::spawn(
+        scheduler
+            .owns(assignment_api.clone(), ListParams::default())
+            .watches(cluster_api.clone(), ListParams::default(), move |cluster| {
+                environments_for_cluster(&cluster, &scheduled_environments.state())
+            })
+            .run(
+                reconcile_environment,
+                on_environment_error,
+                scheduler_context,
+            )
+            .for_each(|scheduling_result| async move {
+                if let Err(scheduling_err) = scheduling_result {
+                    error!("Scheduling error: {:?}", scheduling_err)
+                }
+            }),
+    );
 
     // The controller comes from the `kube_runtime` crate and manages the reconciliation process.
     // It requires the following information:
@@ -38,7 +100,34 @@ async fn main() {
     // - `kube::api::ListParams` to select the `ApplicationAssignment` resources with. Can be used for ApplicationAssignment filtering `ApplicationAssignment` resources before reconciliation,
     // - `reconcile` function with reconciliation logic to be called each time a resource of `ApplicationAssignment` kind is created/updated/deleted,
     // - `on_error` function to call whenever reconciliation fails.
-    Controller::new(assignment_api.clone(), ListParams::default())
+    // Changes to the `ApplicationEnvironment`, `Application` and `ApplicationTemplate` an assignment is
+    // rendered from, and to the `Cluster` it is deployed to, are mapped back to the affected
+    // assignments, so that they are re-rendered as well.
+    let controller = Controller::new(assignment_api.clone(), ListParams::default());
+    let assignments = controller.store();
+
+    let (environment_dependencies, environment_assignments) =
+        (dependencies.clone(), assignments.clone());
+    let (application_dependencies, application_assignments) =
+        (dependencies.clone(), assignments.clone());
+    let (template_dependencies, template_assignments) = (dependencies.clone(), assignments.clone());
+    let (cluster_dependencies, cluster_assignments) = (dependencies, assignments);
+
+    controller
+        .watches(environment_api, ListParams::default(), move |environment| {
+            environment_dependencies
+                .assignments_for_environment(&environment, &environment_assignments)
+        })
+        .watches(application_api, ListParams::default(), move |application| {
+            application_dependencies
+                .assignments_for_application(&application, &application_assignments)
+        })
+        .watches(template_api, ListParams::default(), move |template| {
+            template_dependencies.assignments_for_template(&template, &template_assignments)
+        })
+        .watches(cluster_api, ListParams::default(), move |cluster| {
+            cluster_dependencies.assignments_for_cluster(&cluster, &cluster_assignments)
+        })
         .run(reconcile, on_error, context)
         .for_each(|reconciliation_result| async move {
             println!("reconciliation result: {:?}", reconciliation_result);
@@ -60,6 +149,7 @@ async fn main() {
 /// Context injected with each `reconcile` and `on_error` method invocation.
 struct ContextData {
     controller: ApplicationAssignmentController,
+    dependencies: Dependencies,
 }
 
 impl ContextData {
@@ -67,18 +157,27 @@ impl ContextData {
     ///
     /// # Arguments:
     /// - `client`: A Kubernetes client to make Kubernetes REST API requests with. Resources
-    /// will be created and deleted with this client.
-    pub fn new(client: Client) -> Self {
-        let controller = ApplicationAssignmentController::new(client);
-        ContextData { controller }
+    ///   will be created and deleted with this client.
+    /// - `config`: Validated operator configuration.
+    /// - `dependencies`: Caches of the resources assignments are rendered from.
+    pub fn new(client: Client, config: &Config, dependencies: Dependencies) -> Result<Self, Error> {
+        let controller = ApplicationAssignmentController::new(client, config)?;
+        Ok(ContextData {
+            controller,
+            dependencies,
+        })
     }
 }
 
 /// Action to be taken upon an `ApplicationAssignment` resource during reconciliation
+#[derive(Debug, PartialEq)]
 enum Action {
-    /// Create the subresources, this includes spawning `n` pods with ApplicationAssignment service
+    /// Add the finalizer to an `ApplicationAssignment` seen for the first time, so that its rendered
+    /// output is cleaned up before Kubernetes deletes it
+    AddFinalizer,
+    /// Render the `ApplicationAssignment` and push it to the GitOps repo of its cluster
     Create,
-    /// Delete all subresources created in the `Create` phase
+    /// Delete the rendered output of a deleted `ApplicationAssignment` and release its finalizer
     Delete,
     /// This `ApplicationAssignment` resource is in desired state and requires no actions to be taken
     NoOp,
@@ -106,50 +205,91 @@ async fn reconcile(
         Some(namespace) => namespace,
     };
 
+    let name = application_assignment.name(); // Name of the ApplicationAssignment resource is used to name the subresources as well.
+
+    // Generations of the resources the assignment is rendered from, so that changes to them are
+    // rendered even though the assignment itself did not change.
+    let dependencies = context
+        .get_ref()
+        .dependencies
+        .generations(&application_assignment);
+
     // Performs action as decided by the `determine_action` function.
-    return match determine_action(&application_assignment) {
-        Action::Create => {
-            debug!("Action::Create");
-            // Creates a deployment with `n` ApplicationAssignment service pods, but applies a finalizer first.
-            // Finalizer is applied first, as the operator might be shut down and restarted
-            // at any time, leaving subresources in intermediate state. This prevents leaks on
-            // the `ApplicationAssignment` resource deletion.
-            let name = application_assignment.name(); // Name of the ApplicationAssignment resource is used to name the subresources as well.
-
-            // Apply the finalizer first. If that fails, the `?` operator invokes automatic conversion
-            // of `kube::Error` to the `Error` defined in this crate.
+    match determine_action(&application_assignment, dependencies.as_ref()) {
+        Action::AddFinalizer => {
+            debug!("Action::AddFinalizer");
+            // The finalizer is applied before anything is rendered, as the operator might be shut down
+            // and restarted at any time, leaving rendered output behind. This prevents leaks on
+            // the `ApplicationAssignment` resource deletion. If that fails, the `?` operator invokes
+            // automatic conversion of `kube::Error` to the `Error` defined in this crate.
             application_assignment_controller
                 .add_finalizer_record(&name, &namespace)
                 .await?;
 
-            // Invoke creation of a Kubernetes built-in resource named deployment with `n` ApplicationAssignment service pods.
+            Ok(ReconcilerAction {
+                // Adding the finalizer modifies the resource, which queues it again for rendering.
+                requeue_after: None,
+            })
+        }
+        Action::Create => {
+            debug!("Action::Create");
+
+            // Render and push the assignment, then record the outcome in its status subresource.
+            let status = match application_assignment_controller
+                .create_deployment(&name, &namespace)
+                .await
+            {
+                Ok(deployment) => {
+                    if !deployment.changed {
+                        debug!("{} is unchanged at {:?}", name, deployment.commit);
+                    }
+                    deployed_status(&application_assignment, &deployment, dependencies)
+                }
+                Err(err) => {
+                    let status = failed_status(&application_assignment, &err);
+                    application_assignment_controller
+                        .patch_status(&name, &namespace, &status)
+                        .await?;
+                    return Err(err);
+                }
+            };
+
             application_assignment_controller
-                .create_deployment(&application_assignment.name(), &namespace)
+                .patch_status(&name, &namespace, &status)
                 .await?;
 
             Ok(ReconcilerAction {
-                // Finalizer is added, deployment is deployed, re-check in 60 seconds.
+                // Deployment is deployed, re-check in 60 seconds.
                 requeue_after: Some(Duration::from_secs(60)),
             })
         }
         Action::Delete => {
-            debug!("Action::Create");
+            debug!("Action::Delete");
             // Deletes any subresources related to this `ApplicationAssignment` resources. If and only if all subresources
             // are deleted, the finalizer is removed and Kubernetes is free to remove the `ApplicationAssignment` resource.
 
             // First, delete the deployment. If there is any error deleting the deployment, it is
             // automatically converted into `Error` defined in this crate and the reconciliation is ended
             // with that error.
-
-            // Note: A more advanced implementation would check for the Deployment's existence.
-            application_assignment_controller
-                .delete_deployment(&application_assignment.name(), &namespace)
+            let review = application_assignment_controller
+                .delete_deployment(&name, &namespace)
                 .await?;
 
+            // In pull request mode the removal first has to be merged, keep the finalizer until then.
+            if let Some(review) = review {
+                debug!(
+                    "removal of {} pending review: {}",
+                    name, review.change_request
+                );
+                return Ok(ReconcilerAction {
+                    requeue_after: Some(Duration::from_secs(60)),
+                });
+            }
+
             // Once the deployment is successfully removed, remove the finalizer to make it possible
             // for Kubernetes to delete the `ApplicationAssignment` resource.
             application_assignment_controller
-                .delete_finalizer_record(&application_assignment.name(), &namespace)
+                .delete_finalizer_record(&name, &namespace)
                 .await?;
 
             Ok(ReconcilerAction {
@@ -157,30 +297,133 @@ async fn reconcile(
             })
         }
         Action::NoOp => {
-            debug!("Action::Create");
+            debug!("Action::NoOp");
+
+            // Deployed assignments are checked for drift, such as their output being removed by hand.
+            // Marking a missing deployment not ready queues the assignment to be deployed again.
+            if application_assignment.meta().deletion_timestamp.is_none()
+                && is_deployed(&application_assignment, None)
+            {
+                match application_assignment_controller
+                    .deployment_status(&name, &namespace)
+                    .await
+                {
+                    Ok(DeploymentStatus::Missing) => {
+                        let status = missing_status(&application_assignment);
+                        application_assignment_controller
+                            .patch_status(&name, &namespace, &status)
+                            .await?;
+                    }
+                    Ok(_) => {}
+                    Err(err) => warn!("could not check deployment of {}: {}", name, err),
+                }
+            }
 
             Ok(ReconcilerAction {
                 // The resource is already in desired state, do nothing and re-check after 60 seconds
                 requeue_after: Some(Duration::from_secs(60)),
             })
         }
-    };
+    }
+}
+
+/// Creates and deletes the assignments of an `ApplicationEnvironment`, so that it is assigned to
+/// the clusters it is scheduled to.
+async fn reconcile_environment(
+    environment: ApplicationEnvironment,
+    context: Context<EnvironmentScheduler>,
+) -> Result<ReconcilerAction, Error> {
+    let schedule = context.get_ref().schedule_environment(&environment).await?;
+
+    if !schedule.create.is_empty() || !schedule.delete.is_empty() {
+        info!(
+            "scheduled {} to clusters {:?}, removed assignments {:?}",
+            environment.name(),
+            schedule.create,
+            schedule.delete
+        );
+    }
+
+    Ok(ReconcilerAction {
+        // Clusters are watched, the periodic re-check only catches up on missed events.
+        requeue_after: Some(Duration::from_secs(300)),
+    })
+}
+
+/// Requeues an `ApplicationEnvironment` that could not be scheduled after five seconds.
+fn on_environment_error(
+    error: &Error,
+    _context: Context<EnvironmentScheduler>,
+) -> ReconcilerAction {
+    error!("Scheduling error:\n{:?}", error);
+    ReconcilerAction {
+        requeue_after: Some(Duration::from_secs(5)),
+    }
 }
 
 /// Resources arrives into reconciliation queue in a certain state. This function looks at
 /// the state of given `ApplicationAssignment` resource and decides which actions needs to be performed.
 /// The finite set of possible actions is represented by the `Action` enum.
 ///
+/// An assignment moves through these states:
+/// - no finalizer: `AddFinalizer`
+/// - finalizer, but its current generation, or the current generation of a resource it depends on,
+///   has not been deployed successfully: `Create`
+/// - finalizer and deployed: `NoOp`
+/// - being deleted with the finalizer still present: `Delete`, and `NoOp` once it is released
+///
 /// # Arguments
 /// - `application_assignment`: A reference to `ApplicationAssignment` being reconciled to decide next action upon.
-fn determine_action(application_assignment: &ApplicationAssignment) -> Action {
-    return if application_assignment.meta().deletion_timestamp.is_some() {
-        Action::Delete
-    } else if application_assignment.meta().finalizers.is_some() {
+/// - `dependencies`: Current generations of the resources the assignment is rendered from, if known.
+fn determine_action(
+    application_assignment: &ApplicationAssignment,
+    dependencies: Option<&BTreeMap<String, i64>>,
+) -> Action {
+    let meta = application_assignment.meta();
+    let has_finalizer = meta
+        .finalizers
+        .iter()
+        .flatten()
+        .any(|finalizer| finalizer == FINALIZER);
+
+    if meta.deletion_timestamp.is_some() {
+        if has_finalizer {
+            Action::Delete
+        } else {
+            Action::NoOp
+        }
+    } else if !has_finalizer {
+        Action::AddFinalizer
+    } else if !is_deployed(application_assignment, dependencies) {
         Action::Create
     } else {
         Action::NoOp
-    };
+    }
+}
+
+/// Whether the current generation of an `ApplicationAssignment` has been deployed successfully.
+/// Failed deployments are retried, even when the generation has not changed since. Dependencies
+/// that are not known yet, for example while the caches are still syncing, are not compared.
+fn is_deployed(
+    application_assignment: &ApplicationAssignment,
+    dependencies: Option<&BTreeMap<String, i64>>,
+) -> bool {
+    match &application_assignment.status {
+        Some(status) => {
+            status.observed_generation == application_assignment.meta().generation
+                && match dependencies {
+                    Some(dependencies) => {
+                        status.observed_dependencies.as_ref() == Some(dependencies)
+                    }
+                    None => true,
+                }
+                && matches!(
+                    status.condition(CONDITION_READY),
+                    Some(condition) if condition.status == "True"
+                )
+        }
+        None => false,
+    }
 }
 
 /// Actions to be taken when a reconciliation fails - for whatever reason.
@@ -196,3 +439,120 @@ fn on_error(error: &Error, _context: Context<ContextData>) -> ReconcilerAction {
         requeue_after: Some(Duration::from_secs(5)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+    use k8s_openapi::chrono::Utc;
+    use serde_json::Map;
+    use std::collections::BTreeMap;
+
+    use crate::controllers::assignment::FINALIZER;
+    use crate::models::assignment::{
+        ApplicationAssignment, ApplicationAssignmentSpec, ApplicationAssignmentStatus,
+        CONDITION_READY,
+    };
+
+    use super::{determine_action, Action};
+
+    fn assignment(
+        generation: i64,
+        finalizer: bool,
+        deleted: bool,
+        status: Option<ApplicationAssignmentStatus>,
+    ) -> ApplicationAssignment {
+        let mut assignment = ApplicationAssignment::new(
+            "azure-eastus2-1-cluster-agent-dev",
+            ApplicationAssignmentSpec {
+                cluster: "azure-eastus2-1".to_string(),
+                environment: "dev".to_string(),
+                values: Some(Map::new()),
+                values_from: None,
+            },
+        );
+
+        assignment.metadata.namespace = Some("default".to_string());
+        assignment.metadata.generation = Some(generation);
+        if finalizer {
+            assignment.metadata.finalizers = Some(vec![FINALIZER.to_string()]);
+        }
+        if deleted {
+            assignment.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        }
+        assignment.status = status;
+
+        assignment
+    }
+
+    fn status(observed_generation: i64, ready: bool) -> Option<ApplicationAssignmentStatus> {
+        let mut status = ApplicationAssignmentStatus {
+            observed_generation: Some(observed_generation),
+            ..ApplicationAssignmentStatus::default()
+        };
+        status.set_condition(CONDITION_READY, ready, "Test", "");
+
+        Some(status)
+    }
+
+    #[test]
+    fn adds_finalizer_on_first_sight() {
+        assert_eq!(
+            determine_action(&assignment(1, false, false, None), None),
+            Action::AddFinalizer
+        );
+    }
+
+    #[test]
+    fn creates_until_generation_is_deployed() {
+        assert_eq!(
+            determine_action(&assignment(1, true, false, None), None),
+            Action::Create
+        );
+        assert_eq!(
+            determine_action(&assignment(2, true, false, status(1, true)), None),
+            Action::Create
+        );
+        assert_eq!(
+            determine_action(&assignment(2, true, false, status(2, false)), None),
+            Action::Create
+        );
+        assert_eq!(
+            determine_action(&assignment(2, true, false, status(2, true)), None),
+            Action::NoOp
+        );
+    }
+
+    #[test]
+    fn deletes_while_finalizer_is_present() {
+        assert_eq!(
+            determine_action(&assignment(2, true, true, status(2, true)), None),
+            Action::Delete
+        );
+        assert_eq!(
+            determine_action(&assignment(2, false, true, status(2, true)), None),
+            Action::NoOp
+        );
+    }
+
+    #[test]
+    fn creates_when_dependencies_change() {
+        let mut deployed = status(2, true);
+        let mut generations = BTreeMap::new();
+        generations.insert("Application/cluster-agent".to_string(), 1);
+        deployed.as_mut().unwrap().observed_dependencies = Some(generations.clone());
+
+        let assignment = assignment(2, true, false, deployed);
+
+        assert_eq!(
+            determine_action(&assignment, Some(&generations)),
+            Action::NoOp
+        );
+        assert_eq!(determine_action(&assignment, None), Action::NoOp);
+
+        generations.insert("Application/cluster-agent".to_string(), 2);
+        assert_eq!(
+            determine_action(&assignment, Some(&generations)),
+            Action::Create
+        );
+    }
+}
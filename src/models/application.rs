@@ -1,10 +1,11 @@
-use std::collections::HashMap;
-
 use kube::CustomResource;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
-// use super::templates::TemplatesSpec;
+use super::argocd::ArgoCdSpec;
+use super::flux::FluxSpec;
+use super::values::ValuesFromSource;
 
 /// Struct corresponding to the Specification (`spec`) part of the `Application` resource, directly
 /// reflects context of the `applications.microsoft.com.yaml` file to be found in this repository.
@@ -20,5 +21,17 @@ use serde::{Deserialize, Serialize};
 )]
 pub struct ApplicationSpec {
     pub template: String,
-    pub values: Option<HashMap<String, String>>,
+    /// Values the template is rendered with. Deep-merged under those of the environment and the
+    /// assignment.
+    pub values: Option<Map<String, Value>>,
+    /// ConfigMaps and Secrets values are taken from, in order, before `values` is merged over them.
+    #[serde(rename = "valuesFrom")]
+    pub values_from: Option<Vec<ValuesFromSource>>,
+    /// Name of the workflow backend that deploys this application's assignments: `gitops` (Flux,
+    /// the default), `argocd` or `apply`.
+    pub reconciler: Option<String>,
+    /// Settings of the Flux `Kustomization`s generated for this application's assignments.
+    pub flux: Option<FluxSpec>,
+    /// Settings of the Argo CD `Application`s generated for this application's assignments.
+    pub argocd: Option<ArgoCdSpec>,
 }
@@ -0,0 +1,26 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Settings of the Argo CD `Application` generated for each assignment. Set on an `Application`,
+/// and overridden field by field by its `ApplicationEnvironment`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ArgoCdSpec {
+    /// Namespace Argo CD syncs rendered objects without a namespace to. Defaults to `default`.
+    pub namespace: Option<String>,
+    /// Whether Argo CD creates the namespace if it doesn't exist. Defaults to `false`.
+    pub create_namespace: Option<bool>,
+}
+
+impl ArgoCdSpec {
+    /// Returns these settings, with those set in `overrides` taking precedence.
+    pub fn merge(&self, overrides: &ArgoCdSpec) -> ArgoCdSpec {
+        ArgoCdSpec {
+            namespace: overrides
+                .namespace
+                .clone()
+                .or_else(|| self.namespace.clone()),
+            create_namespace: overrides.create_namespace.or(self.create_namespace),
+        }
+    }
+}
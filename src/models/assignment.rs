@@ -1,7 +1,11 @@
+use k8s_openapi::chrono::{SecondsFormat, Utc};
 use kube::CustomResource;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+use super::values::ValuesFromSource;
 
 /// Struct corresponding to the Specification (`spec`) part of the `ApplicationAssignment` resource, directly
 /// reflects context of the `applicationassignments.microsoft.com.yaml` file to be found in this repository.
@@ -12,6 +16,7 @@ use std::collections::HashMap;
     version = "v1alpha1",
     kind = "ApplicationAssignment",
     plural = "applicationassignments",
+    status = "ApplicationAssignmentStatus",
     derive = "PartialEq",
     namespaced
 )]
@@ -19,5 +24,90 @@ pub struct ApplicationAssignmentSpec {
     pub environment: String,
     pub cluster: String,
 
-    pub values: Option<HashMap<String, String>>,
+    /// Values deep-merged over those of the `ApplicationEnvironment`.
+    pub values: Option<Map<String, Value>>,
+    /// ConfigMaps and Secrets values are taken from, merged over those of the
+    /// `ApplicationEnvironment` and under `values`.
+    #[serde(rename = "valuesFrom")]
+    pub values_from: Option<Vec<ValuesFromSource>>,
+}
+
+/// Observed state of an `ApplicationAssignment`, written by the reconciler through the status subresource.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ApplicationAssignmentStatus {
+    /// The `metadata.generation` of the assignment this status describes.
+    pub observed_generation: Option<i64>,
+    #[serde(default)]
+    pub conditions: Vec<ApplicationAssignmentCondition>,
+    /// Generations of the `ApplicationEnvironment`, `Application` and `ApplicationTemplate` the
+    /// assignment was rendered from, keyed by `Kind/name`.
+    pub observed_dependencies: Option<BTreeMap<String, i64>>,
+    /// Commit of the template repo the assignment was rendered from.
+    pub template_commit: Option<String>,
+    /// Commit in the GitOps repo that contains the rendered assignment.
+    pub gitops_commit: Option<String>,
+    /// Branch the rendered assignment awaits review on, until it is merged.
+    pub review_branch: Option<String>,
+    /// Change request opened to merge `review_branch`, as reported by the provider.
+    pub change_request: Option<String>,
+    /// Objects applied to the cluster by backends that apply directly, as
+    /// `apiVersion/kind/namespace/name`.
+    pub inventory: Option<Vec<String>>,
+    /// Message of the error that failed the most recent reconciliation, if it failed.
+    pub last_error: Option<String>,
+}
+
+pub const CONDITION_READY: &str = "Ready";
+pub const CONDITION_RENDERED: &str = "Rendered";
+pub const CONDITION_PUSHED: &str = "Pushed";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ApplicationAssignmentCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// One of `True`, `False` or `Unknown`.
+    pub status: String,
+    pub reason: String,
+    pub message: String,
+    pub last_transition_time: String,
+}
+
+impl ApplicationAssignmentStatus {
+    /// Returns the condition of the given type, if it has been set.
+    pub fn condition(&self, type_: &str) -> Option<&ApplicationAssignmentCondition> {
+        self.conditions
+            .iter()
+            .find(|condition| condition.type_ == type_)
+    }
+
+    /// Sets the condition of the given type. The transition time is only updated when the
+    /// condition's status actually changes.
+    pub fn set_condition(&mut self, type_: &str, status: bool, reason: &str, message: &str) {
+        let status = if status { "True" } else { "False" };
+        let now = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+
+        match self
+            .conditions
+            .iter_mut()
+            .find(|condition| condition.type_ == type_)
+        {
+            Some(condition) => {
+                if condition.status != status {
+                    condition.last_transition_time = now;
+                }
+                condition.status = status.to_string();
+                condition.reason = reason.to_string();
+                condition.message = message.to_string();
+            }
+            None => self.conditions.push(ApplicationAssignmentCondition {
+                type_: type_.to_string(),
+                status: status.to_string(),
+                reason: reason.to_string(),
+                message: message.to_string(),
+                last_transition_time: now,
+            }),
+        }
+    }
 }
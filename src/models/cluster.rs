@@ -4,6 +4,8 @@ use kube::CustomResource;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use crate::utils::config::GitopsMode;
+
 /// Struct corresponding to the Specification (`spec`) part of the `Cluster` resource, directly
 /// reflects context of the `clusters.microsoft.com.yaml` file to be found in this repository.
 /// The `Cluster` struct will be generated by the `CustomResource` derive macro.
@@ -18,6 +20,54 @@ use serde::{Deserialize, Serialize};
 )]
 pub struct ClusterSpec {
     pub name: String,
+    #[serde(default)]
     pub labels: HashMap<String, String>,
+    #[serde(default)]
     pub environments: Vec<String>,
+
+    /// Facts about the cluster, exposed to templates along with its name and labels.
+    pub facts: Option<ClusterFacts>,
+
+    /// Where this cluster's manifests are committed. Unset fields fall back to the operator's
+    /// configured defaults.
+    pub gitops: Option<ClusterGitopsSpec>,
+
+    /// Secret with a kubeconfig for the cluster, for workflows that apply manifests directly.
+    pub kubeconfig: Option<ClusterKubeconfigSpec>,
+}
+
+/// Declared facts about a cluster. `cloud`, `region` and `zone` fall back to the cluster's labels
+/// of the same name.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ClusterFacts {
+    /// Cloud the cluster runs in, eg. `azure`.
+    pub cloud: Option<String>,
+    /// Region of the cloud, eg. `eastus2`.
+    pub region: Option<String>,
+    /// Availability zone within the region.
+    pub zone: Option<String>,
+    /// Kubernetes version of the cluster, eg. `1.21.2`.
+    pub kubernetes_version: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+pub struct ClusterGitopsSpec {
+    pub repo: Option<String>,
+    pub branch: Option<String>,
+    pub path: Option<String>,
+    /// Set to `pullRequest` to require review of every change to this cluster.
+    pub mode: Option<GitopsMode>,
+    /// Shared bases of this cluster, replacing the operator's configured ones. See
+    /// `GitopsConfig::bases`.
+    pub bases: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ClusterKubeconfigSpec {
+    /// Name of a Secret in the namespace of the `Cluster`.
+    pub secret_name: String,
+    /// Key of the kubeconfig within the Secret, `kubeconfig` if unset.
+    pub key: Option<String>,
 }
@@ -1,11 +1,20 @@
 use kube::CustomResource;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use std::collections::HashMap;
 
+use super::argocd::ArgoCdSpec;
+use super::flux::FluxSpec;
+use super::values::ValuesFromSource;
+
+/// How many of the clusters matching an environment's `selector` it is assigned to.
 #[derive(Clone, Debug, PartialEq, JsonSchema, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub enum ClustersSpec {
+    /// `{count: N}`: N of them, spread evenly across the values of `spreadBy`.
     Count(u32),
+    /// `all`: every one of them.
     All,
 }
 
@@ -21,9 +30,24 @@ pub enum ClustersSpec {
     derive = "PartialEq",
     namespaced
 )]
+#[serde(rename_all = "camelCase")]
 pub struct ApplicationEnvironmentSpec {
     pub application: String,
     pub environment: String,
-    // pub selector: HashMap<String, String>,
-    pub values: Option<HashMap<String, String>>,
+    /// Labels a `Cluster` needs to have for this environment to be assigned to it. Assignments of
+    /// environments without a selector are written by hand.
+    pub selector: Option<HashMap<String, String>>,
+    /// How many of the matching clusters this environment is assigned to, all if unset.
+    pub clusters: Option<ClustersSpec>,
+    /// Cluster label, eg. `region`, that a `count` of clusters is spread evenly across.
+    pub spread_by: Option<String>,
+    /// Values deep-merged over those of the `Application`.
+    pub values: Option<Map<String, Value>>,
+    /// ConfigMaps and Secrets values are taken from, merged over those of the `Application` and
+    /// under `values`.
+    pub values_from: Option<Vec<ValuesFromSource>>,
+    /// Overrides the `Application`'s Flux `Kustomization` settings for this environment.
+    pub flux: Option<FluxSpec>,
+    /// Overrides the `Application`'s Argo CD `Application` settings for this environment.
+    pub argocd: Option<ArgoCdSpec>,
 }
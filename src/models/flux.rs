@@ -0,0 +1,47 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Settings of the Flux `Kustomization` generated for each assignment. Set on an `Application`,
+/// and overridden field by field by its `ApplicationEnvironment`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FluxSpec {
+    /// Names of Flux `Kustomization`s on the same cluster that have to be ready first. The
+    /// `Kustomization` of an assignment is named after the assignment.
+    pub depends_on: Option<Vec<String>>,
+    /// How often Flux reconciles the assignment, eg. `5m`. Defaults to `10m`.
+    pub interval: Option<String>,
+    /// Whether Flux deletes objects that are no longer rendered. Defaults to `true`.
+    pub prune: Option<bool>,
+    /// Objects Flux waits to become ready before it considers the assignment ready.
+    pub health_checks: Option<Vec<FluxHealthCheck>>,
+}
+
+impl FluxSpec {
+    /// Returns these settings, with those set in `overrides` taking precedence.
+    pub fn merge(&self, overrides: &FluxSpec) -> FluxSpec {
+        FluxSpec {
+            depends_on: overrides
+                .depends_on
+                .clone()
+                .or_else(|| self.depends_on.clone()),
+            interval: overrides.interval.clone().or_else(|| self.interval.clone()),
+            prune: overrides.prune.or(self.prune),
+            health_checks: overrides
+                .health_checks
+                .clone()
+                .or_else(|| self.health_checks.clone()),
+        }
+    }
+}
+
+/// Reference to an object Flux checks the health of.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FluxHealthCheck {
+    pub api_version: String,
+    pub kind: String,
+    pub name: String,
+    /// Defaults to the namespace of the generated `Kustomization`.
+    pub namespace: Option<String>,
+}
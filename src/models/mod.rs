@@ -1,6 +1,8 @@
 pub mod application;
+pub mod argocd;
 pub mod assignment;
 pub mod cluster;
 pub mod environment;
+pub mod flux;
 pub mod template;
-pub mod templates;
+pub mod values;
@@ -16,6 +16,10 @@ use serde::{Deserialize, Serialize};
 )]
 pub struct ApplicationTemplateSpec {
     pub repo: String,
-    pub reference: String,
+    /// Branch, tag or commit SHA of `repo` to render from. Defaults to the repo's default branch.
+    pub reference: Option<String>,
     pub path: String,
+    /// Fails rendering on variables the template references that are not defined, instead of
+    /// rendering them empty.
+    pub strict: Option<bool>,
 }
@@ -1,8 +0,0 @@
-use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
-
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
-pub struct TemplatesSpec {
-    pub application: String,
-    pub global: Option<String>,
-}
@@ -0,0 +1,27 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Kind of resource values are taken from.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, JsonSchema)]
+pub enum ValuesFromKind {
+    ConfigMap,
+    /// Values taken from a Secret are only ever written to the GitOps repo encrypted.
+    Secret,
+}
+
+/// Reference to a ConfigMap or Secret, in the namespace of the referring resource, that values are
+/// taken from. Modelled after the `valuesFrom` of Flux `HelmRelease`s.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ValuesFromSource {
+    pub kind: ValuesFromKind,
+    pub name: String,
+    /// Key of the data to take, `values.yaml` if unset.
+    pub values_key: Option<String>,
+    /// Dotted path, eg. `database.connectionString`, the data is put at as a string. Without
+    /// one, the data is parsed as a YAML map and merged into the values.
+    pub target_path: Option<String>,
+    /// Whether to skip the reference if the resource or key doesn't exist, rather than failing.
+    #[serde(default)]
+    pub optional: bool,
+}
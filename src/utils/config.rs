@@ -0,0 +1,495 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+use crate::utils::error::Error;
+
+/// Operator configuration, assembled at startup from (in increasing order of precedence) an
+/// optional YAML config file, environment variables and command line flags.
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Config {
+    pub gitops: GitopsConfig,
+    pub cache: CacheConfig,
+    pub encryption: EncryptionConfig,
+    pub validation: ValidationConfig,
+}
+
+/// Location that rendered manifests are committed to.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct GitopsConfig {
+    /// URL of the cluster GitOps repository, eg. `git@github.com:org/cluster-gitops`.
+    pub repo: String,
+    /// Branch that rendered manifests are pushed to.
+    pub branch: String,
+    /// Path within the repository under which the per cluster directories live.
+    pub path: String,
+    /// Whether changes are pushed to `branch` directly or proposed for review.
+    pub mode: GitopsMode,
+    /// Shared bases listed ahead of the assignments in each cluster's `kustomization.yaml`, as
+    /// paths relative to the cluster's directory. They are Handlebars templates over the
+    /// `Cluster`'s labels and `clusterName`, eg. `../regions/{{region}}`.
+    pub bases: Vec<String>,
+}
+
+impl Default for GitopsConfig {
+    fn default() -> Self {
+        GitopsConfig {
+            repo: String::new(),
+            branch: "main".to_string(),
+            path: String::new(),
+            mode: GitopsMode::Push,
+            bases: Vec::new(),
+        }
+    }
+}
+
+/// How rendered manifests reach the GitOps branch.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum GitopsMode {
+    /// Commits are pushed straight to the branch.
+    Push,
+    /// Commits are pushed to a branch per assignment, and a change request is opened to merge it.
+    /// The review branch is the change request: it is done once the branch contains its tip.
+    PullRequest,
+}
+
+impl FromStr for GitopsMode {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "push" => Ok(GitopsMode::Push),
+            "pullRequest" => Ok(GitopsMode::PullRequest),
+            _ => Err(Error::ConfigError(format!(
+                "gitops mode '{}' must be one of push, pullRequest",
+                mode
+            ))),
+        }
+    }
+}
+
+/// Local working copies of template and GitOps repos.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct CacheConfig {
+    /// Directory the working copies are kept in, in its `application-api-repos` subdirectory,
+    /// which is removed at startup.
+    pub path: String,
+    /// Number of working copies kept before the least recently used ones are removed.
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            path: std::env::temp_dir()
+                .join("application-api")
+                .to_string_lossy()
+                .to_string(),
+            max_entries: 64,
+        }
+    }
+}
+
+/// Encryption of rendered files before they are committed.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct EncryptionConfig {
+    /// age public keys, eg. `age1...`, that can decrypt encrypted files. Without any, files that
+    /// would be encrypted are refused rather than committed in plaintext.
+    pub age_recipients: Vec<String>,
+    /// How files are encrypted.
+    pub format: EncryptionFormat,
+    /// Globs, eg. `**/secret*.yaml`, of rendered files that are always encrypted, relative to the
+    /// assignment's output directory. Manifests with a `Secret`, or with a value taken from a
+    /// Secret, are encrypted regardless. kustomize config files and other files that aren't
+    /// manifests never are, and one with a value taken from a Secret is refused.
+    pub patterns: Vec<String>,
+    /// With the `sops` format, only values under keys matching this regex are encrypted. Empty
+    /// encrypts every value except those under keys ending in `_unencrypted`. Files matching
+    /// `patterns` with a document that has no such value are refused.
+    pub encrypted_regex: String,
+    /// With the `sops` format, the Secret in Flux's namespace holding the age key that Flux
+    /// decrypts the `Kustomization`s' files with.
+    pub decryption_secret: String,
+}
+
+/// Regex of the keys whose values are encrypted by default: those of Kubernetes `Secret`s.
+pub const DEFAULT_ENCRYPTED_REGEX: &str = "^(data|stringData)$";
+
+/// Secret Flux decrypts with by default, as named in its SOPS guide.
+pub const DEFAULT_DECRYPTION_SECRET: &str = "sops-age";
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        EncryptionConfig {
+            age_recipients: Vec::new(),
+            format: EncryptionFormat::Sops,
+            patterns: Vec::new(),
+            encrypted_regex: DEFAULT_ENCRYPTED_REGEX.to_string(),
+            decryption_secret: DEFAULT_DECRYPTION_SECRET.to_string(),
+        }
+    }
+}
+
+/// Format of encrypted files.
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum EncryptionFormat {
+    /// YAML files with their values encrypted by SOPS, which Flux decrypts when applying them.
+    Sops,
+    /// Whole files encrypted with age, ASCII armored.
+    Age,
+}
+
+impl FromStr for EncryptionFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "sops" => Ok(EncryptionFormat::Sops),
+            "age" => Ok(EncryptionFormat::Age),
+            _ => Err(Error::ConfigError(format!(
+                "encryption format '{}' must be one of sops, age",
+                format
+            ))),
+        }
+    }
+}
+
+/// Checks of rendered files before they are committed.
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ValidationConfig {
+    /// Whether manifests of core Kubernetes kinds are checked against their schemas, besides
+    /// being checked for an `apiVersion`, `kind` and `metadata.name`.
+    pub schemas: bool,
+}
+
+const USAGE: &str = "usage: application-api [--config <path>] [--gitops-repo <url>] [--gitops-branch <branch>] [--gitops-path <path>] [--gitops-mode <push|pullRequest>] [--gitops-bases <base,...>] [--cache-path <path>] [--encryption-age-recipients <recipient,...>] [--encryption-format <sops|age>] [--encryption-patterns <glob,...>] [--encryption-encrypted-regex <regex>] [--encryption-decryption-secret <name>] [--validation-schemas <true|false>]";
+
+impl Config {
+    /// Loads the configuration for this process from its command line arguments and environment.
+    pub fn from_env() -> Result<Config, Error> {
+        Config::load(std::env::args().skip(1), |name| std::env::var(name).ok())
+    }
+
+    /// Builds a validated `Config`.
+    ///
+    /// # Arguments
+    /// - `args` - Command line arguments, excluding the program name.
+    /// - `env` - Lookup function for environment variables.
+    ///
+    /// The config file is taken from `--config` or `CONFIG_PATH`. Individual settings are then
+    /// overridden by `GITOPS_REPO`, `GITOPS_BRANCH`, `GITOPS_PATH`, `GITOPS_MODE`, `GITOPS_BASES`
+    /// (comma separated), `CACHE_PATH`, `ENCRYPTION_AGE_RECIPIENTS` (comma separated),
+    /// `ENCRYPTION_FORMAT`, `ENCRYPTION_PATTERNS` (comma separated),
+    /// `ENCRYPTION_ENCRYPTED_REGEX`, `ENCRYPTION_DECRYPTION_SECRET` and `VALIDATION_SCHEMAS`, and
+    /// finally by the matching flags.
+    pub fn load<I, F>(args: I, env: F) -> Result<Config, Error>
+    where
+        I: IntoIterator<Item = String>,
+        F: Fn(&str) -> Option<String>,
+    {
+        let flags = parse_flags(args)?;
+
+        let config_path = flag_value(&flags, "config").or_else(|| env("CONFIG_PATH"));
+
+        let mut config = match config_path {
+            Some(config_path) => Config::from_file(Path::new(&config_path))?,
+            None => Config::default(),
+        };
+
+        let overrides = [
+            ("gitops-repo", "GITOPS_REPO", &mut config.gitops.repo),
+            ("gitops-branch", "GITOPS_BRANCH", &mut config.gitops.branch),
+            ("gitops-path", "GITOPS_PATH", &mut config.gitops.path),
+            ("cache-path", "CACHE_PATH", &mut config.cache.path),
+            (
+                "encryption-encrypted-regex",
+                "ENCRYPTION_ENCRYPTED_REGEX",
+                &mut config.encryption.encrypted_regex,
+            ),
+            (
+                "encryption-decryption-secret",
+                "ENCRYPTION_DECRYPTION_SECRET",
+                &mut config.encryption.decryption_secret,
+            ),
+        ];
+
+        for (flag, variable, setting) in overrides {
+            if let Some(value) = flag_value(&flags, flag).or_else(|| env(variable)) {
+                *setting = value;
+            }
+        }
+
+        if let Some(mode) = flag_value(&flags, "gitops-mode").or_else(|| env("GITOPS_MODE")) {
+            config.gitops.mode = mode.parse()?;
+        }
+
+        if let Some(bases) = flag_value(&flags, "gitops-bases").or_else(|| env("GITOPS_BASES")) {
+            config.gitops.bases = split_list(&bases);
+        }
+
+        if let Some(recipients) = flag_value(&flags, "encryption-age-recipients")
+            .or_else(|| env("ENCRYPTION_AGE_RECIPIENTS"))
+        {
+            config.encryption.age_recipients = split_list(&recipients);
+        }
+
+        if let Some(format) =
+            flag_value(&flags, "encryption-format").or_else(|| env("ENCRYPTION_FORMAT"))
+        {
+            config.encryption.format = format.parse()?;
+        }
+
+        if let Some(patterns) =
+            flag_value(&flags, "encryption-patterns").or_else(|| env("ENCRYPTION_PATTERNS"))
+        {
+            config.encryption.patterns = split_list(&patterns);
+        }
+
+        if let Some(schemas) =
+            flag_value(&flags, "validation-schemas").or_else(|| env("VALIDATION_SCHEMAS"))
+        {
+            config.validation.schemas = schemas.parse().map_err(|_| {
+                Error::ConfigError(format!(
+                    "validation schemas '{}' must be one of true, false",
+                    schemas
+                ))
+            })?;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config, Error> {
+        let contents = std::fs::read_to_string(path).map_err(|err| {
+            Error::ConfigError(format!("could not read config file {:?}: {}", path, err))
+        })?;
+
+        serde_yaml::from_str(&contents).map_err(|err| {
+            Error::ConfigError(format!("could not parse config file {:?}: {}", path, err))
+        })
+    }
+
+    /// Checks that the configuration is complete and consistent.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.gitops.validate()?;
+
+        if self.cache.path.trim().is_empty() {
+            return Err(Error::ConfigError("cache path is required".to_string()));
+        }
+
+        if self.cache.max_entries == 0 {
+            return Err(Error::ConfigError(
+                "cache maxEntries must be at least 1".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl GitopsConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.repo.trim().is_empty() {
+            return Err(Error::ConfigError(
+                "gitops repo is required (set gitops.repo, GITOPS_REPO or --gitops-repo)"
+                    .to_string(),
+            ));
+        }
+
+        if !git2::Reference::is_valid_name(&format!("refs/heads/{}", self.branch)) {
+            return Err(Error::ConfigError(format!(
+                "gitops branch '{}' is not a valid branch name",
+                self.branch
+            )));
+        }
+
+        let path_is_contained = Path::new(&self.path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+        if !path_is_contained {
+            return Err(Error::ConfigError(format!(
+                "gitops path '{}' must be relative to the repository root",
+                self.path
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_flags<I>(args: I) -> Result<Vec<(String, String)>, Error>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut flags = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let name = match arg.strip_prefix("--") {
+            Some(name) => name.to_string(),
+            None => {
+                return Err(Error::ConfigError(format!(
+                    "unexpected argument '{}'\n{}",
+                    arg, USAGE
+                )))
+            }
+        };
+
+        let (name, value) = match name.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => match args.next() {
+                Some(value) => (name, value),
+                None => {
+                    return Err(Error::ConfigError(format!(
+                        "missing value for --{}\n{}",
+                        name, USAGE
+                    )))
+                }
+            },
+        };
+
+        let known_flags = [
+            "config",
+            "gitops-repo",
+            "gitops-branch",
+            "gitops-path",
+            "gitops-mode",
+            "gitops-bases",
+            "cache-path",
+            "encryption-age-recipients",
+            "encryption-format",
+            "encryption-patterns",
+            "encryption-encrypted-regex",
+            "encryption-decryption-secret",
+            "validation-schemas",
+        ];
+
+        if !known_flags.contains(&name.as_str()) {
+            return Err(Error::ConfigError(format!(
+                "unknown flag --{}\n{}",
+                name, USAGE
+            )));
+        }
+
+        flags.push((name, value));
+    }
+
+    Ok(flags)
+}
+
+/// Splits a comma separated list, dropping empty items.
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn flag_value(flags: &[(String, String)], name: &str) -> Option<String> {
+    flags
+        .iter()
+        .rev()
+        .find(|(flag, _)| flag == name)
+        .map(|(_, value)| value.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    use super::{Config, EncryptionFormat, GitopsMode};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn flags_override_environment_and_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            "gitops:\n  repo: git@github.com:org/from-file\n  branch: from-file\n  path: clusters\n"
+        )
+        .unwrap();
+
+        let mut env = HashMap::new();
+        env.insert("CONFIG_PATH", file.path().to_str().unwrap().to_string());
+        env.insert("GITOPS_BRANCH", "from-env".to_string());
+        env.insert(
+            "GITOPS_BASES",
+            "../common, ../regions/{{region}}".to_string(),
+        );
+        env.insert(
+            "ENCRYPTION_AGE_RECIPIENTS",
+            "age1first,age1second".to_string(),
+        );
+        env.insert("ENCRYPTION_PATTERNS", "**/secret*.yaml".to_string());
+        env.insert("ENCRYPTION_DECRYPTION_SECRET", "sops-gpg".to_string());
+        env.insert("VALIDATION_SCHEMAS", "true".to_string());
+
+        let config = Config::load(
+            args(&["--gitops-repo", "git@github.com:org/from-flag"]),
+            |name| env.get(name).cloned(),
+        )
+        .unwrap();
+
+        assert_eq!(config.gitops.repo, "git@github.com:org/from-flag");
+        assert_eq!(config.gitops.branch, "from-env");
+        assert_eq!(config.gitops.path, "clusters");
+        assert_eq!(config.gitops.mode, GitopsMode::Push);
+        assert_eq!(
+            config.gitops.bases,
+            vec!["../common", "../regions/{{region}}"]
+        );
+        assert_eq!(
+            config.encryption.age_recipients,
+            vec!["age1first", "age1second"]
+        );
+        assert_eq!(config.encryption.format, EncryptionFormat::Sops);
+        assert_eq!(config.encryption.patterns, vec!["**/secret*.yaml"]);
+        assert_eq!(config.encryption.decryption_secret, "sops-gpg");
+        assert!(config.validation.schemas);
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        let no_env = |_: &str| None;
+
+        assert!(Config::load(args(&[]), no_env).is_err());
+        assert!(Config::load(
+            args(&["--gitops-repo=repo", "--gitops-branch=a..b"]),
+            no_env
+        )
+        .is_err());
+        assert!(
+            Config::load(args(&["--gitops-repo=repo", "--gitops-path=../up"]), no_env).is_err()
+        );
+        assert!(Config::load(args(&["--gitops-repo=repo", "--unknown=1"]), no_env).is_err());
+        assert!(
+            Config::load(args(&["--gitops-repo=repo", "--gitops-mode=merge"]), no_env).is_err()
+        );
+        assert!(Config::load(
+            args(&["--gitops-repo=repo", "--encryption-format=pgp"]),
+            no_env
+        )
+        .is_err());
+        assert!(Config::load(
+            args(&["--gitops-repo=repo", "--validation-schemas=yes"]),
+            no_env
+        )
+        .is_err());
+        assert!(Config::load(args(&["--gitops-repo=repo"]), no_env).is_ok());
+    }
+}
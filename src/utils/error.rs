@@ -1,5 +1,6 @@
 /// Utility enum that covers all possible errors during reconciliation
 #[derive(Debug, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// Any error originating from the `kube-rs` crate
     #[error("Kubernetes reported error: {source}")]
@@ -12,18 +13,42 @@ pub enum Error {
     #[error("Invalid ApplicationAssignment CRD: {0}")]
     UserInputError(String),
 
+    /// Invalid operator configuration, detected at startup.
+    #[error("Invalid configuration: {0}")]
+    ConfigError(String),
+
     #[error("Git error: {source}")]
     GitError {
         #[from]
         source: git2::Error,
     },
 
+    /// The rendered commit could not be pushed to the GitOps repo.
+    #[error("Push error: {source}")]
+    PushError { source: git2::Error },
+
+    /// The remote rejected a push, typically because the branch moved on since it was fetched.
+    #[error("Push to {branch} rejected: {message}")]
+    PushRejected { branch: String, message: String },
+
+    /// Pushes kept being rejected, even after replaying the change on top of the fetched branch.
+    #[error("Push to {branch} still rejected after {attempts} attempts")]
+    PushRetriesExhausted { branch: String, attempts: usize },
+
     #[error("I/O error: {source}")]
     IoError {
         #[from]
         source: std::io::Error,
     },
 
+    /// A rendered file could not be encrypted.
+    #[error("Encryption error: {0}")]
+    EncryptionError(String),
+
+    /// Rendered files are not valid Kubernetes manifests.
+    #[error("Invalid rendered manifests:\n{0}")]
+    ValidationError(String),
+
     #[error("Render error: {source}")]
     RenderError {
         #[from]
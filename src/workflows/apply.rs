@@ -0,0 +1,686 @@
+use async_trait::async_trait;
+use k8s_openapi::api::core::v1::Secret;
+use kube::api::{DeleteParams, ListParams, Patch, PatchParams};
+use kube::config::{KubeConfigOptions, Kubeconfig};
+use kube::core::{DynamicObject, GroupVersionKind};
+use kube::discovery::{self, verbs, ApiResource, Discovery, Scope};
+use kube::{Api, Client, ResourceExt};
+use log::debug;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::models::assignment::ApplicationAssignment;
+use crate::models::cluster::Cluster;
+use crate::utils::config::ValidationConfig;
+use crate::utils::error::Error;
+use crate::workflows::cache::RepoCache;
+use crate::workflows::review::PendingReview;
+use crate::workflows::template::{render, template_values, with_template};
+use crate::workflows::validation::{is_object, validate_rendered};
+use crate::workflows::workflow::{
+    blocking, Deployment, DeploymentResult, DeploymentStatus, Workflow,
+};
+
+/// Label on every object applied for an assignment, with the assignment as its value. Objects are
+/// only pruned or deleted if they carry it, so objects owned by anyone else are never touched.
+pub const INVENTORY_LABEL: &str = "application-api.microsoft.com/assignment";
+
+/// Field manager for server-side apply.
+const FIELD_MANAGER: &str = "application-api";
+
+/// Namespace of rendered namespaced objects that don't specify one.
+const DEFAULT_NAMESPACE: &str = "default";
+
+/// Workflow backend for clusters without a GitOps agent: the template is rendered as it is for
+/// the GitOps repo, and every rendered object is server-side applied to the cluster directly,
+/// using the kubeconfig referenced by the `Cluster` resource.
+///
+/// Applied objects carry `INVENTORY_LABEL`, by which the objects that are no longer rendered are
+/// found and pruned, even those of a deployment that failed halfway, and by which all of them are
+/// deleted along with the assignment. The assignment's status records what was last applied.
+#[derive(Clone)]
+pub struct ApplyWorkflow {
+    /// Client for the cluster the operator runs in, used to read kubeconfig Secrets.
+    client: Client,
+    /// Working copies of template repos, reused across reconciles.
+    cache: Arc<RepoCache>,
+    /// Checks rendered files are valid manifests before anything is applied.
+    validation: ValidationConfig,
+}
+
+impl ApplyWorkflow {
+    pub fn new(client: Client, cache: Arc<RepoCache>, validation: &ValidationConfig) -> Self {
+        ApplyWorkflow {
+            client,
+            cache,
+            validation: validation.clone(),
+        }
+    }
+
+    /// Renders the assignment's template and parses the result into labeled objects.
+    fn render_objects(
+        &self,
+        deployment: &Deployment,
+    ) -> Result<(git2::Oid, Vec<DynamicObject>), Error> {
+        let label = inventory_label_value(&deployment.assignment);
+
+        with_template(
+            &self.cache,
+            &deployment.template,
+            |template_path, template_commit| {
+                let values = template_values(
+                    &deployment.application,
+                    &deployment.environment,
+                    &deployment.assignment,
+                    deployment.cluster.as_ref(),
+                    &deployment.resolved_values,
+                    template_path,
+                )?;
+
+                let output_dir = tempfile::tempdir()?;
+                let paths = render(
+                    template_path,
+                    output_dir.path(),
+                    Path::new(""),
+                    &values,
+                    deployment.template.spec.strict.unwrap_or_default(),
+                )?;
+                // a broken render is rejected as a whole rather than applied partially
+                validate_rendered(output_dir.path(), &paths, &self.validation)?;
+
+                let objects = parse_rendered(output_dir.path(), &paths, &label)?;
+
+                Ok((template_commit, objects))
+            },
+        )
+    }
+
+    /// Creates a client for the cluster an assignment is deployed to, from the kubeconfig Secret
+    /// its `Cluster` resource refers to.
+    async fn cluster_client(&self, cluster: Option<&Cluster>) -> Result<Client, Error> {
+        let cluster = cluster.ok_or_else(|| {
+            Error::UserInputError(
+                "the apply reconciler requires a Cluster resource with a kubeconfig".to_string(),
+            )
+        })?;
+        let cluster_name = cluster.name();
+
+        let kubeconfig = cluster.spec.kubeconfig.as_ref().ok_or_else(|| {
+            Error::UserInputError(format!("Cluster {} has no kubeconfig", cluster_name))
+        })?;
+        let key = kubeconfig.key.as_deref().unwrap_or("kubeconfig");
+
+        let secret_api: Api<Secret> = Api::namespaced(
+            self.client.clone(),
+            &cluster.namespace().unwrap_or_default(),
+        );
+        let secret = secret_api.get(&kubeconfig.secret_name).await?;
+
+        let data = secret
+            .data
+            .as_ref()
+            .and_then(|data| data.get(key))
+            .ok_or_else(|| {
+                Error::UserInputError(format!(
+                    "Secret {} of Cluster {} has no key {}",
+                    kubeconfig.secret_name, cluster_name, key
+                ))
+            })?;
+
+        let kubeconfig: Kubeconfig = serde_yaml::from_slice(&data.0).map_err(|err| {
+            Error::UserInputError(format!(
+                "kubeconfig of Cluster {} is invalid: {}",
+                cluster_name, err
+            ))
+        })?;
+
+        let config =
+            kube::Config::from_custom_kubeconfig(kubeconfig, &KubeConfigOptions::default()).await?;
+
+        Ok(Client::try_from(config)?)
+    }
+
+    /// Server-side applies `object` and returns its inventory entry and the applied object.
+    async fn apply_object(
+        &self,
+        client: &Client,
+        object: &DynamicObject,
+    ) -> Result<(InventoryEntry, DynamicObject), Error> {
+        let entry = InventoryEntry::from_object(object)?;
+        let api = entry.api(client).await?;
+
+        debug!("applying {}", entry);
+        let applied = api
+            .patch(
+                &entry.name,
+                &PatchParams::apply(FIELD_MANAGER).force(),
+                &Patch::Apply(object),
+            )
+            .await?;
+
+        Ok((entry, applied))
+    }
+
+    /// Lists the objects of every type on the cluster that are labeled with `label`.
+    async fn labeled_objects(
+        &self,
+        client: &Client,
+        label: &str,
+    ) -> Result<Vec<LabeledObject>, Error> {
+        let discovery = Discovery::new(client.clone()).run().await?;
+        let params = ListParams::default().labels(&format!("{}={}", INVENTORY_LABEL, label));
+
+        let mut objects: Vec<LabeledObject> = Vec::new();
+        for group in discovery.groups() {
+            for (resource, capabilities) in group.recommended_resources() {
+                if !capabilities.supports_operation(verbs::LIST) {
+                    continue;
+                }
+
+                let api: Api<DynamicObject> = Api::all_with(client.clone(), &resource);
+                let list = match api.list(&params).await {
+                    Ok(list) => list,
+                    // eg. types of an aggregated API that is unavailable
+                    Err(kube::Error::Api(response)) if response.code == 404 => continue,
+                    Err(err) => return Err(err.into()),
+                };
+
+                for object in list {
+                    // the same object can be served by more than one group, eg. events
+                    let uid = object.metadata.uid.clone();
+                    if objects.iter().any(|labeled| labeled.uid == uid) {
+                        continue;
+                    }
+
+                    objects.push(LabeledObject {
+                        entry: InventoryEntry {
+                            api_version: resource.api_version.clone(),
+                            kind: resource.kind.clone(),
+                            namespace: object.namespace(),
+                            name: object.name(),
+                        },
+                        resource: resource.clone(),
+                        uid,
+                        resource_version: object.resource_version(),
+                    });
+                }
+            }
+        }
+
+        Ok(objects)
+    }
+
+    /// Deletes a labeled object, if it still exists.
+    async fn delete_object(&self, client: &Client, object: &LabeledObject) -> Result<(), Error> {
+        let api: Api<DynamicObject> = match &object.entry.namespace {
+            Some(namespace) => Api::namespaced_with(client.clone(), namespace, &object.resource),
+            None => Api::all_with(client.clone(), &object.resource),
+        };
+
+        debug!("deleting {}", object.entry);
+        match api
+            .delete(&object.entry.name, &DeleteParams::default())
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(kube::Error::Api(response)) if response.code == 404 => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl Workflow for ApplyWorkflow {
+    async fn create(&self, deployment: &Deployment) -> Result<DeploymentResult, Error> {
+        // rendering checks the template out with git2, which blocks
+        let workflow = self.clone();
+        let owned = deployment.clone();
+        let (template_commit, objects) = blocking(move || workflow.render_objects(&owned)).await?;
+        let client = self.cluster_client(deployment.cluster.as_ref()).await?;
+        let label = inventory_label_value(&deployment.assignment);
+        let labeled = self.labeled_objects(&client, &label).await?;
+
+        let mut inventory = Vec::new();
+        let mut applied = Vec::new();
+        for object in objects.iter() {
+            let (entry, object) = self.apply_object(&client, object).await?;
+            inventory.push(entry.to_string());
+            applied.push(object);
+        }
+
+        // prune what earlier deployments applied but this one no longer contains
+        let stale = stale_objects(&labeled, &applied);
+        for object in stale.iter() {
+            self.delete_object(&client, object).await?;
+        }
+
+        let previous: Vec<String> = previous_inventory(&deployment.assignment)?
+            .iter()
+            .map(InventoryEntry::to_string)
+            .collect();
+        let changed =
+            !stale.is_empty() || inventory != previous || applied_changes(&labeled, &applied);
+
+        Ok(DeploymentResult {
+            template_commit,
+            commit: None,
+            changed,
+            review: None,
+            inventory: Some(inventory),
+        })
+    }
+
+    /// Server-side apply converges existing objects too, so updating is the same as creating.
+    async fn update(&self, deployment: &Deployment) -> Result<DeploymentResult, Error> {
+        self.create(deployment).await
+    }
+
+    async fn delete(&self, deployment: &Deployment) -> Result<Option<PendingReview>, Error> {
+        let client = self.cluster_client(deployment.cluster.as_ref()).await?;
+        let label = inventory_label_value(&deployment.assignment);
+
+        for object in self.labeled_objects(&client, &label).await? {
+            self.delete_object(&client, &object).await?;
+        }
+
+        Ok(None)
+    }
+
+    async fn status(&self, deployment: &Deployment) -> Result<DeploymentStatus, Error> {
+        let client = self.cluster_client(deployment.cluster.as_ref()).await?;
+
+        for entry in previous_inventory(&deployment.assignment)? {
+            match entry.api(&client).await?.get(&entry.name).await {
+                Ok(_) => {}
+                Err(kube::Error::Api(response)) if response.code == 404 => {
+                    debug!("{} is missing", entry);
+                    return Ok(DeploymentStatus::Missing);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Ok(DeploymentStatus::Deployed)
+    }
+}
+
+/// An object on a cluster labeled with `INVENTORY_LABEL` for an assignment.
+struct LabeledObject {
+    entry: InventoryEntry,
+    /// Type of the object, as discovered.
+    resource: ApiResource,
+    uid: Option<String>,
+    resource_version: Option<String>,
+}
+
+/// An object applied to a cluster, recorded in the assignment's status as
+/// `apiVersion/kind/namespace/name`, with an empty namespace for cluster scoped objects.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+struct InventoryEntry {
+    api_version: String,
+    kind: String,
+    namespace: Option<String>,
+    name: String,
+}
+
+impl InventoryEntry {
+    fn from_object(object: &DynamicObject) -> Result<Self, Error> {
+        let types = object.types.as_ref().ok_or_else(|| {
+            Error::UserInputError(format!(
+                "object {} has no apiVersion or kind",
+                object.name()
+            ))
+        })?;
+
+        Ok(InventoryEntry {
+            api_version: types.api_version.clone(),
+            kind: types.kind.clone(),
+            namespace: object.namespace(),
+            name: object.name(),
+        })
+    }
+
+    fn parse(entry: &str) -> Result<Self, Error> {
+        let mut parts = entry.rsplitn(4, '/');
+
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(namespace), Some(kind), Some(api_version)) => Ok(InventoryEntry {
+                api_version: api_version.to_string(),
+                kind: kind.to_string(),
+                namespace: Some(namespace.to_string()).filter(|namespace| !namespace.is_empty()),
+                name: name.to_string(),
+            }),
+            _ => Err(Error::UserInputError(format!(
+                "invalid inventory entry '{}'",
+                entry
+            ))),
+        }
+    }
+
+    /// Returns an API for the object's type, namespaced for namespaced types.
+    async fn api(&self, client: &Client) -> Result<Api<DynamicObject>, Error> {
+        let (group, version) = match self.api_version.split_once('/') {
+            Some((group, version)) => (group, version),
+            None => ("", self.api_version.as_str()),
+        };
+        let gvk = GroupVersionKind::gvk(group, version, &self.kind);
+        let (resource, capabilities) = discovery::pinned_kind(client, &gvk).await?;
+
+        Ok(match capabilities.scope {
+            Scope::Namespaced => Api::namespaced_with(
+                client.clone(),
+                self.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE),
+                &resource,
+            ),
+            Scope::Cluster => Api::all_with(client.clone(), &resource),
+        })
+    }
+}
+
+impl fmt::Display for InventoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}/{}/{}",
+            self.api_version,
+            self.kind,
+            self.namespace.as_deref().unwrap_or_default(),
+            self.name
+        )
+    }
+}
+
+/// Parses the rendered YAML files into objects labeled with `label`. Other files are skipped.
+///
+/// # Arguments
+/// - `output_path` - Directory the files were rendered into.
+/// - `paths` - The rendered files, relative to `output_path`.
+/// - `label` - Value of the inventory label.
+fn parse_rendered(
+    output_path: &Path,
+    paths: &[PathBuf],
+    label: &str,
+) -> Result<Vec<DynamicObject>, Error> {
+    let mut objects = Vec::new();
+    for path in paths {
+        let is_yaml = matches!(
+            path.extension().and_then(|extension| extension.to_str()),
+            Some("yaml") | Some("yml")
+        );
+        if !is_yaml {
+            continue;
+        }
+
+        let manifest = std::fs::read_to_string(output_path.join(path))?;
+        objects.append(&mut parse_manifest(path, &manifest, label)?);
+    }
+
+    Ok(objects)
+}
+
+/// Parses a rendered, possibly multi-document, YAML manifest into objects labeled with `label`.
+/// Empty documents and documents that aren't objects, such as a `kustomization.yaml`, are
+/// skipped: templates are applied object by object, without building them with kustomize.
+fn parse_manifest(path: &Path, manifest: &str, label: &str) -> Result<Vec<DynamicObject>, Error> {
+    let mut objects = Vec::new();
+
+    for document in serde_yaml::Deserializer::from_str(manifest) {
+        let document = serde_yaml::Value::deserialize(document).map_err(|err| {
+            Error::UserInputError(format!("{} is not valid YAML: {}", path.display(), err))
+        })?;
+
+        if !is_object(&document) {
+            continue;
+        }
+
+        let value = serde_json::to_value(document).map_err(|err| {
+            Error::UserInputError(format!(
+                "{} contains an invalid object: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        let mut object: DynamicObject = serde_json::from_value(value).map_err(|err| {
+            Error::UserInputError(format!(
+                "{} contains an invalid object: {}",
+                path.display(),
+                err
+            ))
+        })?;
+
+        let has_type = matches!(&object.types, Some(types) if !types.api_version.is_empty() && !types.kind.is_empty());
+        if !has_type || object.metadata.name.is_none() {
+            return Err(Error::UserInputError(format!(
+                "{} contains an object without apiVersion, kind or metadata.name",
+                path.display()
+            )));
+        }
+
+        object
+            .metadata
+            .labels
+            .get_or_insert_with(Default::default)
+            .insert(INVENTORY_LABEL.to_string(), label.to_string());
+
+        objects.push(object);
+    }
+
+    Ok(objects)
+}
+
+/// Inventory recorded in the assignment's status by the previous deployment.
+fn previous_inventory(assignment: &ApplicationAssignment) -> Result<Vec<InventoryEntry>, Error> {
+    assignment
+        .status
+        .iter()
+        .flat_map(|status| status.inventory.iter().flatten())
+        .map(|entry| InventoryEntry::parse(entry))
+        .collect()
+}
+
+/// Labeled objects that are not among the `applied` ones.
+fn stale_objects<'a>(
+    labeled: &'a [LabeledObject],
+    applied: &[DynamicObject],
+) -> Vec<&'a LabeledObject> {
+    labeled
+        .iter()
+        .filter(|labeled| {
+            !applied
+                .iter()
+                .any(|object| object.metadata.uid == labeled.uid)
+        })
+        .collect()
+}
+
+/// Whether applying changed any object: server-side apply leaves the `resourceVersion` of objects
+/// it doesn't change as it was.
+fn applied_changes(labeled: &[LabeledObject], applied: &[DynamicObject]) -> bool {
+    applied.iter().any(|object| {
+        !labeled.iter().any(|labeled| {
+            labeled.uid == object.metadata.uid
+                && labeled.resource_version == object.metadata.resource_version
+        })
+    })
+}
+
+/// Value of `INVENTORY_LABEL` for an assignment. Label values are limited to 63 characters, so
+/// longer names are shortened and made unique with a hash. The hash has to stay the same across
+/// operator versions, hence FNV-1a rather than the standard library's hasher.
+fn inventory_label_value(assignment: &ApplicationAssignment) -> String {
+    let value = format!(
+        "{}.{}",
+        assignment.namespace().unwrap_or_default(),
+        assignment.name()
+    );
+
+    if value.len() <= 63 {
+        return value;
+    }
+
+    let hash = value.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+
+    let prefix: String = value.chars().take(46).collect();
+    format!("{}-{:016x}", prefix.trim_end_matches(['.', '-']), hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::ConfigMap;
+    use kube::core::DynamicObject;
+    use kube::discovery::ApiResource;
+    use serde_json::json;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    use crate::models::assignment::{ApplicationAssignment, ApplicationAssignmentSpec};
+    use crate::utils::config::ValidationConfig;
+    use crate::workflows::template::render;
+    use crate::workflows::validation::validate_rendered;
+
+    use super::{
+        applied_changes, inventory_label_value, parse_manifest, parse_rendered, stale_objects,
+        InventoryEntry, LabeledObject, INVENTORY_LABEL,
+    };
+
+    #[test]
+    fn parses_and_labels_rendered_objects() {
+        let manifest = "
+apiVersion: v1
+kind: Namespace
+metadata:
+  name: cluster-agent
+---
+---
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: cluster-agent
+  namespace: cluster-agent
+  labels:
+    app: cluster-agent
+";
+
+        let objects = parse_manifest(Path::new("app.yaml"), manifest, "default.agent").unwrap();
+        assert_eq!(objects.len(), 2);
+
+        let entries: Vec<String> = objects
+            .iter()
+            .map(|object| InventoryEntry::from_object(object).unwrap().to_string())
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                "v1/Namespace//cluster-agent",
+                "apps/v1/Deployment/cluster-agent/cluster-agent"
+            ]
+        );
+
+        let labels = objects[1].metadata.labels.as_ref().unwrap();
+        assert_eq!(labels[INVENTORY_LABEL], "default.agent");
+        assert_eq!(labels["app"], "cluster-agent");
+
+        let unnamed = "apiVersion: v1\nkind: ConfigMap\n";
+        assert!(parse_manifest(Path::new("app.yaml"), unnamed, "default.agent").is_err());
+    }
+
+    #[test]
+    fn parses_rendered_kustomize_templates() {
+        let output_dir = tempdir().unwrap();
+        let values = json!({ "CLUSTER_NAME": "ours" });
+        let paths = render(
+            Path::new("fixtures/template"),
+            output_dir.path(),
+            Path::new(""),
+            &values,
+            false,
+        )
+        .unwrap();
+        validate_rendered(output_dir.path(), &paths, &ValidationConfig::default()).unwrap();
+
+        // the kustomization.yaml is not applied
+        let objects = parse_rendered(output_dir.path(), &paths, "default.agent").unwrap();
+        let entries: Vec<String> = objects
+            .iter()
+            .map(|object| InventoryEntry::from_object(object).unwrap().to_string())
+            .collect();
+        assert_eq!(
+            entries,
+            vec!["helm.toolkit.fluxcd.io/v2beta1/HelmRelease/default/cluster-agent"]
+        );
+    }
+
+    #[test]
+    fn prunes_labeled_objects_that_are_no_longer_applied() {
+        let resource = ApiResource::erase::<ConfigMap>(&());
+        let labeled_object = |name: &str, uid: &str| LabeledObject {
+            entry: InventoryEntry::parse(&format!("v1/ConfigMap/cluster-agent/{}", name)).unwrap(),
+            resource: resource.clone(),
+            uid: Some(uid.to_string()),
+            resource_version: Some("1".to_string()),
+        };
+        let applied_object = |name: &str, uid: &str, resource_version: &str| {
+            let mut object = DynamicObject::new(name, &resource).within("cluster-agent");
+            object.metadata.uid = Some(uid.to_string());
+            object.metadata.resource_version = Some(resource_version.to_string());
+            object
+        };
+
+        // labeled by an earlier deployment, or one that failed before recording its inventory
+        let labeled = vec![
+            labeled_object("config", "a"),
+            labeled_object("settings", "b"),
+        ];
+
+        let applied = vec![applied_object("config", "a", "1")];
+        let stale = stale_objects(&labeled, &applied);
+        assert_eq!(stale.len(), 1);
+        assert_eq!(
+            stale[0].entry.to_string(),
+            "v1/ConfigMap/cluster-agent/settings"
+        );
+        assert!(!applied_changes(&labeled, &applied));
+
+        let applied = vec![
+            applied_object("config", "a", "2"),
+            applied_object("settings", "b", "1"),
+        ];
+        assert!(stale_objects(&labeled, &applied).is_empty());
+        assert!(applied_changes(&labeled, &applied));
+        assert!(applied_changes(
+            &labeled,
+            &[applied_object("new", "c", "1")]
+        ));
+
+        assert!(InventoryEntry::parse("Namespace/cluster-agent").is_err());
+        assert_eq!(
+            InventoryEntry::parse("v1/Namespace//cluster-agent")
+                .unwrap()
+                .namespace,
+            None
+        );
+    }
+
+    #[test]
+    fn shortens_long_inventory_label_values() {
+        let mut assignment = ApplicationAssignment::new(
+            &"a".repeat(80),
+            ApplicationAssignmentSpec {
+                cluster: "azure-eastus2-1".to_string(),
+                environment: "dev".to_string(),
+                values: None,
+                values_from: None,
+            },
+        );
+        assignment.metadata.namespace = Some("default".to_string());
+
+        let value = inventory_label_value(&assignment);
+        assert_eq!(value.len(), 63);
+        assert_eq!(value, inventory_label_value(&assignment));
+    }
+}
@@ -0,0 +1,343 @@
+use git2::{Index, Repository};
+use serde_json::json;
+use std::path::PathBuf;
+
+use crate::models::application::Application;
+use crate::models::argocd::ArgoCdSpec;
+use crate::models::environment::ApplicationEnvironment;
+use crate::utils::error::Error;
+use crate::workflows::linker::{remove_file, AssignmentOutput, Linker};
+
+/// Directory, within a cluster's directory, that the Argo CD `Application`s are written to. An
+/// app-of-apps `Application` on the cluster points at it.
+pub const APPLICATIONS_DIRECTORY: &str = "argocd";
+
+/// Namespace Argo CD watches for `Application`s.
+const ARGOCD_NAMESPACE: &str = "argocd";
+
+/// Argo CD project the `Application`s belong to.
+const ARGOCD_PROJECT: &str = "default";
+
+const DEFAULT_NAMESPACE: &str = "default";
+
+/// Writes an Argo CD `Application` per assignment, pointing at its rendered output, to
+/// `<cluster>/argocd/<assignment>.yaml`.
+pub struct ArgoCdLinker;
+
+impl ArgoCdLinker {
+    fn application_path(&self, output: &AssignmentOutput) -> PathBuf {
+        output
+            .cluster_path
+            .join(APPLICATIONS_DIRECTORY)
+            .join(format!(
+                "{}.yaml",
+                output
+                    .assignment
+                    .metadata
+                    .name
+                    .as_deref()
+                    .unwrap_or_default()
+            ))
+    }
+
+    /// Builds the Argo CD `Application` that syncs an assignment's rendered output to its cluster.
+    fn application(&self, output: &AssignmentOutput, argocd: &ArgoCdSpec) -> String {
+        let mut sync_policy = json!({
+            "automated": {
+                "prune": true,
+                "selfHeal": true,
+            },
+        });
+        if argocd.create_namespace.unwrap_or_default() {
+            sync_policy["syncOptions"] = json!(["CreateNamespace=true"]);
+        }
+
+        let application = json!({
+            "apiVersion": "argoproj.io/v1alpha1",
+            "kind": "Application",
+            "metadata": {
+                "name": output.assignment.metadata.name,
+                "namespace": ARGOCD_NAMESPACE,
+                "labels": {
+                    "app.kubernetes.io/managed-by": "application-api",
+                },
+                "finalizers": ["resources-finalizer.argocd.argoproj.io"],
+            },
+            "spec": {
+                "project": ARGOCD_PROJECT,
+                "source": {
+                    "repoURL": output.target.repo,
+                    "targetRevision": output.target.branch,
+                    "path": output.path.to_string_lossy(),
+                },
+                "destination": {
+                    "name": output.assignment.spec.cluster,
+                    "namespace": argocd.namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE),
+                },
+                "syncPolicy": sync_policy,
+            },
+        });
+
+        // JSON values always serialize to YAML
+        serde_yaml::to_string(&application).unwrap()
+    }
+}
+
+impl Linker for ArgoCdLinker {
+    fn link(
+        &self,
+        repo: &Repository,
+        _index: &mut Index,
+        output: &AssignmentOutput,
+        application: &Application,
+        environment: &ApplicationEnvironment,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let argocd = application
+            .spec
+            .argocd
+            .clone()
+            .unwrap_or_default()
+            .merge(&environment.spec.argocd.clone().unwrap_or_default());
+
+        let application_relative_path = self.application_path(output);
+        let application_path = repo.workdir().unwrap().join(&application_relative_path);
+
+        std::fs::create_dir_all(application_path.parent().unwrap())?;
+        std::fs::write(application_path, self.application(output, &argocd))?;
+
+        Ok(vec![application_relative_path])
+    }
+
+    fn unlink(
+        &self,
+        repo: &Repository,
+        index: &mut Index,
+        output: &AssignmentOutput,
+    ) -> Result<Vec<PathBuf>, Error> {
+        remove_file(repo, index, &self.application_path(output))?;
+
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::{Repository, RepositoryInitOptions};
+    use serde_json::Map;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    use crate::models::application::{Application, ApplicationSpec};
+    use crate::models::argocd::ArgoCdSpec;
+    use crate::models::assignment::{ApplicationAssignment, ApplicationAssignmentSpec};
+    use crate::models::cluster::{Cluster, ClusterSpec};
+    use crate::models::environment::{ApplicationEnvironment, ApplicationEnvironmentSpec};
+    use crate::models::template::{ApplicationTemplate, ApplicationTemplateSpec};
+    use crate::utils::config::{GitopsConfig, ValidationConfig};
+    use crate::workflows::cache::RepoCache;
+    use crate::workflows::encryption::Encryption;
+    use crate::workflows::gitops::GitopsWorkflow;
+    use crate::workflows::review::BranchChangeRequestProvider;
+    use crate::workflows::template::ResolvedValues;
+    use crate::workflows::testing::commit_file;
+
+    use super::ArgoCdLinker;
+
+    fn read_blob(repo: &Repository, path: &str) -> Option<String> {
+        let tree = repo
+            .revparse_single("refs/heads/main")
+            .unwrap()
+            .peel_to_tree()
+            .unwrap();
+        let entry = tree.get_path(std::path::Path::new(path)).ok()?;
+        let blob = entry.to_object(repo).unwrap().peel_to_blob().unwrap();
+
+        Some(String::from_utf8(blob.content().to_vec()).unwrap())
+    }
+
+    #[test]
+    fn writes_argocd_application_per_assignment() {
+        let template_dir = tempdir().unwrap();
+        let template_repo = Repository::init(template_dir.path()).unwrap();
+        commit_file(
+            &template_repo,
+            "templates/configmap.yaml",
+            "apiVersion: v1\nkind: ConfigMap\nmetadata:\n  name: {{clusterName}}\n",
+        );
+
+        let origin_dir = tempdir().unwrap();
+        let origin = Repository::init_opts(
+            origin_dir.path(),
+            RepositoryInitOptions::new().initial_head("main").bare(true),
+        )
+        .unwrap();
+        let url = origin_dir.path().to_str().unwrap();
+
+        let seed_dir = tempdir().unwrap();
+        let seed = Repository::init_opts(
+            seed_dir.path(),
+            RepositoryInitOptions::new().initial_head("main"),
+        )
+        .unwrap();
+        commit_file(&seed, "README.md", "gitops");
+        seed.remote("origin", url)
+            .unwrap()
+            .push(&["refs/heads/main:refs/heads/main"], None)
+            .unwrap();
+
+        let cache_dir = tempdir().unwrap();
+        let cache = Arc::new(RepoCache::new(cache_dir.path().join("repos"), 8).unwrap());
+        let config = GitopsConfig {
+            repo: url.to_string(),
+            path: "clusters".to_string(),
+            ..GitopsConfig::default()
+        };
+        let workflow = GitopsWorkflow::new(
+            &config,
+            cache,
+            Box::new(BranchChangeRequestProvider::new()),
+            Box::new(ArgoCdLinker),
+            Arc::new(Encryption::default()),
+            &ValidationConfig::default(),
+        )
+        .unwrap();
+
+        let template = ApplicationTemplate::new(
+            "configmap",
+            ApplicationTemplateSpec {
+                repo: template_dir.path().to_str().unwrap().to_string(),
+                reference: None,
+                path: "templates".to_string(),
+                strict: None,
+            },
+        );
+        let application = Application::new(
+            "cluster-agent",
+            ApplicationSpec {
+                template: "configmap".to_string(),
+                values: Some(Map::new()),
+                values_from: None,
+                reconciler: Some("argocd".to_string()),
+                flux: None,
+                argocd: Some(ArgoCdSpec {
+                    namespace: Some("agents".to_string()),
+                    create_namespace: None,
+                }),
+            },
+        );
+        let environment = ApplicationEnvironment::new(
+            "cluster-agent-dev",
+            ApplicationEnvironmentSpec {
+                application: "cluster-agent".to_string(),
+                environment: "dev".to_string(),
+                selector: None,
+                clusters: None,
+                spread_by: None,
+                values: None,
+                values_from: None,
+                flux: None,
+                argocd: Some(ArgoCdSpec {
+                    create_namespace: Some(true),
+                    ..ArgoCdSpec::default()
+                }),
+            },
+        );
+        let assignment = ApplicationAssignment::new(
+            "ours-cluster-agent-dev",
+            ApplicationAssignmentSpec {
+                cluster: "ours".to_string(),
+                environment: "cluster-agent-dev".to_string(),
+                values: None,
+                values_from: None,
+            },
+        );
+
+        let cluster = Cluster::new(
+            "ours",
+            ClusterSpec {
+                name: "ours".to_string(),
+                labels: HashMap::new(),
+                environments: vec![],
+                facts: None,
+                gitops: None,
+                kubeconfig: None,
+            },
+        );
+
+        let deployed = workflow
+            .create_deployment(
+                &application,
+                &template,
+                &environment,
+                &assignment,
+                Some(&cluster),
+                &ResolvedValues::default(),
+            )
+            .unwrap();
+        assert!(deployed.changed);
+
+        let rendered = read_blob(
+            &origin,
+            "clusters/ours/ours-cluster-agent-dev/configmap.yaml",
+        );
+        assert!(rendered.unwrap().contains("name: ours"));
+
+        let argocd_application: serde_yaml::Value = serde_yaml::from_str(
+            &read_blob(&origin, "clusters/ours/argocd/ours-cluster-agent-dev.yaml").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(argocd_application["apiVersion"], "argoproj.io/v1alpha1");
+        assert_eq!(argocd_application["kind"], "Application");
+        assert_eq!(
+            argocd_application["metadata"]["name"],
+            "ours-cluster-agent-dev"
+        );
+        assert_eq!(argocd_application["metadata"]["namespace"], "argocd");
+        assert_eq!(
+            argocd_application["metadata"]["labels"]["app.kubernetes.io/managed-by"],
+            "application-api"
+        );
+        assert_eq!(
+            argocd_application["metadata"]["finalizers"][0],
+            "resources-finalizer.argocd.argoproj.io"
+        );
+        assert_eq!(argocd_application["spec"]["project"], "default");
+        assert_eq!(argocd_application["spec"]["source"]["repoURL"], url);
+        assert_eq!(
+            argocd_application["spec"]["source"]["targetRevision"],
+            "main"
+        );
+        assert_eq!(
+            argocd_application["spec"]["source"]["path"],
+            "clusters/ours/ours-cluster-agent-dev"
+        );
+        assert_eq!(argocd_application["spec"]["destination"]["name"], "ours");
+        // the namespace of the application, with the environment's setting merged over it
+        assert_eq!(
+            argocd_application["spec"]["destination"]["namespace"],
+            "agents"
+        );
+        let sync_policy = &argocd_application["spec"]["syncPolicy"];
+        assert_eq!(sync_policy["automated"]["prune"], true);
+        assert_eq!(sync_policy["automated"]["selfHeal"], true);
+        assert_eq!(sync_policy["syncOptions"][0], "CreateNamespace=true");
+        assert_eq!(read_blob(&origin, "clusters/ours/kustomization.yaml"), None);
+
+        workflow
+            .delete_deployment(&assignment, Some(&cluster))
+            .unwrap();
+
+        assert_eq!(
+            read_blob(&origin, "clusters/ours/argocd/ours-cluster-agent-dev.yaml"),
+            None
+        );
+        assert_eq!(
+            read_blob(
+                &origin,
+                "clusters/ours/ours-cluster-agent-dev/configmap.yaml"
+            ),
+            None
+        );
+    }
+}
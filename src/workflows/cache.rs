@@ -0,0 +1,321 @@
+use git2::{Cred, FetchOptions, FetchPrune, RemoteCallbacks, Repository};
+use log::{debug, warn};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::utils::error::Error;
+
+/// Directory within the configured cache path that holds the working copies.
+pub const CACHE_DIRECTORY: &str = "application-api-repos";
+
+/// File marking `CACHE_DIRECTORY` as created by this service, and so safe to remove.
+const CACHE_MARKER_FILE: &str = ".application-api-cache";
+
+/// A working copy of a remote repo. The mutex serializes everyone using the same working copy.
+type CacheEntry = Arc<Mutex<Option<Repository>>>;
+
+/// Cache of local working copies of remote Git repos, so that template and GitOps repos are
+/// fetched rather than cloned from scratch on every reconcile.
+///
+/// Working copies are keyed by repo URL and a caller chosen key, such as the template reference or
+/// GitOps branch, and only one caller at a time may use a given working copy. When the cache grows
+/// beyond `max_entries`, the least recently used working copies not currently in use are removed.
+pub struct RepoCache {
+    root: PathBuf,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, (CacheEntry, Instant)>>,
+}
+
+impl RepoCache {
+    /// Creates a cache with its working copies in the `CACHE_DIRECTORY` under `path`. Anything
+    /// left in it by a previous process is removed, as nothing tracks whether it is still
+    /// consistent. Fails rather than removing a non-empty directory that lacks the marker file
+    /// the cache creates, so a misconfigured `path` can't wipe anything else.
+    pub fn new(path: PathBuf, max_entries: usize) -> Result<RepoCache, Error> {
+        let root = path.join(CACHE_DIRECTORY);
+        let marker = root.join(CACHE_MARKER_FILE);
+
+        if root.exists() {
+            let is_empty = std::fs::read_dir(&root)?.next().is_none();
+            if !is_empty && !marker.exists() {
+                return Err(Error::ConfigError(format!(
+                    "cache directory {:?} was not created by application-api, refusing to remove it",
+                    root
+                )));
+            }
+            std::fs::remove_dir_all(&root)?;
+        }
+        std::fs::create_dir_all(&root)?;
+        std::fs::write(&marker, "")?;
+
+        Ok(RepoCache {
+            root,
+            max_entries,
+            entries: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Runs `f` with exclusive access to an up to date working copy of the repo at `url`.
+    ///
+    /// The working copy is cloned on first use and fetched on every use after that. Callers are
+    /// responsible for checking out the revision they need, as the checked out tree is left as
+    /// the previous user of the working copy left it.
+    ///
+    /// # Arguments
+    /// - `url` - URL of the remote repo.
+    /// - `key` - Distinguishes working copies of the same repo, eg. per branch.
+    /// - `fetch_options` - Options, including credentials, for the clone or fetch.
+    /// - `f` - Function to run with the working copy.
+    pub fn with_repo<T, F>(
+        &self,
+        url: &str,
+        key: &str,
+        fetch_options: FetchOptions<'_>,
+        f: F,
+    ) -> Result<T, Error>
+    where
+        F: FnOnce(&Repository) -> Result<T, Error>,
+    {
+        let cache_key = format!("{} {}", url, key);
+        let entry = self.entry(&cache_key);
+
+        // a poisoned lock only means an earlier user panicked; the working copy is re-synced below
+        let mut repo = entry
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let path = self.root.join(directory_name(&cache_key));
+        match repo.as_ref() {
+            Some(existing) => fetch(existing, fetch_options)?,
+            None => *repo = Some(open_or_clone(url, &path, fetch_options)?),
+        }
+
+        let result = f(repo.as_ref().unwrap());
+
+        drop(repo);
+        self.evict();
+
+        result
+    }
+
+    fn entry(&self, cache_key: &str) -> CacheEntry {
+        let mut entries = self.entries.lock().unwrap();
+
+        let (entry, last_used) = entries
+            .entry(cache_key.to_string())
+            .or_insert_with(|| (Arc::new(Mutex::new(None)), Instant::now()));
+        *last_used = Instant::now();
+
+        entry.clone()
+    }
+
+    /// Removes the least recently used working copies until the cache is within its size limit.
+    /// Working copies that are in use are never removed.
+    fn evict(&self) {
+        let mut entries = self.entries.lock().unwrap();
+
+        while entries.len() > self.max_entries {
+            // entries are only handed out while holding the map lock, so an entry referenced only
+            // by the map is not in use and cannot become so while we hold the lock.
+            let idle = entries
+                .iter()
+                .filter(|(_, (entry, _))| Arc::strong_count(entry) == 1)
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(cache_key, _)| cache_key.clone());
+
+            let cache_key = match idle {
+                Some(cache_key) => cache_key,
+                None => break,
+            };
+
+            entries.remove(&cache_key);
+
+            let path = self.root.join(directory_name(&cache_key));
+            debug!("evicting cached repo {} at {:?}", cache_key, path);
+            if let Err(err) = std::fs::remove_dir_all(&path) {
+                warn!("failed to remove cached repo {:?}: {}", path, err);
+            }
+        }
+    }
+}
+
+fn open_or_clone(
+    url: &str,
+    path: &std::path::Path,
+    mut fetch_options: FetchOptions<'_>,
+) -> Result<Repository, Error> {
+    if let Ok(repo) = Repository::open(path) {
+        fetch(&repo, fetch_options)?;
+        return Ok(repo);
+    }
+
+    if path.exists() {
+        std::fs::remove_dir_all(path)?;
+    }
+
+    fetch_options.prune(FetchPrune::On);
+
+    let mut builder = git2::build::RepoBuilder::new();
+    builder.fetch_options(fetch_options);
+
+    Ok(builder.clone(url, path)?)
+}
+
+/// Callbacks that authenticate with the SSH key mounted under `SECRETS_PATH`.
+pub fn auth_callbacks<'a>() -> RemoteCallbacks<'a> {
+    // Prepare callbacks.
+    let mut callbacks = RemoteCallbacks::new();
+
+    callbacks.credentials(|_url, username_from_url, _allowed_types| {
+        let secrets_path = match env::var("SECRETS_PATH") {
+            Ok(secrets_path) => secrets_path,
+            Err(_) => "/mnt/secrets_store".to_string(),
+        };
+
+        let private_ssh_key_path = std::path::Path::new(&secrets_path).join("git-ssh-private-key");
+
+        Cred::ssh_key(
+            username_from_url.unwrap(),
+            None,
+            &private_ssh_key_path,
+            None,
+        )
+    });
+
+    callbacks
+}
+
+/// Fetch options that authenticate with `auth_callbacks`.
+pub fn fetch_options<'a>() -> FetchOptions<'a> {
+    let mut fetch_options = FetchOptions::new();
+    fetch_options.remote_callbacks(auth_callbacks());
+
+    fetch_options
+}
+
+/// Fetches all branches and tags of the `origin` remote of `repo`.
+pub fn fetch(repo: &Repository, mut fetch_options: FetchOptions<'_>) -> Result<(), Error> {
+    fetch_options.prune(FetchPrune::On);
+
+    let mut remote = repo.find_remote("origin")?;
+    remote.fetch(
+        &[
+            "+refs/heads/*:refs/remotes/origin/*",
+            "+refs/tags/*:refs/tags/*",
+        ],
+        Some(&mut fetch_options),
+        None,
+    )?;
+
+    Ok(())
+}
+
+/// Directory name for a cache key: a readable prefix plus a hash to keep distinct keys apart.
+fn directory_name(cache_key: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    cache_key.hash(&mut hasher);
+
+    let readable: String = cache_key
+        .rsplit(['/', ':'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(40)
+        .collect();
+
+    format!("{}-{:016x}", readable, hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::{FetchOptions, Repository};
+    use tempfile::tempdir;
+
+    use crate::workflows::testing::commit_file;
+
+    use super::{RepoCache, CACHE_DIRECTORY};
+
+    #[test]
+    fn reuses_and_fetches_working_copies() {
+        let origin_dir = tempdir().unwrap();
+        let origin = Repository::init(origin_dir.path()).unwrap();
+        let first = commit_file(&origin, "release.yaml", "version: 1");
+        let url = origin_dir.path().to_str().unwrap();
+
+        let cache_dir = tempdir().unwrap();
+        let cache = RepoCache::new(cache_dir.path().join("repos"), 10).unwrap();
+
+        let first_path = cache
+            .with_repo(url, "main", FetchOptions::new(), |repo| {
+                assert_eq!(repo.head().unwrap().target(), Some(first));
+                Ok(repo.path().to_path_buf())
+            })
+            .unwrap();
+
+        let second = commit_file(&origin, "release.yaml", "version: 2");
+
+        let second_path = cache
+            .with_repo(url, "main", FetchOptions::new(), |repo| {
+                let fetched = repo.revparse_single("refs/remotes/origin/HEAD").unwrap();
+                assert_eq!(fetched.id(), second);
+                Ok(repo.path().to_path_buf())
+            })
+            .unwrap();
+
+        assert_eq!(first_path, second_path);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_working_copies() {
+        let origin_dir = tempdir().unwrap();
+        let origin = Repository::init(origin_dir.path()).unwrap();
+        commit_file(&origin, "release.yaml", "version: 1");
+        let url = origin_dir.path().to_str().unwrap();
+
+        let cache_dir = tempdir().unwrap();
+        let cache = RepoCache::new(cache_dir.path().join("repos"), 1).unwrap();
+
+        let first_path = cache
+            .with_repo(url, "first", FetchOptions::new(), |repo| {
+                Ok(repo.workdir().unwrap().to_path_buf())
+            })
+            .unwrap();
+        let second_path = cache
+            .with_repo(url, "second", FetchOptions::new(), |repo| {
+                Ok(repo.workdir().unwrap().to_path_buf())
+            })
+            .unwrap();
+
+        assert!(!first_path.exists());
+        assert!(second_path.exists());
+    }
+
+    #[test]
+    fn only_removes_its_own_directory() {
+        let cache_dir = tempdir().unwrap();
+        std::fs::write(cache_dir.path().join("keep.txt"), "kept").unwrap();
+
+        RepoCache::new(cache_dir.path().to_path_buf(), 1).unwrap();
+        let leftover = cache_dir.path().join(CACHE_DIRECTORY).join("leftover");
+        std::fs::write(&leftover, "removed").unwrap();
+
+        RepoCache::new(cache_dir.path().to_path_buf(), 1).unwrap();
+        assert!(!leftover.exists());
+        assert!(cache_dir.path().join("keep.txt").exists());
+
+        // a directory the cache didn't create is left alone
+        let foreign_dir = tempdir().unwrap();
+        let foreign = foreign_dir.path().join(CACHE_DIRECTORY);
+        std::fs::create_dir_all(&foreign).unwrap();
+        std::fs::write(foreign.join("data"), "kept").unwrap();
+
+        assert!(RepoCache::new(foreign_dir.path().to_path_buf(), 1).is_err());
+        assert!(foreign.join("data").exists());
+    }
+}
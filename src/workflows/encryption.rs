@@ -0,0 +1,560 @@
+use age::armor::{ArmoredWriter, Format};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::utils::config::{EncryptionConfig, EncryptionFormat};
+use crate::utils::error::Error;
+use crate::workflows::sops::SopsEncryptor;
+use crate::workflows::validation::is_kustomize_config;
+
+/// Encrypts rendered files that contain sensitive values before they are committed to a GitOps
+/// repo.
+pub trait Encryptor: Send + Sync {
+    /// Returns the encrypted replacement of a rendered file.
+    ///
+    /// # Arguments
+    /// - `path` - Path of the file, relative to the root of the GitOps repo.
+    /// - `plaintext` - Rendered content of the file.
+    /// - `partial` - Whether documents without values to encrypt are left in plaintext, rather
+    ///   than refused, as in files encrypted because they carry values from Secrets.
+    fn encrypt(&self, path: &Path, plaintext: &[u8], partial: bool) -> Result<Vec<u8>, Error>;
+
+    /// Returns the keys and values of an encrypted file that are left readable.
+    fn readable_values(&self, _encrypted: &[u8]) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Encrypts whole files to age X25519 recipients. The output is ASCII armored, so that it can be
+/// committed as text and decrypted with `age --decrypt`.
+pub struct AgeEncryptor {
+    recipients: Vec<age::x25519::Recipient>,
+}
+
+impl AgeEncryptor {
+    /// # Arguments
+    /// - `recipients` - age public keys, eg. `age1...`, that can decrypt the files.
+    pub fn new(recipients: &[String]) -> Result<Self, Error> {
+        Ok(AgeEncryptor {
+            recipients: parse_recipients(recipients)?,
+        })
+    }
+}
+
+impl Encryptor for AgeEncryptor {
+    fn encrypt(&self, path: &Path, plaintext: &[u8], _partial: bool) -> Result<Vec<u8>, Error> {
+        age_encrypt(&self.recipients, plaintext).map_err(|err| {
+            Error::EncryptionError(format!("could not encrypt {}: {}", path.display(), err))
+        })
+    }
+}
+
+/// Parses age X25519 recipients, of which there has to be at least one.
+pub fn parse_recipients(recipients: &[String]) -> Result<Vec<age::x25519::Recipient>, Error> {
+    if recipients.is_empty() {
+        return Err(Error::ConfigError(
+            "at least one age recipient is required".to_string(),
+        ));
+    }
+
+    recipients
+        .iter()
+        .map(|recipient| {
+            recipient.parse().map_err(|err| {
+                Error::ConfigError(format!("invalid age recipient '{}': {}", recipient, err))
+            })
+        })
+        .collect()
+}
+
+/// Encrypts `plaintext` with age to `recipients`, ASCII armored.
+pub fn age_encrypt(
+    recipients: &[age::x25519::Recipient],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let recipients = recipients
+        .iter()
+        .map(|recipient| Box::new(recipient.clone()) as Box<dyn age::Recipient>)
+        .collect();
+
+    let mut encrypted = Vec::new();
+    let armored = ArmoredWriter::wrap_output(&mut encrypted, Format::AsciiArmor)?;
+    let mut writer = age::Encryptor::with_recipients(recipients)
+        .wrap_output(armored)
+        .map_err(|err| Error::EncryptionError(err.to_string()))?;
+    writer.write_all(plaintext)?;
+    writer.finish()?.finish()?;
+
+    Ok(encrypted)
+}
+
+/// Digest of the plaintext of a file, and its encrypted content.
+type EncryptedFile = (Vec<u8>, Vec<u8>);
+
+/// Decides which rendered files are encrypted, and encrypts them with the configured
+/// `Encryptor`.
+pub struct Encryption {
+    encryptor: Option<Box<dyn Encryptor>>,
+    /// Files that are always encrypted, relative to the assignment's output directory.
+    patterns: GlobSet,
+    /// Digest of the plaintext and the encrypted content of each file last encrypted. Encryption
+    /// is randomized, so reusing the previous content keeps unchanged files from being committed
+    /// again on every reconcile.
+    encrypted: Mutex<HashMap<PathBuf, EncryptedFile>>,
+}
+
+impl Default for Encryption {
+    /// No encryption: files that would be encrypted are refused.
+    fn default() -> Self {
+        Encryption {
+            encryptor: None,
+            patterns: GlobSet::empty(),
+            encrypted: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Encryption {
+    /// # Arguments
+    /// - `encryptor` - Encrypts files, if encryption is set up.
+    /// - `patterns` - Globs of files that are always encrypted, relative to the assignment's
+    ///   output directory.
+    pub fn new(encryptor: Option<Box<dyn Encryptor>>, patterns: &[String]) -> Result<Self, Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            let glob = Glob::new(pattern).map_err(|err| {
+                Error::ConfigError(format!("invalid encryption pattern '{}': {}", pattern, err))
+            })?;
+            builder.add(glob);
+        }
+        let patterns = builder
+            .build()
+            .map_err(|err| Error::ConfigError(format!("invalid encryption patterns: {}", err)))?;
+
+        Ok(Encryption {
+            encryptor,
+            patterns,
+            ..Encryption::default()
+        })
+    }
+
+    /// Sets up the encryption described by `config`. Without recipients, nothing can be
+    /// encrypted.
+    pub fn from_config(config: &EncryptionConfig) -> Result<Self, Error> {
+        let encryptor: Option<Box<dyn Encryptor>> = if config.age_recipients.is_empty() {
+            None
+        } else {
+            match config.format {
+                EncryptionFormat::Sops => Some(Box::new(SopsEncryptor::new(
+                    &config.age_recipients,
+                    &config.encrypted_regex,
+                )?)),
+                EncryptionFormat::Age => Some(Box::new(AgeEncryptor::new(&config.age_recipients)?)),
+            }
+        };
+
+        Encryption::new(encryptor, &config.patterns)
+    }
+
+    /// Encrypts, in place, the rendered files that match the configured patterns, and the
+    /// Kubernetes manifests that carry `secrets` (see `carries_secret`). Other files, kustomize
+    /// config files such as `kustomization.yaml` in particular, are left in plaintext, so that
+    /// kustomize and Flux can still build the output. Fails rather than leaving files in plaintext
+    /// if no encryptor is configured, if a secret is still readable after encryption, or if one
+    /// is found in a file that can't be encrypted.
+    ///
+    /// # Arguments
+    /// - `repo_root_path` - Root of the working copy the files were rendered into.
+    /// - `output_path` - The assignment's output directory, relative to `repo_root_path`.
+    /// - `paths` - Rendered files, relative to `repo_root_path`.
+    /// - `secrets` - Values taken from Secrets, see `ResolvedValues::secrets`.
+    pub fn encrypt_rendered(
+        &self,
+        repo_root_path: &Path,
+        output_path: &Path,
+        paths: &[PathBuf],
+        secrets: &[Value],
+    ) -> Result<(), Error> {
+        for path in paths {
+            let file_path = repo_root_path.join(path);
+            let content = fs::read(&file_path)?;
+
+            let matches_pattern = path
+                .strip_prefix(output_path)
+                .map(|path| self.patterns.is_match(path))
+                .unwrap_or_default();
+            let carries_secret = !matches_pattern && carries_secret(path, &content, secrets)?;
+            if !matches_pattern && !carries_secret {
+                continue;
+            }
+
+            let encryptor = self.encryptor.as_ref().ok_or_else(|| {
+                Error::UserInputError(format!(
+                    "{} has to be encrypted, but no encryption is configured",
+                    path.display()
+                ))
+            })?;
+
+            let encrypted =
+                self.encrypt_file(encryptor.as_ref(), path, &content, carries_secret)?;
+            let reveals_secret = encryptor
+                .readable_values(&encrypted)
+                .iter()
+                .any(|readable| secrets.iter().any(|secret| reveals(readable, secret)));
+            if reveals_secret {
+                return Err(Error::UserInputError(format!(
+                    "{} contains values from Secrets that are not encrypted",
+                    path.display()
+                )));
+            }
+
+            fs::write(&file_path, encrypted)?;
+        }
+
+        Ok(())
+    }
+
+    /// Encrypts a file, reusing the previous encrypted content if its plaintext is unchanged.
+    fn encrypt_file(
+        &self,
+        encryptor: &dyn Encryptor,
+        path: &Path,
+        plaintext: &[u8],
+        partial: bool,
+    ) -> Result<Vec<u8>, Error> {
+        let mut hasher = Sha256::new();
+        hasher.update(plaintext);
+        hasher.update([partial as u8]);
+        let digest = hasher.finalize().to_vec();
+
+        if let Some((previous_digest, encrypted)) = self.encrypted.lock().unwrap().get(path) {
+            if *previous_digest == digest {
+                return Ok(encrypted.clone());
+            }
+        }
+
+        let encrypted = encryptor.encrypt(path, plaintext, partial)?;
+        self.encrypted
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (digest, encrypted.clone()));
+
+        Ok(encrypted)
+    }
+}
+
+/// Whether a rendered file has to be encrypted because of values taken from Secrets: it is a
+/// Kubernetes manifest with a `Secret`, or one in which a string taken from a Secret appears.
+/// Numbers and booleans are too short to be told apart from other values, and values templates
+/// transform, eg. `{{upper password}}`, can't be traced, so those are only encrypted along with
+/// the rest of such a manifest. Fails if a string taken from a Secret appears in a file that isn't
+/// a manifest, as only manifests are encrypted.
+fn carries_secret(path: &Path, content: &[u8], secrets: &[Value]) -> Result<bool, Error> {
+    if secrets.is_empty() {
+        return Ok(false);
+    }
+
+    let text = String::from_utf8_lossy(content);
+    let contains_secret = secrets
+        .iter()
+        .filter_map(Value::as_str)
+        .any(|secret| !secret.is_empty() && text.contains(secret));
+
+    match manifest_documents(path, content) {
+        Some(documents) => {
+            let has_secret = documents.iter().any(|document| {
+                document.get("kind").and_then(serde_yaml::Value::as_str) == Some("Secret")
+            });
+            Ok(has_secret || contains_secret)
+        }
+        None if contains_secret => Err(Error::UserInputError(format!(
+            "{} contains values from Secrets, but only Kubernetes manifests can be encrypted",
+            path.display()
+        ))),
+        None => Ok(false),
+    }
+}
+
+/// Returns the documents of a YAML file of Kubernetes objects, or `None` for any other file:
+/// other than YAML, not parsable, or with a document that isn't an object, such as a kustomize
+/// config file.
+fn manifest_documents(path: &Path, content: &[u8]) -> Option<Vec<serde_yaml::Value>> {
+    let is_yaml = matches!(
+        path.extension().and_then(|extension| extension.to_str()),
+        Some("yaml") | Some("yml")
+    );
+    if !is_yaml {
+        return None;
+    }
+
+    let mut documents = Vec::new();
+    for document in serde_yaml::Deserializer::from_str(std::str::from_utf8(content).ok()?) {
+        let document = serde_yaml::Value::deserialize(document).ok()?;
+        if document.is_null() {
+            continue;
+        }
+        let is_object = document.get("apiVersion").is_some() && document.get("kind").is_some();
+        if !is_object || is_kustomize_config(&document) {
+            return None;
+        }
+        documents.push(document);
+    }
+
+    Some(documents)
+}
+
+/// Whether a readable key or value of an encrypted file reveals a value taken from a Secret.
+fn reveals(readable: &str, secret: &Value) -> bool {
+    match secret {
+        Value::String(secret) => !secret.is_empty() && readable.contains(secret.as_str()),
+        // numbers and booleans are too short to look for within other values
+        secret => serde_json::from_str::<Value>(readable).ok().as_ref() == Some(secret),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use age::armor::ArmoredReader;
+    use age::x25519::Identity;
+    use serde_json::json;
+    use std::fs;
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use tempfile::tempdir;
+
+    use super::{AgeEncryptor, Encryption, Encryptor};
+    use crate::utils::config::DEFAULT_ENCRYPTED_REGEX;
+    use crate::workflows::sops::SopsEncryptor;
+    use crate::workflows::template::render;
+
+    fn decrypt(identity: &Identity, encrypted: &[u8]) -> String {
+        let decryptor = match age::Decryptor::new(ArmoredReader::new(encrypted)).unwrap() {
+            age::Decryptor::Recipients(decryptor) => decryptor,
+            _ => panic!("not encrypted to recipients"),
+        };
+
+        let mut decrypted = String::new();
+        decryptor
+            .decrypt(std::iter::once(identity as &dyn age::Identity))
+            .unwrap()
+            .read_to_string(&mut decrypted)
+            .unwrap();
+        decrypted
+    }
+
+    #[test]
+    fn age_encryption_round_trips() {
+        let identity = Identity::generate();
+        let encryptor = AgeEncryptor::new(&[identity.to_public().to_string()]).unwrap();
+
+        let encrypted = encryptor
+            .encrypt(Path::new("secret.yaml"), b"password: hunter2\n", false)
+            .unwrap();
+
+        assert!(String::from_utf8_lossy(&encrypted).starts_with("-----BEGIN AGE ENCRYPTED FILE"));
+        assert_eq!(decrypt(&identity, &encrypted), "password: hunter2\n");
+
+        assert!(AgeEncryptor::new(&[]).is_err());
+        assert!(AgeEncryptor::new(&["age1invalid".to_string()]).is_err());
+    }
+
+    #[test]
+    fn encrypts_files_matching_patterns_or_carrying_secrets() {
+        let identity = Identity::generate();
+        let encryptor = AgeEncryptor::new(&[identity.to_public().to_string()]).unwrap();
+
+        let db =
+            "apiVersion: v1\nkind: ConfigMap\nmetadata:\n  name: db\ndata:\n  password: hunter2\n";
+        let secret =
+            "apiVersion: v1\nkind: Secret\nmetadata:\n  name: token\ndata:\n  token: YWJj\n";
+        let config = "apiVersion: v1\nkind: ConfigMap\nmetadata:\n  name: config\ndata:\n  replicas: \"2\"\n";
+        let repo_dir = tempdir().unwrap();
+        let write_files = || {
+            fs::create_dir_all(repo_dir.path().join("dev")).unwrap();
+            fs::write(repo_dir.path().join("dev/db.yaml"), db).unwrap();
+            fs::write(repo_dir.path().join("dev/secret.yaml"), secret).unwrap();
+            fs::write(repo_dir.path().join("dev/credentials.yaml"), "token: abc\n").unwrap();
+            fs::write(repo_dir.path().join("dev/config.yaml"), config).unwrap();
+            fs::write(
+                repo_dir.path().join("dev/README.md"),
+                "Deploys the agent.\n",
+            )
+            .unwrap();
+        };
+        let paths = vec![
+            PathBuf::from("dev/db.yaml"),
+            PathBuf::from("dev/secret.yaml"),
+            PathBuf::from("dev/credentials.yaml"),
+            PathBuf::from("dev/config.yaml"),
+            PathBuf::from("dev/README.md"),
+        ];
+        let secrets = vec![json!("hunter2")];
+        let patterns = vec!["credentials.yaml".to_string()];
+        let read = |path: &str| fs::read(repo_dir.path().join(path)).unwrap();
+
+        // without an encryptor, files that have to be encrypted are refused
+        write_files();
+        let unconfigured = Encryption::new(None, &patterns).unwrap();
+        assert!(unconfigured
+            .encrypt_rendered(repo_dir.path(), Path::new("dev"), &paths, &[])
+            .is_err());
+
+        // without secrets, only files matching the patterns are encrypted
+        let encryption = Encryption::new(Some(Box::new(encryptor)), &patterns).unwrap();
+        encryption
+            .encrypt_rendered(repo_dir.path(), Path::new("dev"), &paths, &[])
+            .unwrap();
+
+        assert_eq!(
+            decrypt(&identity, &read("dev/credentials.yaml")),
+            "token: abc\n"
+        );
+        assert_eq!(read("dev/db.yaml"), db.as_bytes());
+        assert_eq!(read("dev/secret.yaml"), secret.as_bytes());
+
+        // with secrets, manifests with a Secret or with one of them are as well
+        write_files();
+        encryption
+            .encrypt_rendered(repo_dir.path(), Path::new("dev"), &paths, &secrets)
+            .unwrap();
+
+        let encrypted = read("dev/db.yaml");
+        assert_eq!(decrypt(&identity, &encrypted), db);
+        assert_eq!(decrypt(&identity, &read("dev/secret.yaml")), secret);
+        assert_eq!(read("dev/config.yaml"), config.as_bytes());
+        assert_eq!(read("dev/README.md"), b"Deploys the agent.\n");
+
+        // unchanged files are encrypted to the same content again
+        write_files();
+        encryption
+            .encrypt_rendered(repo_dir.path(), Path::new("dev"), &paths, &secrets)
+            .unwrap();
+        assert_eq!(read("dev/db.yaml"), encrypted);
+
+        // files other than manifests can't carry them
+        fs::write(repo_dir.path().join("dev/README.md"), "Password: hunter2\n").unwrap();
+        let err = encryption
+            .encrypt_rendered(
+                repo_dir.path(),
+                Path::new("dev"),
+                &[PathBuf::from("dev/README.md")],
+                &secrets,
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("only Kubernetes manifests"));
+    }
+
+    #[test]
+    fn leaves_kustomize_config_in_plaintext() {
+        let identity = Identity::generate();
+        let encryptor =
+            SopsEncryptor::new(&[identity.to_public().to_string()], DEFAULT_ENCRYPTED_REGEX)
+                .unwrap();
+        let encryption = Encryption::new(Some(Box::new(encryptor)), &[]).unwrap();
+
+        let repo_dir = tempdir().unwrap();
+        let values = json!({ "CLUSTER_NAME": "ours", "password": "hunter2" });
+        let paths = render(
+            Path::new("fixtures/template"),
+            repo_dir.path(),
+            Path::new("dev"),
+            &values,
+            false,
+        )
+        .unwrap();
+        let kustomization = fs::read(repo_dir.path().join("dev/kustomization.yaml")).unwrap();
+
+        encryption
+            .encrypt_rendered(
+                repo_dir.path(),
+                Path::new("dev"),
+                &paths,
+                &[json!("hunter2")],
+            )
+            .unwrap();
+
+        assert_eq!(
+            fs::read(repo_dir.path().join("dev/kustomization.yaml")).unwrap(),
+            kustomization
+        );
+        let release = fs::read_to_string(repo_dir.path().join("dev/release.yaml")).unwrap();
+        assert!(!release.contains("sops"));
+    }
+
+    #[test]
+    fn encrypts_transformed_secrets() {
+        let identity = Identity::generate();
+        let encryptor =
+            SopsEncryptor::new(&[identity.to_public().to_string()], DEFAULT_ENCRYPTED_REGEX)
+                .unwrap();
+        let encryption = Encryption::new(Some(Box::new(encryptor)), &[]).unwrap();
+
+        let template_dir = tempdir().unwrap();
+        fs::write(
+            template_dir.path().join("config.yaml"),
+            "\
+apiVersion: v1
+kind: ConfigMap
+metadata:
+  name: cluster-agent
+data:
+  password: {{toJson password}}
+  shouted: {{upper password}}
+  port: \"{{port}}\"
+",
+        )
+        .unwrap();
+        fs::write(
+            template_dir.path().join("leak.yaml"),
+            "\
+apiVersion: v1
+kind: ConfigMap
+metadata:
+  name: cluster-agent-{{password}}
+",
+        )
+        .unwrap();
+
+        let values = json!({ "password": "hunter2", "port": 5432 });
+        let secrets = vec![json!("hunter2"), json!(5432)];
+
+        let repo_dir = tempdir().unwrap();
+        render(
+            template_dir.path(),
+            repo_dir.path(),
+            Path::new("dev"),
+            &values,
+            false,
+        )
+        .unwrap();
+        let config = vec![PathBuf::from("dev/config.yaml")];
+        encryption
+            .encrypt_rendered(repo_dir.path(), Path::new("dev"), &config, &secrets)
+            .unwrap();
+
+        let encrypted = fs::read_to_string(repo_dir.path().join("dev/config.yaml")).unwrap();
+        assert!(!encrypted.contains("hunter2"));
+        assert!(!encrypted.contains("HUNTER2"));
+        assert!(!encrypted.contains("5432"));
+        let document: serde_yaml::Value = serde_yaml::from_str(&encrypted).unwrap();
+        assert_eq!(document["metadata"]["name"], "cluster-agent");
+        for key in ["password", "shouted", "port"] {
+            assert!(document["data"][key].as_str().unwrap().starts_with("ENC["));
+        }
+        assert_eq!(document["sops"]["encrypted_regex"], DEFAULT_ENCRYPTED_REGEX);
+
+        // secrets in what is left readable are refused
+        let leak = vec![PathBuf::from("dev/leak.yaml")];
+        let err = encryption
+            .encrypt_rendered(repo_dir.path(), Path::new("dev"), &leak, &secrets)
+            .unwrap_err();
+        assert!(err.to_string().contains("not encrypted"));
+    }
+}
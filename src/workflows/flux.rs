@@ -0,0 +1,597 @@
+use git2::{Index, Repository};
+use handlebars::Handlebars;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::models::application::Application;
+use crate::models::environment::ApplicationEnvironment;
+use crate::models::flux::FluxSpec;
+use crate::utils::config::{EncryptionConfig, EncryptionFormat};
+use crate::utils::error::Error;
+use crate::workflows::linker::{remove_file, AssignmentOutput, Linker};
+
+/// Directory, within a cluster's directory, that the Flux `Kustomization`s are written to. It is
+/// owned by the operator: everything in it is listed in the cluster's `kustomization.yaml`.
+pub const KUSTOMIZATIONS_DIRECTORY: &str = "flux";
+
+/// Namespace Flux watches for `Kustomization`s.
+const FLUX_NAMESPACE: &str = "flux-system";
+
+/// `GitRepository` that Flux bootstraps the cluster from, which also holds the rendered output.
+const FLUX_SOURCE: &str = "flux-system";
+
+const DEFAULT_INTERVAL: &str = "10m";
+
+/// Writes a Flux `Kustomization` per assignment, pointing at its rendered output, to
+/// `<cluster>/flux/<assignment>.yaml`, and lists them in the cluster's `kustomization.yaml`.
+pub struct FluxLinker {
+    /// Secret holding the age key Flux decrypts SOPS encrypted files with, when they are.
+    decryption_secret: Option<String>,
+}
+
+impl FluxLinker {
+    pub fn new(encryption: &EncryptionConfig) -> Self {
+        // without recipients nothing is encrypted, and Flux shouldn't need the Secret
+        let decrypts =
+            encryption.format == EncryptionFormat::Sops && !encryption.age_recipients.is_empty();
+
+        FluxLinker {
+            decryption_secret: decrypts.then(|| encryption.decryption_secret.clone()),
+        }
+    }
+
+    fn kustomization_path(&self, output: &AssignmentOutput) -> PathBuf {
+        output
+            .cluster_path
+            .join(KUSTOMIZATIONS_DIRECTORY)
+            .join(format!(
+                "{}.yaml",
+                output
+                    .assignment
+                    .metadata
+                    .name
+                    .as_deref()
+                    .unwrap_or_default()
+            ))
+    }
+
+    /// Builds the Flux `Kustomization` that syncs an assignment's rendered output to its cluster.
+    fn kustomization(&self, output: &AssignmentOutput, flux: &FluxSpec) -> String {
+        let mut spec = Map::new();
+        spec.insert(
+            "interval".to_string(),
+            json!(flux.interval.as_deref().unwrap_or(DEFAULT_INTERVAL)),
+        );
+        spec.insert(
+            "path".to_string(),
+            json!(format!("./{}", output.path.to_string_lossy())),
+        );
+        spec.insert("prune".to_string(), json!(flux.prune.unwrap_or(true)));
+        spec.insert(
+            "sourceRef".to_string(),
+            json!({
+                "kind": "GitRepository",
+                "name": FLUX_SOURCE,
+            }),
+        );
+
+        if let Some(depends_on) = &flux.depends_on {
+            let depends_on: Vec<Value> = depends_on
+                .iter()
+                .map(|name| json!({ "name": name }))
+                .collect();
+            spec.insert("dependsOn".to_string(), Value::Array(depends_on));
+        }
+
+        if let Some(health_checks) = &flux.health_checks {
+            let health_checks: Vec<Value> = health_checks
+                .iter()
+                .map(|health_check| {
+                    let mut reference = json!({
+                        "apiVersion": health_check.api_version,
+                        "kind": health_check.kind,
+                        "name": health_check.name,
+                    });
+                    if let Some(namespace) = &health_check.namespace {
+                        reference["namespace"] = json!(namespace);
+                    }
+                    reference
+                })
+                .collect();
+            spec.insert("healthChecks".to_string(), Value::Array(health_checks));
+        }
+
+        if let Some(decryption_secret) = &self.decryption_secret {
+            spec.insert(
+                "decryption".to_string(),
+                json!({
+                    "provider": "sops",
+                    "secretRef": {
+                        "name": decryption_secret,
+                    },
+                }),
+            );
+        }
+
+        let kustomization = json!({
+            "apiVersion": "kustomize.toolkit.fluxcd.io/v1beta2",
+            "kind": "Kustomization",
+            "metadata": {
+                "name": output.assignment.metadata.name,
+                "namespace": FLUX_NAMESPACE,
+                "labels": {
+                    "app.kubernetes.io/managed-by": "application-api",
+                },
+            },
+            "spec": spec,
+        });
+
+        // JSON values always serialize to YAML
+        serde_yaml::to_string(&kustomization).unwrap()
+    }
+
+    /// Resolves the target's shared bases for the assignment's cluster, and checks that each of
+    /// them exists in the working copy, so that a typo or a missing label can't break the whole
+    /// cluster's `kustomization.yaml`.
+    fn shared_bases(
+        &self,
+        repo: &Repository,
+        output: &AssignmentOutput,
+    ) -> Result<Vec<String>, Error> {
+        let cluster_name = &output.assignment.spec.cluster;
+
+        let mut values: HashMap<&str, &str> = HashMap::new();
+        if let Some(cluster) = output.cluster {
+            for (label, value) in cluster.spec.labels.iter() {
+                values.insert(label, value);
+            }
+        }
+        values.insert("clusterName", cluster_name);
+
+        // a label the cluster doesn't have is an error rather than an empty path segment
+        let mut handlebars = Handlebars::new();
+        handlebars.set_strict_mode(true);
+
+        let absolute_cluster_path = repo.workdir().unwrap().join(&output.cluster_path);
+
+        let mut bases = vec![];
+        for base in output.target.bases.iter() {
+            let base = handlebars.render_template(base, &values).map_err(|err| {
+                Error::UserInputError(format!(
+                    "shared base '{}' of Cluster {} could not be resolved: {}",
+                    base, cluster_name, err
+                ))
+            })?;
+
+            if !absolute_cluster_path.join(&base).exists() {
+                return Err(Error::UserInputError(format!(
+                    "shared base '{}' of Cluster {} does not exist in gitops repo {}",
+                    base, cluster_name, output.target.repo
+                )));
+            }
+
+            bases.push(base);
+        }
+
+        Ok(bases)
+    }
+
+    /// Rewrites the cluster's `kustomization.yaml` to list its shared bases and the
+    /// `Kustomization`s in the operator's directory, so that hand-made directories next to them
+    /// are left alone.
+    fn write_aggregate(
+        &self,
+        repo: &Repository,
+        output: &AssignmentOutput,
+    ) -> Result<PathBuf, Error> {
+        let absolute_cluster_path = repo.workdir().unwrap().join(&output.cluster_path);
+        let kustomizations_path = absolute_cluster_path.join(KUSTOMIZATIONS_DIRECTORY);
+
+        let mut resources = vec![];
+        if kustomizations_path.exists() {
+            for entry_result in std::fs::read_dir(&kustomizations_path)? {
+                let entry = entry_result?;
+                let file_name = entry.file_name().to_string_lossy().to_string();
+
+                if entry.file_type()?.is_file() && file_name.ends_with(".yaml") {
+                    resources.push(format!("{}/{}", KUSTOMIZATIONS_DIRECTORY, file_name));
+                }
+            }
+        }
+        // directory order differs between file systems
+        resources.sort();
+
+        let mut bases = self.shared_bases(repo, output)?;
+        bases.append(&mut resources);
+
+        let kustomization = json!({
+            "apiVersion": "kustomize.config.k8s.io/v1beta1",
+            "kind": "Kustomization",
+            "resources": bases,
+        });
+
+        std::fs::create_dir_all(&absolute_cluster_path)?;
+        std::fs::write(
+            absolute_cluster_path.join("kustomization.yaml"),
+            serde_yaml::to_string(&kustomization).unwrap(),
+        )?;
+
+        Ok(output.cluster_path.join("kustomization.yaml"))
+    }
+}
+
+impl Linker for FluxLinker {
+    fn link(
+        &self,
+        repo: &Repository,
+        _index: &mut Index,
+        output: &AssignmentOutput,
+        application: &Application,
+        environment: &ApplicationEnvironment,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let flux = application
+            .spec
+            .flux
+            .clone()
+            .unwrap_or_default()
+            .merge(&environment.spec.flux.clone().unwrap_or_default());
+
+        let kustomization_relative_path = self.kustomization_path(output);
+        let kustomization_path = repo.workdir().unwrap().join(&kustomization_relative_path);
+
+        std::fs::create_dir_all(kustomization_path.parent().unwrap())?;
+        std::fs::write(kustomization_path, self.kustomization(output, &flux))?;
+
+        Ok(vec![
+            kustomization_relative_path,
+            self.write_aggregate(repo, output)?,
+        ])
+    }
+
+    fn unlink(
+        &self,
+        repo: &Repository,
+        index: &mut Index,
+        output: &AssignmentOutput,
+    ) -> Result<Vec<PathBuf>, Error> {
+        remove_file(repo, index, &self.kustomization_path(output))?;
+
+        Ok(vec![self.write_aggregate(repo, output)?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::Repository;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use tempfile::tempdir;
+
+    use crate::models::application::{Application, ApplicationSpec};
+    use crate::models::assignment::{ApplicationAssignment, ApplicationAssignmentSpec};
+    use crate::models::cluster::{Cluster, ClusterSpec};
+    use crate::models::environment::{ApplicationEnvironment, ApplicationEnvironmentSpec};
+    use crate::models::flux::{FluxHealthCheck, FluxSpec};
+    use crate::utils::config::{EncryptionConfig, EncryptionFormat, GitopsConfig};
+    use crate::workflows::linker::{AssignmentOutput, Linker};
+    use crate::workflows::testing::commit_file;
+
+    use super::FluxLinker;
+
+    fn read_yaml(repo: &Repository, path: &str) -> serde_yaml::Value {
+        let contents = std::fs::read_to_string(repo.workdir().unwrap().join(path)).unwrap();
+        serde_yaml::from_str(&contents).unwrap()
+    }
+
+    #[test]
+    fn writes_flux_kustomization_per_assignment() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        commit_file(&repo, "clusters/ours/hand-made/configmap.yaml", "hand made");
+        let mut index = repo.index().unwrap();
+
+        let target = GitopsConfig {
+            path: "clusters".to_string(),
+            ..GitopsConfig::default()
+        };
+
+        let application = Application::new(
+            "cluster-agent",
+            ApplicationSpec {
+                template: "cluster-agent".to_string(),
+                values: None,
+                values_from: None,
+                reconciler: None,
+                flux: Some(FluxSpec {
+                    depends_on: Some(vec!["ours-ingress-dev".to_string()]),
+                    interval: Some("5m".to_string()),
+                    prune: None,
+                    health_checks: Some(vec![FluxHealthCheck {
+                        api_version: "apps/v1".to_string(),
+                        kind: "Deployment".to_string(),
+                        name: "cluster-agent".to_string(),
+                        namespace: Some("cluster-agent".to_string()),
+                    }]),
+                }),
+                argocd: None,
+            },
+        );
+        let environment = ApplicationEnvironment::new(
+            "cluster-agent-dev",
+            ApplicationEnvironmentSpec {
+                application: "cluster-agent".to_string(),
+                environment: "dev".to_string(),
+                selector: None,
+                clusters: None,
+                spread_by: None,
+                values: None,
+                values_from: None,
+                flux: Some(FluxSpec {
+                    interval: Some("1m".to_string()),
+                    prune: Some(false),
+                    ..FluxSpec::default()
+                }),
+                argocd: None,
+            },
+        );
+
+        let assignments: Vec<ApplicationAssignment> =
+            ["ours-ingress-dev", "ours-cluster-agent-dev"]
+                .iter()
+                .map(|name| {
+                    ApplicationAssignment::new(
+                        name,
+                        ApplicationAssignmentSpec {
+                            cluster: "ours".to_string(),
+                            environment: "cluster-agent-dev".to_string(),
+                            values: None,
+                            values_from: None,
+                        },
+                    )
+                })
+                .collect();
+        let outputs: Vec<AssignmentOutput> = assignments
+            .iter()
+            .map(|assignment| AssignmentOutput {
+                target: &target,
+                assignment,
+                cluster: None,
+                cluster_path: PathBuf::from("clusters/ours"),
+                path: Path::new("clusters/ours").join(assignment.metadata.name.as_ref().unwrap()),
+            })
+            .collect();
+
+        let linker = FluxLinker::new(&EncryptionConfig::default());
+        for output in outputs.iter() {
+            let paths = linker
+                .link(&repo, &mut index, output, &application, &environment)
+                .unwrap();
+            assert!(paths.contains(&PathBuf::from("clusters/ours/kustomization.yaml")));
+        }
+
+        let kustomization = read_yaml(&repo, "clusters/ours/flux/ours-cluster-agent-dev.yaml");
+        assert_eq!(kustomization["kind"], "Kustomization");
+        assert_eq!(
+            kustomization["spec"]["path"],
+            "./clusters/ours/ours-cluster-agent-dev"
+        );
+        assert_eq!(kustomization["spec"]["interval"], "1m");
+        assert_eq!(kustomization["spec"]["prune"], false);
+        assert_eq!(
+            kustomization["spec"]["dependsOn"][0]["name"],
+            "ours-ingress-dev"
+        );
+        assert_eq!(
+            kustomization["spec"]["healthChecks"][0]["namespace"],
+            "cluster-agent"
+        );
+        assert!(kustomization["spec"].get("decryption").is_none());
+
+        let aggregate = read_yaml(&repo, "clusters/ours/kustomization.yaml");
+        assert_eq!(
+            aggregate["resources"],
+            serde_yaml::from_str::<serde_yaml::Value>(
+                "[flux/ours-cluster-agent-dev.yaml, flux/ours-ingress-dev.yaml]"
+            )
+            .unwrap()
+        );
+
+        linker.unlink(&repo, &mut index, &outputs[0]).unwrap();
+
+        assert!(!dir
+            .path()
+            .join("clusters/ours/flux/ours-ingress-dev.yaml")
+            .exists());
+        let aggregate = read_yaml(&repo, "clusters/ours/kustomization.yaml");
+        assert_eq!(
+            aggregate["resources"],
+            serde_yaml::from_str::<serde_yaml::Value>("[flux/ours-cluster-agent-dev.yaml]")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn lists_shared_bases_of_cluster() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        commit_file(&repo, "clusters/common/kustomization.yaml", "common");
+        commit_file(
+            &repo,
+            "clusters/regions/eastus2/kustomization.yaml",
+            "eastus2",
+        );
+
+        let target = GitopsConfig {
+            path: "clusters".to_string(),
+            bases: vec!["../common".to_string(), "../regions/{{region}}".to_string()],
+            ..GitopsConfig::default()
+        };
+        let application = Application::new(
+            "cluster-agent",
+            ApplicationSpec {
+                template: "cluster-agent".to_string(),
+                values: None,
+                values_from: None,
+                reconciler: None,
+                flux: None,
+                argocd: None,
+            },
+        );
+        let environment = ApplicationEnvironment::new(
+            "cluster-agent-dev",
+            ApplicationEnvironmentSpec {
+                application: "cluster-agent".to_string(),
+                environment: "dev".to_string(),
+                selector: None,
+                clusters: None,
+                spread_by: None,
+                values: None,
+                values_from: None,
+                flux: None,
+                argocd: None,
+            },
+        );
+        let assignment = ApplicationAssignment::new(
+            "ours-cluster-agent-dev",
+            ApplicationAssignmentSpec {
+                cluster: "ours".to_string(),
+                environment: "cluster-agent-dev".to_string(),
+                values: None,
+                values_from: None,
+            },
+        );
+
+        let cluster = |labels: &[(&str, &str)]| {
+            Cluster::new(
+                "ours",
+                ClusterSpec {
+                    name: "ours".to_string(),
+                    labels: labels
+                        .iter()
+                        .map(|(label, value)| (label.to_string(), value.to_string()))
+                        .collect::<HashMap<String, String>>(),
+                    environments: vec![],
+                    facts: None,
+                    gitops: None,
+                    kubeconfig: None,
+                },
+            )
+        };
+        let link = |cluster: &Cluster| {
+            let output = AssignmentOutput {
+                target: &target,
+                assignment: &assignment,
+                cluster: Some(cluster),
+                cluster_path: PathBuf::from("clusters/ours"),
+                path: PathBuf::from("clusters/ours/ours-cluster-agent-dev"),
+            };
+            FluxLinker::new(&EncryptionConfig::default()).link(
+                &repo,
+                &mut repo.index().unwrap(),
+                &output,
+                &application,
+                &environment,
+            )
+        };
+
+        link(&cluster(&[("region", "eastus2")])).unwrap();
+
+        let aggregate = read_yaml(&repo, "clusters/ours/kustomization.yaml");
+        assert_eq!(
+            aggregate["resources"],
+            serde_yaml::from_str::<serde_yaml::Value>(
+                "[../common, ../regions/eastus2, flux/ours-cluster-agent-dev.yaml]"
+            )
+            .unwrap()
+        );
+
+        // bases that don't exist, or use labels the cluster doesn't have, are never written
+        assert!(link(&cluster(&[("region", "westus")])).is_err());
+        assert!(link(&cluster(&[])).is_err());
+    }
+
+    #[test]
+    fn decrypts_sops_encrypted_output() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+
+        let target = GitopsConfig {
+            path: "clusters".to_string(),
+            ..GitopsConfig::default()
+        };
+        let application = Application::new(
+            "cluster-agent",
+            ApplicationSpec {
+                template: "cluster-agent".to_string(),
+                values: None,
+                values_from: None,
+                reconciler: None,
+                flux: None,
+                argocd: None,
+            },
+        );
+        let environment = ApplicationEnvironment::new(
+            "cluster-agent-dev",
+            ApplicationEnvironmentSpec {
+                application: "cluster-agent".to_string(),
+                environment: "dev".to_string(),
+                selector: None,
+                clusters: None,
+                spread_by: None,
+                values: None,
+                values_from: None,
+                flux: None,
+                argocd: None,
+            },
+        );
+        let assignment = ApplicationAssignment::new(
+            "ours-cluster-agent-dev",
+            ApplicationAssignmentSpec {
+                cluster: "ours".to_string(),
+                environment: "cluster-agent-dev".to_string(),
+                values: None,
+                values_from: None,
+            },
+        );
+        let output = AssignmentOutput {
+            target: &target,
+            assignment: &assignment,
+            cluster: None,
+            cluster_path: PathBuf::from("clusters/ours"),
+            path: PathBuf::from("clusters/ours/ours-cluster-agent-dev"),
+        };
+
+        let link = |encryption: &EncryptionConfig| {
+            FluxLinker::new(encryption)
+                .link(
+                    &repo,
+                    &mut repo.index().unwrap(),
+                    &output,
+                    &application,
+                    &environment,
+                )
+                .unwrap();
+            read_yaml(&repo, "clusters/ours/flux/ours-cluster-agent-dev.yaml")
+        };
+
+        let encryption = EncryptionConfig {
+            age_recipients: vec!["age1recipient".to_string()],
+            decryption_secret: "sops-keys".to_string(),
+            ..EncryptionConfig::default()
+        };
+        let kustomization = link(&encryption);
+        assert_eq!(kustomization["spec"]["decryption"]["provider"], "sops");
+        assert_eq!(
+            kustomization["spec"]["decryption"]["secretRef"]["name"],
+            "sops-keys"
+        );
+
+        // whole files encrypted with age aren't decrypted by Flux
+        let kustomization = link(&EncryptionConfig {
+            format: EncryptionFormat::Age,
+            ..encryption
+        });
+        assert!(kustomization["spec"].get("decryption").is_none());
+    }
+}
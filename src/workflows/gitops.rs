@@ -1,191 +1,133 @@
-use git2::build::RepoBuilder;
+use async_trait::async_trait;
+use git2::build::CheckoutBuilder;
 use git2::{
-    Cred, Direction, Index, ObjectType, Oid, PushOptions, RemoteCallbacks, Repository, Signature,
+    Direction, ErrorCode, Index, ObjectType, Oid, PushOptions, Repository, ResetType, Signature,
 };
-use handlebars::Handlebars;
-use log::debug;
-use std::collections::HashMap;
-use std::env;
-use std::ffi::OsString;
-use std::fs::create_dir_all;
+use log::{debug, warn};
+use std::cell::RefCell;
 use std::path::{Path, PathBuf};
-use tempfile::{tempdir, TempDir};
+use std::sync::Arc;
 
 use crate::models::application::Application;
 use crate::models::assignment::ApplicationAssignment;
+use crate::models::cluster::Cluster;
 use crate::models::environment::ApplicationEnvironment;
 use crate::models::template::ApplicationTemplate;
+use crate::utils::config::{GitopsConfig, GitopsMode, ValidationConfig};
 use crate::utils::error::Error;
+use crate::workflows::cache::{auth_callbacks, fetch, fetch_options, RepoCache};
+use crate::workflows::encryption::Encryption;
+use crate::workflows::linker::{AssignmentOutput, Linker};
+use crate::workflows::review::{ChangeRequest, ChangeRequestProvider, PendingReview};
+use crate::workflows::template::{render, template_values, with_template, ResolvedValues};
+use crate::workflows::validation::validate_rendered;
+use crate::workflows::workflow::{
+    blocking, Deployment, DeploymentResult, DeploymentStatus, Workflow,
+};
+
+/// Number of times a change is pushed, and replayed on top of the fetched branch if the push is
+/// rejected, before giving up.
+const MAX_PUSH_ATTEMPTS: usize = 5;
+
+/// Outcome of committing a change to the GitOps repo.
+struct Published {
+    commit: Oid,
+    changed: bool,
+    review: Option<PendingReview>,
+}
 
+#[derive(Clone)]
 pub struct GitopsWorkflow {
-    pub application_repo_url: String,
+    /// Repository, branch and path used for clusters that don't specify their own.
+    pub defaults: GitopsConfig,
+    /// Working copies of template and GitOps repos, reused across reconciles.
+    cache: Arc<RepoCache>,
+    /// Opens change requests for review branches in pull request mode.
+    reviews: Arc<dyn ChangeRequestProvider>,
+    /// Points the cluster's GitOps agent at rendered assignments.
+    linker: Arc<dyn Linker>,
+    /// Encrypts rendered files that match its patterns, and manifests that carry values taken
+    /// from Secrets.
+    encryption: Arc<Encryption>,
+    /// Checks rendered files are valid manifests before they are committed.
+    validation: ValidationConfig,
 }
 
 impl GitopsWorkflow {
-    pub fn new(application_repo_url: &str) -> Result<GitopsWorkflow, Error> {
-        return Ok(GitopsWorkflow {
-            application_repo_url: application_repo_url.to_string(),
-        });
-    }
-
-    fn get_auth_callback(&self) -> RemoteCallbacks {
-        // Prepare callbacks.
-        let mut callbacks = RemoteCallbacks::new();
-
-        callbacks.credentials(|_url, username_from_url, _allowed_types| {
-            let secrets_path = match env::var("SECRETS_PATH") {
-                Ok(secrets_path) => secrets_path,
-                Err(_) => "/mnt/secrets_store".to_string()
-            };
-
-            let private_ssh_key_path = std::path::Path::new(&secrets_path).join("git-ssh-private-key");
-
-            Cred::ssh_key(
-                username_from_url.unwrap(),
-                None,
-                &private_ssh_key_path,
-                None,
-            )
-        });
-
-        callbacks
-    }
-
-    fn get_repo_builder(&self) -> RepoBuilder {
-        let auth_callback = self.get_auth_callback();
-
-        // Prepare fetch options.
-        let mut fetch_options = git2::FetchOptions::new();
-        fetch_options.remote_callbacks(auth_callback);
-
-        // Prepare builder.
-        let mut builder = git2::build::RepoBuilder::new();
-        builder.fetch_options(fetch_options);
-
-        builder
-    }
-
-    fn clone_template_repo(
-        &self,
-        template: &ApplicationTemplate,
-        temp_dir: &TempDir,
-    ) -> Result<Repository, Error> {
-        let repo_path = temp_dir.path().join("template");
-
-        let mut repo_builder = self.get_repo_builder();
-
-        match repo_builder.clone(&template.spec.repo, &repo_path) {
-            Ok(repo) => Ok(repo),
-            Err(err) => Err(Error::GitError { source: err }),
-        }
-    }
-
-    fn clone_cluster_gitops_repo(
-        &self,
-        application_gitops_temp_dir: &TempDir,
-    ) -> Result<Repository, Error> {
-        let repo_path = application_gitops_temp_dir.path().join("gitops");
-
-        let mut repo_builder = self.get_repo_builder();
-
-        match repo_builder.clone(&self.application_repo_url, &repo_path) {
-            Ok(repo) => Ok(repo),
-            Err(err) => Err(Error::GitError { source: err }),
-        }
+    pub fn new(
+        config: &GitopsConfig,
+        cache: Arc<RepoCache>,
+        reviews: Box<dyn ChangeRequestProvider>,
+        linker: Box<dyn Linker>,
+        encryption: Arc<Encryption>,
+        validation: &ValidationConfig,
+    ) -> Result<GitopsWorkflow, Error> {
+        config.validate()?;
+
+        Ok(GitopsWorkflow {
+            defaults: config.clone(),
+            cache,
+            reviews: Arc::from(reviews),
+            linker: Arc::from(linker),
+            encryption,
+            validation: validation.clone(),
+        })
     }
 
-    fn render(
-        &self,
-        template_path: &Path,
-        repo_root_path: &Path,
-        root_relative_path: &Path,
-        values: &HashMap<&str, &str>,
-    ) -> Result<Vec<PathBuf>, Error> {
-        let mut paths = Vec::new();
-
-        let output_path = repo_root_path.join(root_relative_path);
-        create_dir_all(&output_path)?;
-
-        let entries = std::fs::read_dir(template_path)?;
-
-        for entry_result in entries {
-            let entry = entry_result?;
-            let file_type = entry.file_type()?;
-            let is_dotted_file_name =
-                entry.file_name().to_str().unwrap().chars().next().unwrap() == '.';
-
-            let entry_template_path = entry.path();
-
-            let output_relative_path = Path::new(root_relative_path).join(entry.file_name());
-            let output_absolute_path = Path::new(&repo_root_path).join(&output_relative_path);
-
-            if file_type.is_dir() {
-                if !is_dotted_file_name {
-                    let mut subpaths = self.render(
-                        &entry_template_path,
-                        repo_root_path,
-                        &output_relative_path,
-                        values,
-                    )?;
-                    paths.append(&mut subpaths);
-                }
-            } else {
-                // debug!("adding path to list {:?}", output_relative_path);
-                paths.push(output_relative_path);
-
-                let template = std::fs::read_to_string(entry_template_path)?;
-                let mut handlebars = Handlebars::new();
-                handlebars
-                    .register_template_string("template", template)
-                    .unwrap();
-
-                let rendered_file = match handlebars.render("template", values) {
-                    Ok(rendered_file) => rendered_file,
-                    Err(err) => return Err(Error::RenderError { source: err }),
-                };
-
-                std::fs::write(output_absolute_path, rendered_file.as_bytes())?;
+    /// Resolves the GitOps repository, branch and path that manifests for a cluster are written to.
+    /// Settings in the `gitops` section of the `Cluster` resource take precedence over the
+    /// operator defaults.
+    ///
+    /// # Arguments
+    /// - `cluster` - The `Cluster` resource assigned to, if one exists.
+    pub fn target(&self, cluster: Option<&Cluster>) -> Result<GitopsConfig, Error> {
+        let mut target = self.defaults.clone();
+
+        if let Some(gitops) = cluster.and_then(|cluster| cluster.spec.gitops.as_ref()) {
+            if let Some(repo) = &gitops.repo {
+                target.repo = repo.clone();
             }
-        }
-
-        Ok(paths)
-    }
-
-    fn link(&self, cluster_path: &Path) -> Result<(), Error> {
-        // read all directory names (aka applications)
-        let entries = std::fs::read_dir(cluster_path)?;
-
-        let mut applications = vec![OsString::from("../common")];
-
-        for entry_result in entries {
-            let entry = entry_result?;
-            let file_type = entry.file_type()?;
-
-            let file_name = entry.file_name();
-
-            if file_type.is_dir() && !file_name.eq("flux-system") {
-                applications.push(file_name);
+            if let Some(branch) = &gitops.branch {
+                target.branch = branch.clone();
+            }
+            if let Some(path) = &gitops.path {
+                target.path = path.clone();
+            }
+            if let Some(mode) = gitops.mode {
+                target.mode = mode;
+            }
+            if let Some(bases) = &gitops.bases {
+                target.bases = bases.clone();
             }
         }
 
-        let application_list: String = applications
-            .into_iter()
-            .map(|application| {
-                let display_string = application.to_string_lossy();
-                format!("    - {}\n", display_string)
-            })
-            .collect();
-        let kustomization = format!(
-            "apiVersion: kustomize.config.k8s.io/v1beta1
-kind: Kustomization
-resources:
-{}",
-            application_list
-        );
+        if let Err(err) = target.validate() {
+            return Err(Error::UserInputError(format!(
+                "Cluster {} has an invalid gitops target: {}",
+                cluster
+                    .and_then(|cluster| cluster.metadata.name.as_deref())
+                    .unwrap_or_default(),
+                err
+            )));
+        }
 
-        let kustomization_path = cluster_path.join("kustomization.yaml");
+        Ok(target)
+    }
 
-        std::fs::write(kustomization_path, kustomization.as_bytes())?;
+    /// Resets a GitOps working copy to the fetched tip of `branch`, discarding anything left
+    /// behind by a previous reconcile.
+    fn checkout_branch(&self, repo: &Repository, branch: &str) -> Result<(), Error> {
+        let remote_branch = format!("refs/remotes/origin/{}", branch);
+        let commit = repo.revparse_single(&remote_branch)?.peel_to_commit()?;
+
+        let local_branch = format!("refs/heads/{}", branch);
+        repo.reference(&local_branch, commit.id(), true, "reset to remote")?;
+        repo.set_head(&local_branch)?;
+        repo.reset(
+            commit.as_object(),
+            ResetType::Hard,
+            Some(CheckoutBuilder::new().force().remove_untracked(true)),
+        )?;
 
         Ok(())
     }
@@ -196,7 +138,7 @@ resources:
         index: &mut Index,
         paths: Vec<PathBuf>,
         message: &str,
-    ) -> Result<Oid, Error> {
+    ) -> Result<Option<Oid>, Error> {
         for path in paths.iter() {
             // debug!("adding path to index {:?}", path);
             index.add_path(path)?;
@@ -217,9 +159,15 @@ resources:
             }
         };
 
+        // skip empty commits, so reconciling an unchanged assignment leaves the history alone
+        if parent_commit.tree_id() == oid {
+            debug!("tree {} is unchanged, skipping commit", oid);
+            return Ok(None);
+        }
+
         let tree = repo.find_tree(oid)?;
 
-        repo.commit(
+        let commit_oid = repo.commit(
             Some("HEAD"), //  point HEAD to our new commit
             &signature,   // author
             &signature,   // committer
@@ -228,196 +176,621 @@ resources:
             &[&parent_commit],
         )?; // parents
 
-        Ok(oid)
+        Ok(Some(commit_oid))
     }
 
-    fn push(&self, repo: &Repository, url: &str, branch: &str) -> Result<(), Error> {
-        let mut remote = match repo.find_remote("origin") {
-            Ok(r) => r,
-            Err(_) => repo.remote("origin", url)?,
-        };
+    /// Checks out the tip of the target branch, applies `change` to the working tree and index,
+    /// and commits and pushes the result.
+    ///
+    /// If the push is rejected because someone else pushed to the branch in the meantime, the
+    /// branch is fetched and `change` is replayed on top of its new tip, up to
+    /// `MAX_PUSH_ATTEMPTS` times in total.
+    ///
+    /// Returns the pushed commit, or `None` if `change` left the branch as it was, in which case
+    /// nothing is committed or pushed.
+    ///
+    /// # Arguments
+    /// - `repo` - GitOps working copy.
+    /// - `target` - GitOps repo and branch to push to.
+    /// - `message` - Commit message.
+    /// - `change` - Applies the change and returns the paths to add to the index.
+    fn commit_and_push<F>(
+        &self,
+        repo: &Repository,
+        target: &GitopsConfig,
+        message: &str,
+        change: F,
+    ) -> Result<Option<Oid>, Error>
+    where
+        F: Fn(&mut Index) -> Result<Vec<PathBuf>, Error>,
+    {
+        for attempt in 1..=MAX_PUSH_ATTEMPTS {
+            if attempt > 1 {
+                fetch(repo, fetch_options())?;
+            }
 
-        let connect_auth_callback = self.get_auth_callback();
-        remote.connect_auth(Direction::Push, Some(connect_auth_callback), None)?;
+            self.checkout_branch(repo, &target.branch)?;
 
-        let ref_spec = format!("refs/heads/{}:refs/heads/{}", branch, branch);
+            let mut index = repo.index()?;
+            let paths = change(&mut index)?;
+            let oid = match self.commit_files(repo, &mut index, paths, message)? {
+                Some(oid) => oid,
+                None => return Ok(None),
+            };
 
-        let push_auth_callback = self.get_auth_callback();
-        let mut push_options = PushOptions::new();
-        push_options.remote_callbacks(push_auth_callback);
+            let ref_spec = format!("refs/heads/{}:refs/heads/{}", target.branch, target.branch);
 
-        remote.push(&[ref_spec], Some(&mut push_options))?;
+            match self.push(repo, &target.repo, &ref_spec, &target.branch) {
+                Ok(()) => return Ok(Some(oid)),
+                Err(Error::PushRejected { message, .. }) => {
+                    warn!(
+                        "push to {} {} rejected on attempt {}: {}",
+                        target.repo, target.branch, attempt, message
+                    );
+                }
+                Err(err) => return Err(err),
+            }
+        }
 
-        Ok(())
+        Err(Error::PushRetriesExhausted {
+            branch: target.branch.clone(),
+            attempts: MAX_PUSH_ATTEMPTS,
+        })
     }
 
-    pub fn create_deployment(
+    /// Commits a change for review: the change is applied on top of the target branch and pushed
+    /// to `branch` instead, and a change request is opened to merge it into the target branch.
+    ///
+    /// The review branch is rebuilt from the target branch every time, and only force pushed when
+    /// its content changes. Once its change request is merged the review branch is deleted.
+    ///
+    /// # Arguments
+    /// - `repo` - GitOps working copy.
+    /// - `target` - GitOps repo and branch the change is to be merged into.
+    /// - `branch` - Review branch.
+    /// - `message` - Commit message, its first line doubles as the change request title.
+    /// - `change` - Applies the change and returns the paths to add to the index.
+    fn commit_for_review<F>(
         &self,
-        application: &Application,
-        template: &ApplicationTemplate,
-        environment: &ApplicationEnvironment,
-        assignment: &ApplicationAssignment,
-    ) -> Result<Oid, Error> {
-        let template_temp_dir = tempdir()?;
-        let cluster_gitops_temp_dir = tempdir()?;
+        repo: &Repository,
+        target: &GitopsConfig,
+        branch: &str,
+        message: &str,
+        change: F,
+    ) -> Result<Published, Error>
+    where
+        F: Fn(&mut Index) -> Result<Vec<PathBuf>, Error>,
+    {
+        let request = ChangeRequest {
+            repo: target.repo.clone(),
+            branch: branch.to_string(),
+            base: target.branch.clone(),
+            title: message.lines().next().unwrap_or_default().to_string(),
+            description: message.to_string(),
+        };
 
-        println!("template temp dir {:?}", template_temp_dir);
-        println!("cluster_gitops_temp_dir {:?}", cluster_gitops_temp_dir);
+        let remote_branch = format!("refs/remotes/origin/{}", branch);
+        let mut proposed = repo.refname_to_id(&remote_branch).ok();
 
-        let template_repo = self.clone_template_repo(template, &template_temp_dir)?;
+        let reviews = &self.reviews;
+        if proposed.is_some() && reviews.is_merged(repo, &request)? {
+            debug!("review branch {} was merged, deleting it", branch);
+            let ref_spec = format!(":refs/heads/{}", branch);
+            self.push(repo, &target.repo, &ref_spec, branch)?;
+            proposed = None;
+        }
 
-        // clone application cluster gitops repo specified by application_repo_url
-        let cluster_gitops_repo = self.clone_cluster_gitops_repo(&cluster_gitops_temp_dir)?;
+        self.checkout_branch(repo, &target.branch)?;
+
+        let mut index = repo.index()?;
+        let paths = change(&mut index)?;
+        let commit = match self.commit_files(repo, &mut index, paths, message)? {
+            Some(commit) => commit,
+            // the target branch already has the change, eg. squash merged, so there is nothing
+            // to review
+            None => {
+                if proposed.is_some() {
+                    debug!("review branch {} is no longer needed, deleting it", branch);
+                    let ref_spec = format!(":refs/heads/{}", branch);
+                    self.push(repo, &target.repo, &ref_spec, branch)?;
+                    reviews.close(repo, &request)?;
+                }
 
-        let template_path = Path::new(template_repo.path())
-            .parent()
-            .unwrap()
-            .join(&template.spec.path);
+                return Ok(Published {
+                    commit: repo.head()?.peel_to_commit()?.id(),
+                    changed: false,
+                    review: None,
+                });
+            }
+        };
 
-        println!("template_path {:?}", template_path);
+        // keep the review branch, and any review of it, as is when its content is unchanged
+        let unchanged = match proposed {
+            Some(proposed) => {
+                repo.find_commit(proposed)?.tree_id() == repo.find_commit(commit)?.tree_id()
+            }
+            None => false,
+        };
 
-        let cluster_gitops_repo_path = Path::new(cluster_gitops_repo.path()).parent().unwrap();
+        let commit = if unchanged {
+            proposed.unwrap()
+        } else {
+            let local_branch = format!("refs/heads/{}", branch);
+            repo.reference(&local_branch, commit, true, "review branch")?;
 
-        // TODO: should we be less opinionated / more configurable about where applications go?
-        let cluster_relative_path = Path::new(&assignment.spec.cluster);
-        let cluster_path = cluster_gitops_repo_path.join(&cluster_relative_path);
+            let ref_spec = format!("+{}:{}", local_branch, local_branch);
+            self.push(repo, &target.repo, &ref_spec, branch)?;
+            commit
+        };
 
-        let application_name = application.metadata.name.as_ref().unwrap();
-        let assignment_name = assignment.metadata.name.as_ref().unwrap();
+        let change_request = reviews.open(repo, &request)?;
 
-        // output -> cluster relative path / assignment name
-        let output_relative_path = cluster_relative_path
-            //            .join(&application_name)
-            //            .join(&environment.spec.environment)
-            .join(&assignment_name);
+        Ok(Published {
+            commit,
+            changed: !unchanged,
+            review: Some(PendingReview {
+                branch: branch.to_string(),
+                change_request,
+            }),
+        })
+    }
 
-        println!("output_relative_path {:?}", output_relative_path);
+    /// Commits a change to the target branch, either directly or for review depending on the
+    /// target's mode.
+    ///
+    /// # Arguments
+    /// - `repo` - GitOps working copy.
+    /// - `target` - GitOps repo and branch to commit to.
+    /// - `review_branch` - Branch to propose the change on in pull request mode.
+    /// - `message` - Commit message.
+    /// - `change` - Applies the change and returns the paths to add to the index.
+    fn publish<F>(
+        &self,
+        repo: &Repository,
+        target: &GitopsConfig,
+        review_branch: &str,
+        message: &str,
+        change: F,
+    ) -> Result<Published, Error>
+    where
+        F: Fn(&mut Index) -> Result<Vec<PathBuf>, Error>,
+    {
+        match target.mode {
+            GitopsMode::Push => {
+                let pushed = self.commit_and_push(repo, target, message, change)?;
+
+                let commit = match pushed {
+                    Some(commit) => commit,
+                    None => repo.head()?.peel_to_commit()?.id(),
+                };
 
-        let mut index = cluster_gitops_repo.index()?;
-        index.remove_dir(&output_relative_path, 0)?;
+                Ok(Published {
+                    commit,
+                    changed: pushed.is_some(),
+                    review: None,
+                })
+            }
+            GitopsMode::PullRequest => {
+                self.commit_for_review(repo, target, review_branch, message, change)
+            }
+        }
+    }
 
-        // build template context variables
-        let mut template_values: HashMap<&str, &str> = HashMap::new();
-        template_values.insert("clusterName", &assignment.spec.cluster);
+    /// Pushes `ref_spec` to the remote. A push the remote rejects, typically because `branch` has
+    /// moved on since it was fetched, fails with `Error::PushRejected`.
+    fn push(
+        &self,
+        repo: &Repository,
+        url: &str,
+        ref_spec: &str,
+        branch: &str,
+    ) -> Result<(), Error> {
+        let mut remote = match repo.find_remote("origin") {
+            Ok(r) => r,
+            Err(_) => repo.remote("origin", url)?,
+        };
 
-        // TODO: Fetch assigned cluster when we are using Cluster API
-        template_values.insert("cloud", "azure");
-        template_values.insert("cloudRegion", "eastus2");
+        let connect_auth_callback = auth_callbacks();
+        remote
+            .connect_auth(Direction::Push, Some(connect_auth_callback), None)
+            .map_err(|source| Error::PushError { source })?;
 
-        // add in values from Application
-        if let Some(values) = &application.spec.values {
-            for (key, value) in values.iter() {
-                template_values.insert(&key, &value);
+        // the remote reports rejected ref updates through this callback rather than as an error
+        let rejection: RefCell<Option<String>> = RefCell::new(None);
+
+        let mut push_callbacks = auth_callbacks();
+        push_callbacks.push_update_reference(|_reference, status| {
+            if let Some(status) = status {
+                *rejection.borrow_mut() = Some(status.to_string());
             }
-        }
+            Ok(())
+        });
 
-        // add in values from ApplicationEnvironment
-        if let Some(values) = &environment.spec.values {
-            for (key, value) in values.iter() {
-                template_values.insert(&key, &value);
+        let mut push_options = PushOptions::new();
+        push_options.remote_callbacks(push_callbacks);
+
+        match remote.push(&[ref_spec], Some(&mut push_options)) {
+            Ok(()) => {}
+            Err(source) if source.code() == ErrorCode::NotFastForward => {
+                return Err(Error::PushRejected {
+                    branch: branch.to_string(),
+                    message: source.message().to_string(),
+                })
             }
+            Err(source) => return Err(Error::PushError { source }),
         }
 
-        // add in values from ApplicationAssignment
-        if let Some(values) = &assignment.spec.values {
-            for (key, value) in values.iter() {
-                template_values.insert(&key, &value);
-            }
+        drop(push_options);
+
+        match rejection.into_inner() {
+            Some(message) => Err(Error::PushRejected {
+                branch: branch.to_string(),
+                message,
+            }),
+            None => Ok(()),
         }
+    }
 
-        let mut paths = self.render(
-            &template_path,
-            cluster_gitops_repo_path,
-            &output_relative_path,
-            &template_values,
-        )?;
-        self.link(&cluster_path)?;
+    pub fn create_deployment(
+        &self,
+        application: &Application,
+        template: &ApplicationTemplate,
+        environment: &ApplicationEnvironment,
+        assignment: &ApplicationAssignment,
+        cluster: Option<&Cluster>,
+        resolved: &ResolvedValues,
+    ) -> Result<DeploymentResult, Error> {
+        let target = self.target(cluster)?;
+
+        // the template working copy is always locked before the gitops one, so that concurrent
+        // reconciles can't deadlock on each other.
+        with_template(&self.cache, template, |template_path, template_commit| {
+            debug!(
+                "template {} resolved to commit {}",
+                template.spec.repo, template_commit
+            );
+
+            self.cache.with_repo(
+                &target.repo,
+                &format!("gitops {}", target.branch),
+                fetch_options(),
+                |cluster_gitops_repo| {
+                    let published = self.commit_deployment(
+                        cluster_gitops_repo,
+                        &target,
+                        template_path,
+                        template_commit,
+                        application,
+                        template,
+                        environment,
+                        assignment,
+                        cluster,
+                        resolved,
+                    )?;
+
+                    Ok(DeploymentResult {
+                        template_commit,
+                        commit: Some(published.commit),
+                        changed: published.changed,
+                        review: published.review,
+                        inventory: None,
+                    })
+                },
+            )
+        })
+    }
+
+    /// Renders an assignment into a GitOps working copy checked out at the target branch, then
+    /// commits and pushes it.
+    #[allow(clippy::too_many_arguments)]
+    fn commit_deployment(
+        &self,
+        cluster_gitops_repo: &Repository,
+        target: &GitopsConfig,
+        template_path: &Path,
+        template_commit: Oid,
+        application: &Application,
+        template: &ApplicationTemplate,
+        environment: &ApplicationEnvironment,
+        assignment: &ApplicationAssignment,
+        cluster: Option<&Cluster>,
+        resolved: &ResolvedValues,
+    ) -> Result<Published, Error> {
+        println!("template_path {:?}", template_path);
 
-        let kustomization_path = cluster_relative_path.join("kustomization.yaml");
-        paths.push(kustomization_path);
+        let cluster_gitops_repo_path = cluster_gitops_repo.workdir().unwrap();
+
+        let output = assignment_output(target, assignment, cluster);
+
+        let application_name = application.metadata.name.as_ref().unwrap();
+        let assignment_name = assignment.metadata.name.as_ref().unwrap();
+
+        println!("output_relative_path {:?}", output.path);
+
+        let template_values = template_values(
+            application,
+            environment,
+            assignment,
+            cluster,
+            resolved,
+            template_path,
+        )?;
 
         // TODO(ENH): Support different messages
         let message = format!(
-            "Reconciling created ApplicationAssignment {} for Application {} for Cluster {}",
-            assignment.metadata.name.as_ref().unwrap(),
+            "Reconciling created ApplicationAssignment {} for Application {} for Cluster {}\n\nTemplate: {} {}",
+            assignment_name,
             application_name,
-            assignment.spec.cluster
+            assignment.spec.cluster,
+            template.spec.repo,
+            template_commit
         );
 
-        // add and commit output path in application cluster gitops repo
-        let oid = self.commit_files(&cluster_gitops_repo, &mut index, paths, &message)?;
+        // render, add and commit output path in application cluster gitops repo
+        let review_branch = review_branch(assignment);
+        self.publish(
+            cluster_gitops_repo,
+            target,
+            &review_branch,
+            &message,
+            |index| {
+                self.remove_output(cluster_gitops_repo, index, &output.path)?;
+
+                let mut paths = render(
+                    template_path,
+                    cluster_gitops_repo_path,
+                    &output.path,
+                    &template_values,
+                    template.spec.strict.unwrap_or_default(),
+                )?;
+                validate_rendered(cluster_gitops_repo_path, &paths, &self.validation)?;
+                // encrypted before anything is staged, so plaintext never reaches the index
+                self.encryption.encrypt_rendered(
+                    cluster_gitops_repo_path,
+                    &output.path,
+                    &paths,
+                    &resolved.secrets,
+                )?;
+                paths.extend(self.linker.link(
+                    cluster_gitops_repo,
+                    index,
+                    &output,
+                    application,
+                    environment,
+                )?);
+
+                Ok(paths)
+            },
+        )
+    }
+
+    pub fn delete_deployment(
+        &self,
+        assignment: &ApplicationAssignment,
+        cluster: Option<&Cluster>,
+    ) -> Result<Option<PendingReview>, Error> {
+        debug!("gitopsworkflow: delete_deployment");
+        let target = self.target(cluster)?;
+
+        self.cache.with_repo(
+            &target.repo,
+            &format!("gitops {}", target.branch),
+            fetch_options(),
+            |cluster_gitops_repo| {
+                let output = assignment_output(&target, assignment, cluster);
+
+                let assignment_name = assignment.metadata.name.as_ref().unwrap();
 
-        // TODO: make more flexible to support different branches
-        self.push(&cluster_gitops_repo, &self.application_repo_url, "main")?;
+                // TODO(ENH): Support different messages
+                let message = format!(
+                    "Reconciling deleted ApplicationAssignment {} for Environment {} for Cluster {}",
+                    assignment_name, assignment.spec.environment, assignment.spec.cluster
+                );
 
-        Ok(oid)
+                // remove output path and commit in application cluster gitops repo
+                let review_branch = review_branch(assignment);
+                let published =
+                    self.publish(cluster_gitops_repo, &target, &review_branch, &message, |index| {
+                        self.remove_output(cluster_gitops_repo, index, &output.path)?;
+
+                        self.linker.unlink(cluster_gitops_repo, index, &output)
+                    })?;
+
+                Ok(published.review)
+            },
+        )
     }
 
-    pub fn delete_deployment(&self, assignment: &ApplicationAssignment) -> Result<Oid, Error> {
-        debug!("gitopsworkflow: delete_deployment");
-        let application_gitops_temp_dir = tempdir()?;
+    /// Checks whether the rendered assignment is on the target branch, or else on its review branch.
+    pub fn deployment_status(
+        &self,
+        assignment: &ApplicationAssignment,
+        cluster: Option<&Cluster>,
+    ) -> Result<DeploymentStatus, Error> {
+        let target = self.target(cluster)?;
+
+        self.cache.with_repo(
+            &target.repo,
+            &format!("gitops {}", target.branch),
+            fetch_options(),
+            |cluster_gitops_repo| {
+                let output = assignment_output(&target, assignment, cluster);
+
+                let remote_branch = format!("refs/remotes/origin/{}", target.branch);
+                let tree = cluster_gitops_repo
+                    .revparse_single(&remote_branch)?
+                    .peel_to_tree()?;
+
+                if tree.get_path(&output.path).is_ok() {
+                    return Ok(DeploymentStatus::Deployed);
+                }
 
-        // clone cluster gitops repo specified by application_repo_url
-        let cluster_gitops_repo = self.clone_cluster_gitops_repo(&application_gitops_temp_dir)?;
-        let application_gitops_repo_path = Path::new(cluster_gitops_repo.path()).parent().unwrap();
+                let review_branch = review_branch(assignment);
+                let remote_review_branch = format!("refs/remotes/origin/{}", review_branch);
+                if target.mode == GitopsMode::PullRequest
+                    && cluster_gitops_repo
+                        .refname_to_id(&remote_review_branch)
+                        .is_ok()
+                {
+                    return Ok(DeploymentStatus::PendingReview {
+                        branch: review_branch,
+                    });
+                }
 
-        let cluster_relative_path = Path::new(&assignment.spec.cluster);
-        let cluster_path = application_gitops_repo_path.join(&cluster_relative_path);
+                Ok(DeploymentStatus::Missing)
+            },
+        )
+    }
 
-        let assignment_name = assignment.metadata.name.as_ref().unwrap();
+    /// Removes a previously rendered output directory from both the index and the working tree,
+    /// so that files dropped from the template don't linger.
+    fn remove_output(
+        &self,
+        repo: &Repository,
+        index: &mut Index,
+        output_relative_path: &Path,
+    ) -> Result<(), Error> {
+        index.remove_dir(output_relative_path, 0)?;
 
-        // output -> cluster relative path / assignment name
-        let output_relative_path = cluster_relative_path
-            //            .join(&application_name)
-            //            .join(&environment.spec.environment)
-            .join(&assignment_name);
+        let output_path = repo.workdir().unwrap().join(output_relative_path);
+        if output_path.exists() {
+            std::fs::remove_dir_all(output_path)?;
+        }
 
-        let mut index = cluster_gitops_repo.index()?;
+        Ok(())
+    }
+}
 
-        index.remove_dir(&output_relative_path, 0)?;
+/// git2 blocks, so the workflow runs on the blocking thread pool, see `blocking`.
+#[async_trait]
+impl Workflow for GitopsWorkflow {
+    async fn create(&self, deployment: &Deployment) -> Result<DeploymentResult, Error> {
+        let workflow = self.clone();
+        let deployment = deployment.clone();
+
+        blocking(move || {
+            workflow.create_deployment(
+                &deployment.application,
+                &deployment.template,
+                &deployment.environment,
+                &deployment.assignment,
+                deployment.cluster.as_ref(),
+                &deployment.resolved_values,
+            )
+        })
+        .await
+    }
 
-        self.link(&cluster_path)?;
+    /// Rendering replaces the previous output as a whole, so updating is the same as creating.
+    async fn update(&self, deployment: &Deployment) -> Result<DeploymentResult, Error> {
+        self.create(deployment).await
+    }
 
-        let kustomization_path = cluster_relative_path.join("kustomization.yaml");
-        let paths: Vec<PathBuf> = vec![kustomization_path];
+    async fn delete(&self, deployment: &Deployment) -> Result<Option<PendingReview>, Error> {
+        let workflow = self.clone();
+        let deployment = deployment.clone();
 
-        // TODO(ENH): Support different messages
-        let message = format!(
-            "Reconciling deleted ApplicationAssignment {} for Environment {} for Cluster {}",
-            assignment.metadata.name.as_ref().unwrap(),
-            assignment.spec.environment,
-            assignment.spec.cluster
-        );
+        blocking(move || {
+            workflow.delete_deployment(&deployment.assignment, deployment.cluster.as_ref())
+        })
+        .await
+    }
 
-        // add and commit output path in application cluster gitops repo
-        let oid = self.commit_files(&cluster_gitops_repo, &mut index, paths, &message)?;
+    async fn status(&self, deployment: &Deployment) -> Result<DeploymentStatus, Error> {
+        let workflow = self.clone();
+        let deployment = deployment.clone();
 
-        // TODO: make more flexible to support different branches
-        self.push(&cluster_gitops_repo, &self.application_repo_url, "main")?;
+        blocking(move || {
+            workflow.deployment_status(&deployment.assignment, deployment.cluster.as_ref())
+        })
+        .await
+    }
+}
 
-        Ok(oid)
+/// Locates an assignment's output in the GitOps repo: `<path>/<cluster>/<assignment>`.
+fn assignment_output<'a>(
+    target: &'a GitopsConfig,
+    assignment: &'a ApplicationAssignment,
+    cluster: Option<&'a Cluster>,
+) -> AssignmentOutput<'a> {
+    let cluster_path = Path::new(&target.path).join(&assignment.spec.cluster);
+    let path = cluster_path.join(assignment.metadata.name.as_deref().unwrap_or_default());
+
+    AssignmentOutput {
+        target,
+        assignment,
+        cluster,
+        cluster_path,
+        path,
     }
 }
 
+/// Branch that changes to an assignment are proposed on in pull request mode.
+fn review_branch(assignment: &ApplicationAssignment) -> String {
+    format!(
+        "application-api/{}/{}",
+        assignment
+            .metadata
+            .namespace
+            .as_deref()
+            .unwrap_or("default"),
+        assignment.metadata.name.as_deref().unwrap_or_default()
+    )
+}
+
 #[cfg(test)]
 mod tests {
+    use git2::{FetchOptions, Repository, RepositoryInitOptions, Signature};
     use kube::core::metadata::ObjectMeta;
+    use serde_json::{Map, Value};
     use std::collections::HashMap;
     use std::path::Path;
+    use std::sync::Arc;
+    use tempfile::{tempdir, TempDir};
 
     use crate::models::application::{Application, ApplicationSpec};
     use crate::models::assignment::{ApplicationAssignment, ApplicationAssignmentSpec};
+    use crate::models::cluster::{Cluster, ClusterGitopsSpec, ClusterSpec};
     use crate::models::environment::{ApplicationEnvironment, ApplicationEnvironmentSpec};
     use crate::models::template::{ApplicationTemplate, ApplicationTemplateSpec};
+    use crate::utils::config::{EncryptionConfig, GitopsConfig, GitopsMode, ValidationConfig};
+    use crate::workflows::cache::RepoCache;
+    use crate::workflows::encryption::Encryption;
+    use crate::workflows::flux::FluxLinker;
+    use crate::workflows::review::BranchChangeRequestProvider;
+    use crate::workflows::template::ResolvedValues;
+    use crate::workflows::testing::commit_file;
+    use crate::workflows::workflow::DeploymentStatus;
 
     use super::GitopsWorkflow;
 
+    fn test_workflow(repo: &str, cache_dir: &TempDir) -> GitopsWorkflow {
+        let cache = Arc::new(RepoCache::new(cache_dir.path().join("repos"), 8).unwrap());
+        let config = GitopsConfig {
+            repo: repo.to_string(),
+            ..GitopsConfig::default()
+        };
+
+        GitopsWorkflow::new(
+            &config,
+            cache,
+            Box::new(BranchChangeRequestProvider::new()),
+            Box::new(FluxLinker::new(&EncryptionConfig::default())),
+            Arc::new(Encryption::default()),
+            &ValidationConfig::default(),
+        )
+        .unwrap()
+    }
+
     #[test]
     fn can_create_deployment() {
-        let workflow =
-            GitopsWorkflow::new("git@github.com:timfpark/workload-cluster-gitops").unwrap();
+        let cache_dir = tempdir().unwrap();
+        let workflow = test_workflow(
+            "git@github.com:timfpark/workload-cluster-gitops",
+            &cache_dir,
+        );
 
-        let application_values: HashMap<String, String> = HashMap::new();
+        let application_values: Map<String, Value> = Map::new();
 
         let application = Application {
             api_version: "v1alpha1".to_string(),
@@ -430,10 +803,14 @@ mod tests {
             spec: ApplicationSpec {
                 template: "external-service".to_string(),
                 values: Some(application_values),
+                values_from: None,
+                reconciler: None,
+                flux: None,
+                argocd: None,
             },
         };
 
-        let environment_values: HashMap<String, String> = HashMap::new();
+        let environment_values: Map<String, Value> = Map::new();
 
         let environment = ApplicationEnvironment {
             api_version: "v1alpha1".to_string(),
@@ -446,11 +823,17 @@ mod tests {
             spec: ApplicationEnvironmentSpec {
                 application: "cluster-agent".to_string(),
                 environment: "dev".to_string(),
+                selector: None,
+                clusters: None,
+                spread_by: None,
                 values: Some(environment_values),
+                values_from: None,
+                flux: None,
+                argocd: None,
             },
         };
 
-        let assignment_values: HashMap<String, String> = HashMap::new();
+        let assignment_values: Map<String, Value> = Map::new();
 
         let assignment = ApplicationAssignment {
             api_version: "v1alpha1".to_string(),
@@ -464,7 +847,9 @@ mod tests {
                 cluster: "azure-eastus2-1".to_string(),
                 environment: "dev".to_string(),
                 values: Some(assignment_values),
+                values_from: None,
             },
+            status: None,
         };
 
         let template = ApplicationTemplate {
@@ -477,39 +862,321 @@ mod tests {
             },
             spec: ApplicationTemplateSpec {
                 repo: "git@github.com:timfpark/cluster-agent".to_string(),
-                reference: "main".to_string(),
+                reference: Some("main".to_string()),
                 path: "templates/deployment".to_string(),
+                strict: None,
             },
         };
 
-        match workflow.create_deployment(&application, &template, &environment, &assignment) {
-            Err(err) => {
-                println!("create deployment failed with: {:?}", err);
-                assert_eq!(false, true);
-            }
-            Ok(_) => {}
+        let cluster = Cluster::new(
+            "azure-eastus2-1",
+            ClusterSpec {
+                name: "azure-eastus2-1".to_string(),
+                labels: HashMap::new(),
+                environments: vec![],
+                facts: None,
+                gitops: None,
+                kubeconfig: None,
+            },
+        );
+
+        if let Err(err) = workflow.create_deployment(
+            &application,
+            &template,
+            &environment,
+            &assignment,
+            Some(&cluster),
+            &ResolvedValues::default(),
+        ) {
+            println!("create deployment failed with: {:?}", err);
+            assert_eq!(false, true);
         }
     }
 
     #[test]
-    fn can_render_application() {
-        let workflow =
-            GitopsWorkflow::new("git@github.com:timfpark/workload-cluster-gitops").unwrap();
+    fn cluster_overrides_default_target() {
+        let cache_dir = tempdir().unwrap();
+        let workflow = test_workflow(
+            "git@github.com:timfpark/workload-cluster-gitops",
+            &cache_dir,
+        );
+
+        let cluster = Cluster::new(
+            "azure-eastus2-1",
+            ClusterSpec {
+                name: "azure-eastus2-1".to_string(),
+                labels: HashMap::new(),
+                environments: vec![],
+                facts: None,
+                gitops: Some(ClusterGitopsSpec {
+                    repo: Some("git@github.com:timfpark/production-gitops".to_string()),
+                    branch: None,
+                    path: Some("clusters".to_string()),
+                    mode: Some(GitopsMode::PullRequest),
+                    bases: Some(vec!["../regions/{{region}}".to_string()]),
+                }),
+                kubeconfig: None,
+            },
+        );
+
+        let target = workflow.target(Some(&cluster)).unwrap();
+        assert_eq!(target.repo, "git@github.com:timfpark/production-gitops");
+        assert_eq!(target.branch, "main");
+        assert_eq!(target.path, "clusters");
+        assert_eq!(target.mode, GitopsMode::PullRequest);
+        assert_eq!(target.bases, vec!["../regions/{{region}}"]);
+
+        assert_eq!(workflow.target(None).unwrap(), workflow.defaults);
+    }
+
+    #[test]
+    fn replays_rejected_push_on_fetched_branch() {
+        let origin_dir = tempdir().unwrap();
+        Repository::init_bare(origin_dir.path()).unwrap();
+        let url = origin_dir.path().to_str().unwrap();
+
+        // another writer to the same GitOps repo
+        let other_dir = tempdir().unwrap();
+        let other = Repository::init_opts(
+            other_dir.path(),
+            RepositoryInitOptions::new().initial_head("main"),
+        )
+        .unwrap();
+        let mut other_remote = other.remote("origin", url).unwrap();
+        commit_file(&other, "README.md", "gitops");
+        other_remote
+            .push(&["refs/heads/main:refs/heads/main"], None)
+            .unwrap();
 
-        let mut values: HashMap<&str, &str> = HashMap::new();
-        values.insert("CLUSTER_NAME", "my-cluster");
+        let cache_dir = tempdir().unwrap();
+        let workflow = test_workflow(url, &cache_dir);
+        let target = workflow.target(None).unwrap();
+
+        let attempts = std::cell::Cell::new(0);
+        workflow
+            .cache
+            .with_repo(url, "gitops main", FetchOptions::new(), |repo| {
+                // lands after our working copy was fetched, so our first push is rejected
+                commit_file(&other, "clusters/other/app.yaml", "other");
+                other_remote
+                    .push(&["refs/heads/main:refs/heads/main"], None)
+                    .unwrap();
 
-        let template_path = Path::new("./fixtures/template");
-        let repo_root_path = Path::new("./fixtures/");
-        let root_relative_path = Path::new("applications/my-cluster");
-        let output_path = repo_root_path.join(root_relative_path);
+                workflow.commit_and_push(repo, &target, "ours", |_index| {
+                    attempts.set(attempts.get() + 1);
+                    let path = Path::new("clusters/ours/app.yaml");
+                    std::fs::create_dir_all(repo.workdir().unwrap().join("clusters/ours"))?;
+                    std::fs::write(repo.workdir().unwrap().join(path), "ours")?;
+                    Ok(vec![path.to_path_buf()])
+                })
+            })
+            .unwrap();
 
-        std::fs::create_dir_all(output_path).unwrap();
+        assert_eq!(attempts.get(), 2);
 
-        let paths = workflow
-            .render(template_path, repo_root_path, root_relative_path, &values)
+        let origin = Repository::open_bare(origin_dir.path()).unwrap();
+        let tree = origin
+            .revparse_single("refs/heads/main")
+            .unwrap()
+            .peel_to_tree()
             .unwrap();
+        assert!(tree.get_path(Path::new("clusters/ours/app.yaml")).is_ok());
+        assert!(tree.get_path(Path::new("clusters/other/app.yaml")).is_ok());
+    }
 
-        assert_eq!(paths.len(), 2);
+    #[test]
+    fn skips_commit_when_tree_is_unchanged() {
+        let origin_dir = tempdir().unwrap();
+        let origin = Repository::init_opts(
+            origin_dir.path(),
+            RepositoryInitOptions::new().initial_head("main").bare(true),
+        )
+        .unwrap();
+        let url = origin_dir.path().to_str().unwrap();
+
+        let seed_dir = tempdir().unwrap();
+        let seed = Repository::init_opts(
+            seed_dir.path(),
+            RepositoryInitOptions::new().initial_head("main"),
+        )
+        .unwrap();
+        commit_file(&seed, "clusters/ours/app.yaml", "ours");
+        seed.remote("origin", url)
+            .unwrap()
+            .push(&["refs/heads/main:refs/heads/main"], None)
+            .unwrap();
+        let tip = origin.refname_to_id("refs/heads/main").unwrap();
+
+        let cache_dir = tempdir().unwrap();
+        let workflow = test_workflow(url, &cache_dir);
+        let target = workflow.target(None).unwrap();
+
+        let pushed = workflow
+            .cache
+            .with_repo(url, "gitops main", FetchOptions::new(), |repo| {
+                workflow.commit_and_push(repo, &target, "ours", |_index| {
+                    let path = Path::new("clusters/ours/app.yaml");
+                    std::fs::write(repo.workdir().unwrap().join(path), "ours")?;
+                    Ok(vec![path.to_path_buf()])
+                })
+            })
+            .unwrap();
+
+        assert_eq!(pushed, None);
+        assert_eq!(origin.refname_to_id("refs/heads/main").unwrap(), tip);
+    }
+
+    #[test]
+    fn proposes_changes_for_review_in_pull_request_mode() {
+        let origin_dir = tempdir().unwrap();
+        let origin = Repository::init_opts(
+            origin_dir.path(),
+            RepositoryInitOptions::new().initial_head("main").bare(true),
+        )
+        .unwrap();
+        let url = origin_dir.path().to_str().unwrap();
+
+        let seed_dir = tempdir().unwrap();
+        let seed = Repository::init_opts(
+            seed_dir.path(),
+            RepositoryInitOptions::new().initial_head("main"),
+        )
+        .unwrap();
+        commit_file(&seed, "README.md", "gitops");
+        seed.remote("origin", url)
+            .unwrap()
+            .push(&["refs/heads/main:refs/heads/main"], None)
+            .unwrap();
+        let base = origin.refname_to_id("refs/heads/main").unwrap();
+
+        let cache_dir = tempdir().unwrap();
+        let mut workflow = test_workflow(url, &cache_dir);
+        workflow.defaults.mode = GitopsMode::PullRequest;
+        let target = workflow.target(None).unwrap();
+
+        let publish = |content: &str| {
+            workflow
+                .cache
+                .with_repo(url, "gitops main", FetchOptions::new(), |repo| {
+                    workflow.publish(
+                        repo,
+                        &target,
+                        "application-api/default/ours",
+                        "ours",
+                        |_| {
+                            let path = Path::new("clusters/ours/app.yaml");
+                            std::fs::create_dir_all(repo.workdir().unwrap().join("clusters/ours"))?;
+                            std::fs::write(repo.workdir().unwrap().join(path), content)?;
+                            Ok(vec![path.to_path_buf()])
+                        },
+                    )
+                })
+                .unwrap()
+        };
+
+        let proposed = publish("ours");
+        let review = proposed.review.unwrap();
+        assert_eq!(review.branch, "application-api/default/ours");
+        assert!(proposed.changed);
+        assert_eq!(origin.refname_to_id("refs/heads/main").unwrap(), base);
+        assert_eq!(
+            origin
+                .refname_to_id("refs/heads/application-api/default/ours")
+                .unwrap(),
+            proposed.commit
+        );
+
+        // unchanged content leaves the review branch alone
+        let again = publish("ours");
+        assert!(!again.changed);
+        assert_eq!(again.commit, proposed.commit);
+
+        // merging the review branch completes the deployment and removes the branch
+        origin
+            .reference("refs/heads/main", proposed.commit, true, "merge")
+            .unwrap();
+
+        let merged = publish("ours");
+        assert_eq!(merged.review, None);
+        assert!(!merged.changed);
+        assert_eq!(merged.commit, proposed.commit);
+        assert!(origin
+            .find_reference("refs/heads/application-api/default/ours")
+            .is_err());
+
+        // a squash merge, which the review branch is no ancestor of, removes the branch too
+        let proposed = publish("ours, updated");
+        assert!(proposed.review.is_some());
+
+        let signature = Signature::now("test", "test@example.com").unwrap();
+        let squashed = origin
+            .commit(
+                Some("refs/heads/main"),
+                &signature,
+                &signature,
+                "squash",
+                &origin.find_commit(proposed.commit).unwrap().tree().unwrap(),
+                &[&origin.find_commit(merged.commit).unwrap()],
+            )
+            .unwrap();
+
+        let squash_merged = publish("ours, updated");
+        assert_eq!(squash_merged.review, None);
+        assert!(!squash_merged.changed);
+        assert_eq!(squash_merged.commit, squashed);
+        assert!(origin
+            .find_reference("refs/heads/application-api/default/ours")
+            .is_err());
+    }
+
+    #[test]
+    fn reports_missing_deployment() {
+        let origin_dir = tempdir().unwrap();
+        Repository::init_opts(
+            origin_dir.path(),
+            RepositoryInitOptions::new().initial_head("main").bare(true),
+        )
+        .unwrap();
+        let url = origin_dir.path().to_str().unwrap();
+
+        let seed_dir = tempdir().unwrap();
+        let seed = Repository::init_opts(
+            seed_dir.path(),
+            RepositoryInitOptions::new().initial_head("main"),
+        )
+        .unwrap();
+        commit_file(&seed, "azure-eastus2-1/deployed/app.yaml", "deployed");
+        seed.remote("origin", url)
+            .unwrap()
+            .push(&["refs/heads/main:refs/heads/main"], None)
+            .unwrap();
+
+        let cache_dir = tempdir().unwrap();
+        let workflow = test_workflow(url, &cache_dir);
+
+        let assignment = |name: &str| {
+            ApplicationAssignment::new(
+                name,
+                ApplicationAssignmentSpec {
+                    cluster: "azure-eastus2-1".to_string(),
+                    environment: "dev".to_string(),
+                    values: None,
+                    values_from: None,
+                },
+            )
+        };
+
+        assert_eq!(
+            workflow
+                .deployment_status(&assignment("deployed"), None)
+                .unwrap(),
+            DeploymentStatus::Deployed
+        );
+        assert_eq!(
+            workflow
+                .deployment_status(&assignment("removed"), None)
+                .unwrap(),
+            DeploymentStatus::Missing
+        );
     }
 }
@@ -0,0 +1,679 @@
+use handlebars::template::{HelperTemplate, Parameter, Template, TemplateElement};
+use handlebars::{
+    no_escape, Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, ScopedJson,
+};
+use semver::{Version, VersionReq};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::Path;
+
+use crate::utils::error::Error;
+
+/// Directory of a template whose files are registered as partials rather than rendered.
+pub const PARTIALS_DIRECTORY: &str = "_partials";
+
+/// Helpers registered on top of the Handlebars built-ins, see `registry`.
+const HELPERS: [(&str, HelperFn); 13] = [
+    ("b64enc", b64enc),
+    ("b64dec", b64dec),
+    ("quote", quote),
+    ("indent", indent),
+    ("nindent", nindent),
+    ("toYaml", to_yaml),
+    ("toJson", to_json),
+    ("default", default),
+    ("required", required),
+    ("lower", lower),
+    ("upper", upper),
+    ("sha256sum", sha256sum),
+    ("semverCompare", semver_compare),
+];
+
+/// Helpers built into Handlebars.
+const BUILTIN_HELPERS: [&str; 17] = [
+    "if", "unless", "each", "with", "lookup", "raw", "log", "eq", "ne", "gt", "gte", "lt", "lte",
+    "and", "or", "not", "len",
+];
+
+/// Helpers whose parameters may be missing, even in strict mode, since they test for them.
+const PRESENCE_HELPERS: [&str; 4] = ["if", "unless", "default", "required"];
+
+/// Builds the Handlebars registry a template is rendered with: its helpers, and the partials in
+/// the template's `_partials` directory, named by their path without extension, eg.
+/// `{{> common/labels}}` for `_partials/common/labels.yaml`.
+///
+/// Output is not HTML escaped, since templates render YAML.
+///
+/// Helpers, usable as subexpressions as well:
+/// - `b64enc` / `b64dec` - Base64 encodes / decodes a string.
+/// - `quote` - Quotes a value as a double quoted YAML string.
+/// - `indent N s` / `nindent N s` - Indents every line of `s` by N spaces, after a newline for
+///   `nindent`.
+/// - `toYaml` / `toJson` - Serializes a value.
+/// - `default d v` - `v`, or `d` if `v` is missing, null, false or empty.
+/// - `required "message" v` - `v`, failing the render with `message` if it's missing or empty.
+/// - `lower` / `upper` - Changes the case of a string.
+/// - `sha256sum` - Hex SHA-256 digest of a string.
+/// - `semverCompare "constraint" version` - Whether a version, eg. `v1.21.2`, satisfies a
+///   constraint, eg. `>=1.21.0, <1.23.0`, with `||` between alternatives.
+///
+/// In strict mode, rendering a variable that is not defined fails, as does passing one to a
+/// helper other than `if`, `unless`, `default` and `required`.
+///
+/// # Arguments
+/// - `template_path` - Directory of the template within its checked out repo.
+/// - `strict` - Whether to render in strict mode.
+pub fn registry(template_path: &Path, strict: bool) -> Result<Handlebars<'static>, Error> {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(no_escape);
+    handlebars.set_strict_mode(strict);
+
+    for (name, helper) in HELPERS.iter() {
+        handlebars.register_helper(
+            name,
+            Box::new(ValueHelper {
+                name,
+                helper: *helper,
+                accepts_missing: PRESENCE_HELPERS.contains(name),
+            }),
+        );
+    }
+
+    let partials_path = template_path.join(PARTIALS_DIRECTORY);
+    if partials_path.is_dir() {
+        register_partials(&mut handlebars, &partials_path, "")?;
+    }
+
+    Ok(handlebars)
+}
+
+/// Registers the text files under `path` as partials, prefixing their names with `prefix`.
+/// Other files, which can't be included in a render, are skipped.
+fn register_partials(
+    handlebars: &mut Handlebars<'static>,
+    path: &Path,
+    prefix: &str,
+) -> Result<(), Error> {
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let entry_path = entry.path();
+
+        if entry.file_type()?.is_dir() {
+            let prefix = format!("{}{}/", prefix, entry.file_name().to_string_lossy());
+            register_partials(handlebars, &entry_path, &prefix)?;
+        } else {
+            let name = match entry_path.file_stem() {
+                Some(stem) => format!("{}{}", prefix, stem.to_string_lossy()),
+                None => continue,
+            };
+            let partial = match String::from_utf8(std::fs::read(&entry_path)?) {
+                Ok(partial) if !partial.contains('\0') => partial,
+                _ => continue,
+            };
+            handlebars
+                .register_partial(&name, partial)
+                .map_err(|err| Error::RenderError { source: err.into() })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Computes the value of a helper from its parameters, or a message why it can't.
+type HelperFn = fn(&[&Value]) -> Result<Value, String>;
+
+/// A helper that computes a value from its parameters.
+struct ValueHelper {
+    name: &'static str,
+    helper: HelperFn,
+    /// Whether parameters may be missing in strict mode.
+    accepts_missing: bool,
+}
+
+impl HelperDef for ValueHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        if r.strict_mode() && !self.accepts_missing {
+            if let Some(param) = h.params().iter().find(|param| param.is_value_missing()) {
+                return Err(RenderError::strict_error(param.relative_path()));
+            }
+        }
+
+        let params: Vec<&Value> = h.params().iter().map(|param| param.value()).collect();
+
+        (self.helper)(&params)
+            .map(ScopedJson::Derived)
+            .map_err(|message| RenderError::new(format!("`{}` helper: {}", self.name, message)))
+    }
+}
+
+/// A variable a template references that its values don't define.
+#[derive(Debug, PartialEq, Clone)]
+pub struct UndefinedVariable {
+    /// Name of the template, ie. its path within the template directory, or the partial's name.
+    pub template: String,
+    pub line: usize,
+    /// The variable as written in the template, eg. `cluster.region`.
+    pub variable: String,
+}
+
+impl fmt::Display for UndefinedVariable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.template, self.line, self.variable)
+    }
+}
+
+/// Lists the variables referenced by the template registered as `name`, and the partials it
+/// includes, that `values` don't define, so that all of them can be reported at once rather than
+/// one per render. Mirrors strict mode: parameters of `if`, `unless`, `default` and `required`
+/// may be missing. Variables within `each` and `with` blocks are only checked if they are
+/// relative to `@root`, since their context is only known when rendering.
+pub fn undefined_variables(
+    handlebars: &Handlebars,
+    name: &str,
+    values: &Value,
+) -> Vec<UndefinedVariable> {
+    let mut walker = VariableWalker {
+        handlebars,
+        values,
+        partials: Vec::new(),
+        guarded: Vec::new(),
+        undefined: Vec::new(),
+    };
+    if let Some(template) = handlebars.get_template(name) {
+        walker.walk_template(template, name, false);
+    }
+
+    walker.undefined
+}
+
+/// Walks the syntax tree of templates, collecting the variables they reference that are
+/// undefined.
+struct VariableWalker<'a> {
+    handlebars: &'a Handlebars<'a>,
+    values: &'a Value,
+    /// Partials being walked, to not recurse endlessly.
+    partials: Vec<String>,
+    /// Variables known to be defined within the blocks being walked, as the blocks only render if
+    /// they are, eg. `debug` within `{{#if debug}}`.
+    guarded: Vec<String>,
+    undefined: Vec<UndefinedVariable>,
+}
+
+impl<'a> VariableWalker<'a> {
+    /// # Arguments
+    /// - `template` - The template, or block of a template, to walk.
+    /// - `name` - Name of the template.
+    /// - `scoped` - Whether the template is within a block that changes the context.
+    fn walk_template(&mut self, template: &'a Template, name: &str, scoped: bool) {
+        for (index, element) in template.elements.iter().enumerate() {
+            let line = template.mapping.get(index).map_or(0, |mapping| mapping.0);
+
+            match element {
+                TemplateElement::Expression(helper) | TemplateElement::HtmlExpression(helper) => {
+                    self.walk_helper(helper, name, line, scoped)
+                }
+                TemplateElement::HelperBlock(helper) => {
+                    self.walk_helper(helper, name, line, scoped);
+
+                    let helper_name = helper.name.as_name();
+                    let keeps_context = matches!(helper_name, Some("if") | Some("unless"))
+                        && helper.block_param.is_none();
+                    // the block of `if`, and the inverse of `unless`, only render if the
+                    // condition is defined
+                    let guard = match helper.params.first() {
+                        Some(Parameter::Path(path)) if keeps_context => {
+                            variable(path, scoped).map(str::to_string)
+                        }
+                        _ => None,
+                    };
+                    let (block_guard, inverse_guard) = match helper_name {
+                        Some("if") => (guard, None),
+                        _ => (None, guard),
+                    };
+
+                    if let Some(block) = &helper.template {
+                        self.walk_guarded(block, name, scoped || !keeps_context, block_guard);
+                    }
+                    if let Some(inverse) = &helper.inverse {
+                        self.walk_guarded(inverse, name, scoped, inverse_guard);
+                    }
+                }
+                TemplateElement::PartialExpression(partial)
+                | TemplateElement::PartialBlock(partial) => {
+                    // partials given a context or parameters are rendered with those
+                    let partial_scoped =
+                        scoped || !partial.params.is_empty() || !partial.hash.is_empty();
+                    let partial_name = partial.name.as_name().unwrap_or_default().to_string();
+
+                    if !self.partials.contains(&partial_name) {
+                        if let Some(included) = self.handlebars.get_template(&partial_name) {
+                            self.partials.push(partial_name.clone());
+                            self.walk_template(included, &partial_name, partial_scoped);
+                            self.partials.pop();
+                        }
+                    }
+                    if let Some(block) = &partial.template {
+                        self.walk_template(block, name, scoped);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Walks a block with `guard`, if any, known to be defined within it.
+    fn walk_guarded(
+        &mut self,
+        template: &'a Template,
+        name: &str,
+        scoped: bool,
+        guard: Option<String>,
+    ) {
+        let is_guarded = guard.is_some();
+        self.guarded.extend(guard);
+        self.walk_template(template, name, scoped);
+        if is_guarded {
+            self.guarded.pop();
+        }
+    }
+
+    /// Walks an expression: either a variable, or a helper call and its parameters.
+    fn walk_helper(&mut self, helper: &'a HelperTemplate, name: &str, line: usize, scoped: bool) {
+        let helper_name = helper.name.as_name().unwrap_or_default();
+
+        if !is_helper(helper_name) {
+            // blocks of values render conditionally, like `if`
+            if !helper.block && helper.params.is_empty() && helper.hash.is_empty() {
+                if let Parameter::Path(path) = &helper.name {
+                    self.check_path(path, name, line, scoped);
+                }
+            }
+            return;
+        }
+
+        let accepts_missing = PRESENCE_HELPERS.contains(&helper_name);
+        for param in helper.params.iter().chain(helper.hash.values()) {
+            match param {
+                Parameter::Path(path) if !accepts_missing => {
+                    self.check_path(path, name, line, scoped)
+                }
+                Parameter::Subexpression(subexpression) => {
+                    if let TemplateElement::Expression(helper) = subexpression.as_element() {
+                        self.walk_helper(helper, name, line, scoped);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Records `path` if it is undefined.
+    fn check_path(&mut self, path: &handlebars::Path, name: &str, line: usize, scoped: bool) {
+        let (raw, variable) = match (path, variable(path, scoped)) {
+            (handlebars::Path::Relative((_, raw)), Some(variable)) => (raw, variable),
+            _ => return,
+        };
+        // a guarded variable is defined, and so are the maps containing it
+        let is_guarded = self.guarded.iter().any(|guarded| {
+            guarded == variable
+                || matches!(guarded.strip_prefix(variable), Some(rest) if rest.starts_with(['.', '/']))
+        });
+        if is_guarded {
+            return;
+        }
+
+        let mut value = Some(self.values);
+        for segment in variable.split(['.', '/']) {
+            let segment = segment.trim_start_matches('[').trim_end_matches(']');
+            value = match value {
+                Some(Value::Object(values)) => values.get(segment),
+                Some(Value::Array(values)) => segment
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| values.get(index)),
+                _ => None,
+            };
+        }
+
+        if value.is_none() {
+            self.undefined.push(UndefinedVariable {
+                template: name.to_string(),
+                line,
+                variable: raw.to_string(),
+            });
+        }
+    }
+}
+
+/// Returns the variable `path` refers to within the root values, or `None` if it isn't one that
+/// can be checked, eg. a path within the context of an `each` block.
+fn variable(path: &handlebars::Path, scoped: bool) -> Option<&str> {
+    let raw = match path {
+        handlebars::Path::Relative((_, raw)) => raw.as_str(),
+        // `@index` and the like
+        handlebars::Path::Local(_) => return None,
+    };
+
+    let (variable, scoped) = match raw
+        .strip_prefix("@root.")
+        .or_else(|| raw.strip_prefix("@root/"))
+    {
+        Some(variable) => (variable, false),
+        None => (raw, scoped),
+    };
+    if scoped || variable.starts_with("../") || variable.starts_with('@') {
+        return None;
+    }
+    let variable = ["this.", "this/", "./"]
+        .iter()
+        .find_map(|prefix| variable.strip_prefix(prefix))
+        .unwrap_or(variable);
+    if variable.is_empty() || variable == "this" || variable == "." {
+        return None;
+    }
+
+    Some(variable)
+}
+
+/// Whether `name` is a helper rather than a variable.
+fn is_helper(name: &str) -> bool {
+    BUILTIN_HELPERS.contains(&name) || HELPERS.iter().any(|(helper, _)| *helper == name)
+}
+
+/// Returns the parameter at `index`, which has to be given.
+fn param<'a>(params: &[&'a Value], index: usize) -> Result<&'a Value, String> {
+    params
+        .get(index)
+        .copied()
+        .ok_or_else(|| format!("expects at least {} parameters", index + 1))
+}
+
+/// Returns the parameter at `index` as a string. Missing values and null are empty, and other
+/// values are serialized as JSON.
+fn string_param(params: &[&Value], index: usize) -> Result<String, String> {
+    Ok(match param(params, index)? {
+        Value::String(string) => string.clone(),
+        Value::Null => String::new(),
+        value => value.to_string(),
+    })
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Bool(boolean) => !boolean,
+        Value::String(string) => string.is_empty(),
+        Value::Array(values) => values.is_empty(),
+        Value::Object(values) => values.is_empty(),
+        Value::Number(_) => false,
+    }
+}
+
+fn b64enc(params: &[&Value]) -> Result<Value, String> {
+    Ok(Value::String(base64::encode(string_param(params, 0)?)))
+}
+
+fn b64dec(params: &[&Value]) -> Result<Value, String> {
+    let decoded = base64::decode(string_param(params, 0)?).map_err(|err| err.to_string())?;
+
+    String::from_utf8(decoded)
+        .map(Value::String)
+        .map_err(|_| "decoded value is not UTF-8 text".to_string())
+}
+
+fn quote(params: &[&Value]) -> Result<Value, String> {
+    // JSON strings are valid double quoted YAML strings
+    Ok(Value::String(
+        Value::String(string_param(params, 0)?).to_string(),
+    ))
+}
+
+fn indent(params: &[&Value]) -> Result<Value, String> {
+    let width = param(params, 0)?
+        .as_u64()
+        .ok_or_else(|| "width has to be a number".to_string())?;
+    let padding = " ".repeat(width as usize);
+
+    let indented: Vec<String> = string_param(params, 1)?
+        .split('\n')
+        .map(|line| format!("{}{}", padding, line))
+        .collect();
+
+    Ok(Value::String(indented.join("\n")))
+}
+
+fn nindent(params: &[&Value]) -> Result<Value, String> {
+    let indented = indent(params)?;
+
+    Ok(Value::String(format!(
+        "\n{}",
+        indented.as_str().unwrap_or_default()
+    )))
+}
+
+fn to_yaml(params: &[&Value]) -> Result<Value, String> {
+    let yaml = serde_yaml::to_string(param(params, 0)?).map_err(|err| err.to_string())?;
+
+    Ok(Value::String(
+        yaml.trim_start_matches("---\n").trim_end().to_string(),
+    ))
+}
+
+fn to_json(params: &[&Value]) -> Result<Value, String> {
+    Ok(Value::String(param(params, 0)?.to_string()))
+}
+
+fn default(params: &[&Value]) -> Result<Value, String> {
+    let default = param(params, 0)?;
+
+    match params.get(1) {
+        Some(value) if !is_empty(value) => Ok((*value).clone()),
+        _ => Ok(default.clone()),
+    }
+}
+
+fn required(params: &[&Value]) -> Result<Value, String> {
+    let message = string_param(params, 0)?;
+
+    match params.get(1) {
+        Some(value) if !is_empty(value) => Ok((*value).clone()),
+        _ => Err(message),
+    }
+}
+
+fn lower(params: &[&Value]) -> Result<Value, String> {
+    Ok(Value::String(string_param(params, 0)?.to_lowercase()))
+}
+
+fn upper(params: &[&Value]) -> Result<Value, String> {
+    Ok(Value::String(string_param(params, 0)?.to_uppercase()))
+}
+
+fn sha256sum(params: &[&Value]) -> Result<Value, String> {
+    let digest = Sha256::digest(string_param(params, 0)?.as_bytes());
+
+    Ok(Value::String(
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect(),
+    ))
+}
+
+fn semver_compare(params: &[&Value]) -> Result<Value, String> {
+    let constraint = string_param(params, 0)?;
+    let version = string_param(params, 1)?;
+
+    let version = Version::parse(version.trim().trim_start_matches('v'))
+        .map_err(|err| format!("invalid version '{}': {}", version, err))?;
+
+    for alternative in constraint.split("||") {
+        let requirement = VersionReq::parse(alternative.trim())
+            .map_err(|err| format!("invalid constraint '{}': {}", constraint, err))?;
+        if requirement.matches(&version) {
+            return Ok(Value::Bool(true));
+        }
+    }
+
+    Ok(Value::Bool(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::fs;
+    use tempfile::tempdir;
+
+    use super::{registry, undefined_variables};
+
+    #[test]
+    fn renders_helpers_and_partials() {
+        let template_dir = tempdir().unwrap();
+        fs::create_dir_all(template_dir.path().join("_partials/common")).unwrap();
+        fs::write(
+            template_dir.path().join("_partials/common/labels.yaml"),
+            "app: {{name}}\nring: {{lower ring}}",
+        )
+        .unwrap();
+
+        let handlebars = registry(template_dir.path(), false).unwrap();
+        let values = json!({
+            "name": "cluster-agent",
+            "ring": "MAIN",
+            "password": "hunter2",
+            "resources": { "limits": { "cpu": "500m" } },
+            "kubernetesVersion": "v1.21.2",
+        });
+        let render = |template: &str| handlebars.render_template(template, &values);
+
+        assert_eq!(
+            render("{{> common/labels}}").unwrap(),
+            "app: cluster-agent\nring: main"
+        );
+        assert_eq!(render("{{b64enc password}}").unwrap(), "aHVudGVyMg==");
+        assert_eq!(render("{{b64dec (b64enc password)}}").unwrap(), "hunter2");
+        assert_eq!(render("{{quote name}}").unwrap(), "\"cluster-agent\"");
+        assert_eq!(render("{{upper name}}").unwrap(), "CLUSTER-AGENT");
+        assert_eq!(
+            render("resources:{{nindent 2 (toYaml resources)}}").unwrap(),
+            "resources:\n  limits:\n    cpu: 500m"
+        );
+        assert_eq!(
+            render("{{toJson resources}}").unwrap(),
+            "{\"limits\":{\"cpu\":\"500m\"}}"
+        );
+        assert_eq!(render("{{default 1 replicas}}").unwrap(), "1");
+        assert_eq!(render("{{default \"x\" name}}").unwrap(), "cluster-agent");
+        assert!(render("{{required \"replicas is required\" replicas}}")
+            .unwrap_err()
+            .to_string()
+            .contains("replicas is required"));
+        assert_eq!(
+            render("{{sha256sum password}}").unwrap(),
+            "f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7"
+        );
+        assert_eq!(
+            render("{{#if (semverCompare \">=1.21.0, <1.23.0\" kubernetesVersion)}}new{{else}}old{{/if}}")
+                .unwrap(),
+            "new"
+        );
+        assert_eq!(
+            render("{{semverCompare \"<1.20.0 || >=1.22.0\" kubernetesVersion}}").unwrap(),
+            "false"
+        );
+    }
+
+    #[test]
+    fn renders_values_unescaped() {
+        let template_dir = tempdir().unwrap();
+        fs::create_dir_all(template_dir.path().join("_partials")).unwrap();
+        fs::write(
+            template_dir.path().join("_partials/logo.png"),
+            b"\x89PNG\0\xff",
+        )
+        .unwrap();
+
+        let handlebars = registry(template_dir.path(), true).unwrap();
+        assert!(!handlebars.has_template("logo"));
+
+        let values = json!({ "connectionString": "Server=db;User=<sa>&Password=\"it's\"" });
+        assert_eq!(
+            handlebars
+                .render_template("connectionString: {{connectionString}}", &values)
+                .unwrap(),
+            "connectionString: Server=db;User=<sa>&Password=\"it's\""
+        );
+    }
+
+    #[test]
+    fn finds_undefined_variables() {
+        let template_dir = tempdir().unwrap();
+        fs::create_dir_all(template_dir.path().join("_partials")).unwrap();
+        fs::write(
+            template_dir.path().join("_partials/labels.yaml"),
+            "app: {{name}}\nteam: {{team}}",
+        )
+        .unwrap();
+
+        let mut handlebars = registry(template_dir.path(), true).unwrap();
+        let template = "\
+name: {{name}}
+cluster: {{CLUSTER_NAM}}
+region: {{cluster.region}}
+replicas: {{default 1 replicas}}
+{{#if debug}}debug: {{debug}}{{/if}}{{#unless logLevel}}info{{else}}{{logLevel}}{{/unless}}
+{{#if probe.path}}{{probe.port}}{{probe.path}}{{probe}}{{/if}}{{#if port}}{{else}}{{port}}{{/if}}
+image: {{lower image.tag}}
+{{#each ports}}port: {{this.port}} {{@root.hostname}}{{/each}}
+labels:
+  {{> labels}}
+";
+        handlebars
+            .register_template_string("dev/deployment.yaml", template)
+            .unwrap();
+
+        let values = json!({
+            "name": "cluster-agent",
+            "cluster": { "name": "azure-eastus2-1" },
+            "ports": [{ "port": 80 }],
+        });
+        let undefined: Vec<String> =
+            undefined_variables(&handlebars, "dev/deployment.yaml", &values)
+                .iter()
+                .map(|variable| variable.to_string())
+                .collect();
+
+        assert_eq!(
+            undefined,
+            vec![
+                "dev/deployment.yaml:2: CLUSTER_NAM",
+                "dev/deployment.yaml:3: cluster.region",
+                "dev/deployment.yaml:6: probe.port",
+                "dev/deployment.yaml:6: port",
+                "dev/deployment.yaml:7: image.tag",
+                "dev/deployment.yaml:8: @root.hostname",
+                "labels:2: team",
+            ]
+        );
+
+        // rendering fails on the first one, naming the file, line and variable
+        let err = handlebars
+            .render("dev/deployment.yaml", &values)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("dev/deployment.yaml"));
+        assert!(err.contains("line 2"));
+        assert!(err.contains("CLUSTER_NAM"));
+
+        // guarded variables render in strict mode without being defined
+        handlebars
+            .register_template_string("dev/debug.yaml", "{{#if debug}}debug: {{debug}}{{/if}}")
+            .unwrap();
+        assert!(undefined_variables(&handlebars, "dev/debug.yaml", &values).is_empty());
+        assert_eq!(handlebars.render("dev/debug.yaml", &values).unwrap(), "");
+    }
+}
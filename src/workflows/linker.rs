@@ -0,0 +1,72 @@
+use git2::{Index, Repository};
+use std::path::{Path, PathBuf};
+
+use crate::models::application::Application;
+use crate::models::assignment::ApplicationAssignment;
+use crate::models::cluster::Cluster;
+use crate::models::environment::ApplicationEnvironment;
+use crate::utils::config::GitopsConfig;
+use crate::utils::error::Error;
+
+/// Where an assignment is rendered to in the GitOps repo.
+pub struct AssignmentOutput<'a> {
+    /// GitOps repo, branch and path the assignment is committed to.
+    pub target: &'a GitopsConfig,
+    pub assignment: &'a ApplicationAssignment,
+    /// The `Cluster` resource assigned to, if one exists.
+    pub cluster: Option<&'a Cluster>,
+    /// Directory of the assignment's cluster, relative to the repo root.
+    pub cluster_path: PathBuf,
+    /// Directory the assignment is rendered into, relative to the repo root.
+    pub path: PathBuf,
+}
+
+/// Points the GitOps agent running on a cluster at the rendered output of its assignments, eg. by
+/// writing a Flux `Kustomization` or an Argo CD `Application` for each.
+pub trait Linker: Send + Sync {
+    /// Writes whatever makes the cluster's GitOps agent deploy a freshly rendered assignment.
+    /// Returns the written paths, relative to the repo root, to add to the index.
+    ///
+    /// # Arguments
+    /// - `repo` - GitOps working copy, with the assignment already rendered into it.
+    /// - `index` - Index of the working copy.
+    /// - `output` - Where the assignment was rendered to.
+    /// - `application` - The `Application` the assignment deploys.
+    /// - `environment` - The `ApplicationEnvironment` the assignment deploys.
+    fn link(
+        &self,
+        repo: &Repository,
+        index: &mut Index,
+        output: &AssignmentOutput,
+        application: &Application,
+        environment: &ApplicationEnvironment,
+    ) -> Result<Vec<PathBuf>, Error>;
+
+    /// Removes whatever `link` wrote for an assignment whose rendered output was removed.
+    /// Returns the paths, relative to the repo root, to add to the index.
+    ///
+    /// # Arguments
+    /// - `repo` - GitOps working copy, with the assignment's output already removed.
+    /// - `index` - Index of the working copy.
+    /// - `output` - Where the assignment was rendered to.
+    fn unlink(
+        &self,
+        repo: &Repository,
+        index: &mut Index,
+        output: &AssignmentOutput,
+    ) -> Result<Vec<PathBuf>, Error>;
+}
+
+/// Removes a file from both the index and the working tree, if it exists.
+pub fn remove_file(repo: &Repository, index: &mut Index, path: &Path) -> Result<(), Error> {
+    let absolute_path = repo.workdir().unwrap().join(path);
+
+    if absolute_path.exists() {
+        std::fs::remove_file(absolute_path)?;
+    }
+    if index.get_path(path, 0).is_some() {
+        index.remove_path(path)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,313 @@
+use jsonschema::paths::PathChunk;
+use jsonschema::JSONSchema;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::path::Path;
+
+use crate::utils::error::Error;
+
+/// Optional file at the root of a template describing the values it is rendered with.
+pub const TEMPLATE_MANIFEST_FILE: &str = "template.yaml";
+
+/// Contents of a template's `template.yaml`, eg.:
+///
+/// ```yaml
+/// schema:
+///   type: object
+///   required: [image]
+///   properties:
+///     image:
+///       type: string
+///       description: Image of the agent, including its tag.
+///     replicas:
+///       type: integer
+///       default: 1
+///       minimum: 1
+/// ```
+#[derive(Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TemplateManifest {
+    /// JSON Schema of the values set by the application, its environment and its assignment.
+    /// `default`s of properties are filled in where values are missing, and the values are then
+    /// validated against it. The facts of the cluster are added afterwards, so they don't have
+    /// to be part of it.
+    pub schema: Value,
+}
+
+impl TemplateManifest {
+    /// Reads the `template.yaml` of a template, if it has one.
+    ///
+    /// # Arguments
+    /// - `template_path` - Directory of the template within its checked out repo.
+    pub fn load(template_path: &Path) -> Result<Option<TemplateManifest>, Error> {
+        let manifest_path = template_path.join(TEMPLATE_MANIFEST_FILE);
+        if !manifest_path.is_file() {
+            return Ok(None);
+        }
+
+        let invalid = |err: &dyn std::fmt::Display| {
+            Error::UserInputError(format!(
+                "invalid {} in template: {}",
+                TEMPLATE_MANIFEST_FILE, err
+            ))
+        };
+
+        let manifest: TemplateManifest =
+            serde_yaml::from_str(&std::fs::read_to_string(manifest_path)?)
+                .map_err(|err| invalid(&err))?;
+        if !manifest.schema.is_null() {
+            JSONSchema::compile(&manifest.schema).map_err(|err| invalid(&err))?;
+        }
+
+        Ok(Some(manifest))
+    }
+
+    /// Fills the defaults of the schema into `values`, and validates them against it. Every
+    /// value that doesn't match is reported in the error, eg. `replicas: "two" is not of type
+    /// "integer"`.
+    pub fn apply(&self, values: &mut Value) -> Result<(), Error> {
+        if self.schema.is_null() {
+            return Ok(());
+        }
+
+        Defaults {
+            root: &self.schema,
+            filling: Vec::new(),
+        }
+        .apply(&self.schema, values);
+
+        let schema = JSONSchema::compile(&self.schema).map_err(|err| {
+            Error::UserInputError(format!(
+                "invalid {} in template: {}",
+                TEMPLATE_MANIFEST_FILE, err
+            ))
+        })?;
+
+        let errors: Vec<String> = match schema.validate(values) {
+            Ok(()) => return Ok(()),
+            Err(errors) => errors
+                .map(|err| format!("{}: {}", value_path(err.instance_path.iter()), err))
+                .collect(),
+        };
+
+        Err(Error::UserInputError(format!(
+            "values don't match the schema in {} of the template: {}",
+            TEMPLATE_MANIFEST_FILE,
+            errors.join(", ")
+        )))
+    }
+}
+
+/// Prepares the values a template is rendered with according to its `template.yaml`, if it has
+/// one, see `TemplateManifest::apply`.
+pub fn apply_template_manifest(template_path: &Path, values: &mut Value) -> Result<(), Error> {
+    match TemplateManifest::load(template_path)? {
+        Some(manifest) => manifest.apply(values),
+        None => Ok(()),
+    }
+}
+
+/// Formats the location of a value within the values, eg. `image.tag` or `ports[0]`.
+fn value_path<'a>(chunks: impl Iterator<Item = &'a PathChunk>) -> String {
+    let mut path = String::new();
+    for chunk in chunks {
+        match chunk {
+            PathChunk::Property(key) if path.is_empty() => path.push_str(key),
+            PathChunk::Property(key) => path.push_str(&format!(".{}", key)),
+            PathChunk::Index(index) => path.push_str(&format!("[{}]", index)),
+            PathChunk::Keyword(keyword) => path.push_str(&format!(".{}", keyword)),
+        }
+    }
+
+    if path.is_empty() {
+        "values".to_string()
+    } else {
+        path
+    }
+}
+
+/// Fills in the `default`s of a schema, which validating doesn't. Follows `properties`, `allOf`
+/// and local `$ref`s, eg. to `#/definitions/resources`.
+struct Defaults<'a> {
+    root: &'a Value,
+    /// Schemas of missing objects being filled in, so recursive schemas end.
+    filling: Vec<&'a Value>,
+}
+
+impl<'a> Defaults<'a> {
+    /// Resolves `$ref`s of a schema. `None` for boolean schemas and unknown references.
+    fn resolve(&self, schema: &'a Value) -> Option<&'a Value> {
+        match schema.get("$ref").and_then(Value::as_str) {
+            Some(reference) => self.resolve(self.root.pointer(reference.strip_prefix('#')?)?),
+            None => Some(schema).filter(|schema| schema.is_object()),
+        }
+    }
+
+    /// Adds the defaults of missing properties to `value`, and of missing objects whose
+    /// properties have defaults.
+    fn apply(&mut self, schema: &'a Value, value: &mut Value) {
+        let schema = match self.resolve(schema) {
+            Some(schema) => schema,
+            None => return,
+        };
+
+        for subschema in schema
+            .get("allOf")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            self.apply(subschema, value);
+        }
+
+        let (properties, values) = match (schema.get("properties"), value) {
+            (Some(Value::Object(properties)), Value::Object(values)) => (properties, values),
+            _ => return,
+        };
+
+        for (key, property) in properties.iter() {
+            if !values.contains_key(key) {
+                let default = self
+                    .resolve(property)
+                    .and_then(|property| property.get("default"))
+                    .cloned();
+
+                let filling = self
+                    .filling
+                    .iter()
+                    .any(|filling| std::ptr::eq(*filling, property));
+                let default = default.or_else(|| {
+                    if filling {
+                        return None;
+                    }
+                    self.filling.push(property);
+                    let mut nested = Value::Object(Map::new());
+                    self.apply(property, &mut nested);
+                    self.filling.pop();
+                    Some(nested).filter(|nested| nested != &Value::Object(Map::new()))
+                });
+
+                if let Some(default) = default {
+                    values.insert(key.clone(), default);
+                }
+            }
+
+            if let Some(value) = values.get_mut(key) {
+                self.apply(property, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    use super::{apply_template_manifest, TemplateManifest};
+
+    #[test]
+    fn applies_defaults_and_validates_values() {
+        let template_dir = tempdir().unwrap();
+        let mut values = json!({ "replicas": 2 });
+
+        // templates without a manifest take any values
+        apply_template_manifest(template_dir.path(), &mut values).unwrap();
+        assert_eq!(values, json!({ "replicas": 2 }));
+
+        std::fs::write(
+            template_dir.path().join("template.yaml"),
+            "\
+schema:
+  type: object
+  required: [image]
+  additionalProperties: false
+  properties:
+    image:
+      type: string
+      description: Image of the agent, including its tag.
+      pattern: ':'
+    replicas:
+      type: integer
+      default: 1
+      minimum: 1
+    logLevel:
+      enum: [debug, info, warn]
+      default: info
+    resources:
+      $ref: '#/definitions/resources'
+    ports:
+      type: array
+      items:
+        type: integer
+  definitions:
+    resources:
+      type: object
+      additionalProperties: false
+      properties:
+        cpu:
+          type: string
+          default: 250m
+        memory:
+          type: string
+",
+        )
+        .unwrap();
+        let manifest = TemplateManifest::load(template_dir.path())
+            .unwrap()
+            .unwrap();
+
+        let mut values = json!({ "image": "cluster-agent:1.0" });
+        manifest.apply(&mut values).unwrap();
+        assert_eq!(
+            values,
+            json!({
+                "image": "cluster-agent:1.0",
+                "replicas": 1,
+                "logLevel": "info",
+                "resources": { "cpu": "250m" },
+            })
+        );
+
+        let mut values = json!({
+            "replicas": "two",
+            "logLevel": "trace",
+            "resources": { "memory": 512, "disk": "1Gi" },
+            "ports": [80, "http"],
+            "debug": true,
+        });
+        let err = manifest.apply(&mut values).unwrap_err().to_string();
+        for expected in [
+            "values: \"image\" is a required property",
+            "replicas: \"two\" is not of type \"integer\"",
+            "logLevel: \"trace\" is not one of [\"debug\",\"info\",\"warn\"]",
+            "resources.memory: 512 is not of type \"string\"",
+            "resources: Additional properties are not allowed ('disk' was unexpected)",
+            "ports[1]: \"http\" is not of type \"integer\"",
+            "values: Additional properties are not allowed ('debug' was unexpected)",
+        ] {
+            assert!(err.contains(expected), "{} not in {}", expected, err);
+        }
+
+        let mut values = json!({ "image": "cluster-agent", "replicas": 0 });
+        let err = manifest.apply(&mut values).unwrap_err().to_string();
+        assert!(
+            err.contains("image: \"cluster-agent\" does not match \":\""),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("replicas: 0 is less than the minimum of 1"),
+            "{}",
+            err
+        );
+
+        // schemas that don't compile are refused
+        std::fs::write(
+            template_dir.path().join("template.yaml"),
+            "schema:\n  type: thing\n",
+        )
+        .unwrap();
+        assert!(TemplateManifest::load(template_dir.path()).is_err());
+    }
+}
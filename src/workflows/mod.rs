@@ -1,2 +1,16 @@
+pub mod apply;
+pub mod argocd;
+pub mod cache;
+pub mod encryption;
+pub mod flux;
 pub mod gitops;
-// pub mod workflow;
+pub mod helpers;
+pub mod linker;
+pub mod manifest;
+pub mod review;
+pub mod sops;
+pub mod template;
+#[cfg(test)]
+pub mod testing;
+pub mod validation;
+pub mod workflow;
@@ -0,0 +1,152 @@
+use git2::Repository;
+use std::sync::Mutex;
+
+use crate::utils::error::Error;
+
+/// A proposal to merge a branch of the GitOps repo into the branch clusters are deployed from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ChangeRequest {
+    /// URL of the GitOps repo.
+    pub repo: String,
+    /// Branch with the proposed change.
+    pub branch: String,
+    /// Branch the change is to be merged into.
+    pub base: String,
+    pub title: String,
+    pub description: String,
+}
+
+/// A change that was pushed for review and not yet merged.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PendingReview {
+    /// Branch with the proposed change.
+    pub branch: String,
+    /// Reference to the change request, eg. its URL, as returned by the provider.
+    pub change_request: String,
+}
+
+/// Opens and tracks change requests (pull requests, merge requests, ...) with the service hosting
+/// the GitOps repo.
+pub trait ChangeRequestProvider: Send + Sync {
+    /// Opens a change request, or finds the one already open for `request.branch`, and returns a
+    /// reference to it.
+    ///
+    /// # Arguments
+    /// - `repo` - Working copy of the GitOps repo, with `request.branch` pushed and fetched.
+    /// - `request` - The change request to open.
+    fn open(&self, repo: &Repository, request: &ChangeRequest) -> Result<String, Error>;
+
+    /// Returns whether the change request for `request.branch` has been merged into `request.base`.
+    ///
+    /// # Arguments
+    /// - `repo` - Freshly fetched working copy of the GitOps repo.
+    /// - `request` - The change request to check.
+    fn is_merged(&self, repo: &Repository, request: &ChangeRequest) -> Result<bool, Error>;
+
+    /// Closes the change request for `request.branch` without merging it, eg. because `base`
+    /// already has its change. Its branch has been deleted by then.
+    ///
+    /// # Arguments
+    /// - `repo` - Working copy of the GitOps repo.
+    /// - `request` - The change request to close.
+    fn close(&self, repo: &Repository, request: &ChangeRequest) -> Result<(), Error>;
+}
+
+/// Provider that needs nothing but the Git repo itself: the pushed branch is the change request,
+/// and it counts as merged once `base` contains its tip, however it got there, eg. merged by hand
+/// or through a pull request opened on the hosting service. Change requests are only recorded in
+/// memory.
+#[derive(Default)]
+pub struct BranchChangeRequestProvider {
+    requests: Mutex<Vec<ChangeRequest>>,
+}
+
+impl BranchChangeRequestProvider {
+    pub fn new() -> Self {
+        BranchChangeRequestProvider::default()
+    }
+
+    /// Returns the change requests that are open.
+    #[cfg(test)]
+    pub fn requests(&self) -> Vec<ChangeRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl ChangeRequestProvider for BranchChangeRequestProvider {
+    fn open(&self, _repo: &Repository, request: &ChangeRequest) -> Result<String, Error> {
+        let mut requests = self.requests.lock().unwrap();
+
+        match requests
+            .iter_mut()
+            .find(|existing| existing.repo == request.repo && existing.branch == request.branch)
+        {
+            Some(existing) => *existing = request.clone(),
+            None => requests.push(request.clone()),
+        }
+
+        Ok(format!(
+            "{} {} -> {}",
+            request.repo, request.branch, request.base
+        ))
+    }
+
+    fn is_merged(&self, repo: &Repository, request: &ChangeRequest) -> Result<bool, Error> {
+        let branch = repo.refname_to_id(&format!("refs/remotes/origin/{}", request.branch))?;
+        let base = repo.refname_to_id(&format!("refs/remotes/origin/{}", request.base))?;
+
+        Ok(branch == base || repo.graph_descendant_of(base, branch)?)
+    }
+
+    fn close(&self, _repo: &Repository, request: &ChangeRequest) -> Result<(), Error> {
+        self.requests
+            .lock()
+            .unwrap()
+            .retain(|existing| existing.repo != request.repo || existing.branch != request.branch);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::Repository;
+    use tempfile::tempdir;
+
+    use crate::workflows::testing::commit_file;
+
+    use super::{BranchChangeRequestProvider, ChangeRequest, ChangeRequestProvider};
+
+    #[test]
+    fn tracks_branch_change_requests_until_merged() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let base = commit_file(&repo, "README.md", "gitops");
+        let proposed = commit_file(&repo, "clusters/ours/app.yaml", "ours");
+        repo.reference("refs/remotes/origin/main", base, true, "base")
+            .unwrap();
+        repo.reference("refs/remotes/origin/review", proposed, true, "review")
+            .unwrap();
+
+        let request = ChangeRequest {
+            repo: "gitops".to_string(),
+            branch: "review".to_string(),
+            base: "main".to_string(),
+            title: "ours".to_string(),
+            description: "ours".to_string(),
+        };
+
+        let provider = BranchChangeRequestProvider::new();
+        provider.open(&repo, &request).unwrap();
+        provider.open(&repo, &request).unwrap();
+        assert_eq!(provider.requests(), vec![request.clone()]);
+        assert!(!provider.is_merged(&repo, &request).unwrap());
+
+        repo.reference("refs/remotes/origin/main", proposed, true, "merge")
+            .unwrap();
+        assert!(provider.is_merged(&repo, &request).unwrap());
+
+        provider.close(&repo, &request).unwrap();
+        assert!(provider.requests().is_empty());
+    }
+}
@@ -0,0 +1,678 @@
+use aes_gcm::aead::generic_array::typenum::U32;
+use aes_gcm::aes::Aes256;
+use aes_gcm::{AeadInPlace, AesGcm, NewAead};
+use k8s_openapi::chrono::{SecondsFormat, Utc};
+use rand::RngCore;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+use sha2::{Digest, Sha512};
+use std::path::Path;
+
+use crate::utils::error::Error;
+use crate::workflows::encryption::{age_encrypt, parse_recipients, Encryptor};
+
+/// AES-256-GCM with the 32 byte nonces SOPS uses.
+type SopsCipher = AesGcm<Aes256, U32>;
+
+/// Version of SOPS whose file format is written.
+const SOPS_VERSION: &str = "3.7.1";
+
+/// Suffix of the keys whose values SOPS leaves in plaintext when no `encrypted_regex` is set.
+const UNENCRYPTED_SUFFIX: &str = "_unencrypted";
+
+/// Encrypts YAML files the way `sops --encrypt --age` does, so that Flux's SOPS decryption can
+/// decrypt them. Every document of a file is encrypted on its own, with its own data key, since
+/// Flux decrypts objects one at a time.
+pub struct SopsEncryptor {
+    recipients: Vec<age::x25519::Recipient>,
+    /// Only values under matching keys are encrypted, if set.
+    encrypted_regex: Option<Regex>,
+}
+
+/// Which values of a document are encrypted, as recorded in its `sops` metadata.
+enum Selection<'a> {
+    /// Values under keys matching the regex.
+    EncryptedRegex(&'a Regex),
+    /// Values under no key ending in `UNENCRYPTED_SUFFIX`.
+    UnencryptedSuffix,
+}
+
+/// The `sops` metadata of an encrypted document.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SopsMetadata {
+    pub kms: Vec<Value>,
+    pub gcp_kms: Vec<Value>,
+    pub azure_kv: Vec<Value>,
+    pub hc_vault: Vec<Value>,
+    pub age: Vec<SopsAgeKey>,
+    /// Time of encryption, which authenticates the `mac`.
+    pub lastmodified: String,
+    /// Encrypted digest of all values of the document.
+    pub mac: String,
+    pub pgp: Vec<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encrypted_regex: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unencrypted_suffix: Option<String>,
+    pub version: String,
+}
+
+/// The data key of a document, encrypted to an age recipient.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SopsAgeKey {
+    pub recipient: String,
+    pub enc: String,
+}
+
+impl SopsEncryptor {
+    /// # Arguments
+    /// - `recipients` - age public keys, eg. `age1...`, that can decrypt the files.
+    /// - `encrypted_regex` - Only values under keys matching it are encrypted. Empty encrypts
+    ///   every value except those under keys ending in `_unencrypted`.
+    pub fn new(recipients: &[String], encrypted_regex: &str) -> Result<Self, Error> {
+        let encrypted_regex = if encrypted_regex.is_empty() {
+            None
+        } else {
+            Some(Regex::new(encrypted_regex).map_err(|err| {
+                Error::ConfigError(format!(
+                    "invalid encrypted regex '{}': {}",
+                    encrypted_regex, err
+                ))
+            })?)
+        };
+
+        Ok(SopsEncryptor {
+            recipients: parse_recipients(recipients)?,
+            encrypted_regex,
+        })
+    }
+
+    /// Returns which values are encrypted.
+    fn selection(&self) -> Selection<'_> {
+        match &self.encrypted_regex {
+            Some(regex) => Selection::EncryptedRegex(regex),
+            None => Selection::UnencryptedSuffix,
+        }
+    }
+
+    /// Encrypts the values of one YAML document and adds the `sops` metadata to it. A document
+    /// without values to encrypt is refused, or returned as it is if `partial`, see
+    /// `Encryptor::encrypt`.
+    fn encrypt_document(
+        &self,
+        mut document: Mapping,
+        selection: &Selection,
+        partial: bool,
+    ) -> Result<Mapping, Error> {
+        let sops_key = Value::String("sops".to_string());
+        if document.contains_key(&sops_key) {
+            return Err(Error::EncryptionError(
+                "document is already encrypted".to_string(),
+            ));
+        }
+
+        let mut data_key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut data_key);
+
+        let mut mac = Sha512::new();
+        let mut encrypted = 0;
+        for (key, value) in document.iter_mut() {
+            let mut path = vec![mapping_key(key)?];
+            encrypted += encrypt_tree(value, &mut path, selection, &data_key, &mut mac)?;
+        }
+
+        if encrypted == 0 && partial {
+            return Ok(document);
+        }
+        // a file that is always encrypted mustn't be committed with its values in plaintext
+        // because its keys don't match, eg. a ConfigMap with the default `encrypted_regex`
+        if encrypted == 0 {
+            return Err(Error::EncryptionError(format!(
+                "document {} has no values to encrypt",
+                document_name(&document)
+            )));
+        }
+
+        let lastmodified = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        let mac: String = mac
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+
+        let age = self
+            .recipients
+            .iter()
+            .map(|recipient| {
+                let enc = age_encrypt(std::slice::from_ref(recipient), &data_key)?;
+                Ok(SopsAgeKey {
+                    recipient: recipient.to_string(),
+                    enc: String::from_utf8_lossy(&enc).to_string(),
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        let metadata = SopsMetadata {
+            kms: vec![],
+            gcp_kms: vec![],
+            azure_kv: vec![],
+            hc_vault: vec![],
+            age,
+            mac: encrypt_value(&data_key, &mac, &lastmodified, "str")?,
+            lastmodified,
+            pgp: vec![],
+            encrypted_regex: match selection {
+                Selection::EncryptedRegex(regex) => Some(regex.as_str().to_string()),
+                _ => None,
+            },
+            unencrypted_suffix: match selection {
+                Selection::UnencryptedSuffix => Some(UNENCRYPTED_SUFFIX.to_string()),
+                _ => None,
+            },
+            version: SOPS_VERSION.to_string(),
+        };
+        // the metadata consists of strings and lists only
+        document.insert(sops_key, serde_yaml::to_value(metadata).unwrap());
+
+        Ok(document)
+    }
+}
+
+impl Encryptor for SopsEncryptor {
+    fn encrypt(&self, path: &Path, plaintext: &[u8], partial: bool) -> Result<Vec<u8>, Error> {
+        let selection = self.selection();
+        let is_yaml = matches!(
+            path.extension().and_then(|extension| extension.to_str()),
+            Some("yaml") | Some("yml")
+        );
+        if !is_yaml {
+            return Err(Error::EncryptionError(format!(
+                "{} can't be encrypted with SOPS, only YAML files can",
+                path.display()
+            )));
+        }
+
+        let plaintext = std::str::from_utf8(plaintext)
+            .map_err(|_| Error::EncryptionError(format!("{} is not UTF-8 text", path.display())))?;
+
+        let mut encrypted = String::new();
+        for document in serde_yaml::Deserializer::from_str(plaintext) {
+            let document = match Value::deserialize(document) {
+                Ok(Value::Null) => continue,
+                Ok(Value::Mapping(document)) => document,
+                Ok(_) => {
+                    return Err(Error::EncryptionError(format!(
+                        "{} contains a document that is not a map",
+                        path.display()
+                    )))
+                }
+                Err(err) => {
+                    return Err(Error::EncryptionError(format!(
+                        "{} is not valid YAML: {}",
+                        path.display(),
+                        err
+                    )))
+                }
+            };
+
+            let document = self
+                .encrypt_document(document, &selection, partial)
+                .map_err(|err| {
+                    Error::EncryptionError(format!("could not encrypt {}: {}", path.display(), err))
+                })?;
+            // YAML maps always serialize
+            encrypted.push_str(&serde_yaml::to_string(&document).unwrap());
+        }
+
+        Ok(encrypted.into_bytes())
+    }
+
+    fn readable_values(&self, encrypted: &[u8]) -> Vec<String> {
+        fn collect(value: &Value, readable: &mut Vec<String>) {
+            match value {
+                Value::Mapping(mapping) => mapping.iter().for_each(|(key, value)| {
+                    collect(key, readable);
+                    collect(value, readable);
+                }),
+                Value::Sequence(values) => values.iter().for_each(|value| collect(value, readable)),
+                Value::String(string) if string.starts_with("ENC[AES256_GCM,") => {}
+                Value::String(string) => readable.push(string.clone()),
+                Value::Bool(boolean) => readable.push(boolean.to_string()),
+                Value::Number(number) => readable.push(number.to_string()),
+                Value::Null => {}
+            }
+        }
+
+        let mut readable = Vec::new();
+        let encrypted = String::from_utf8_lossy(encrypted);
+        for document in serde_yaml::Deserializer::from_str(&encrypted) {
+            if let Ok(Value::Mapping(mut document)) = Value::deserialize(document) {
+                document.remove(&Value::String("sops".to_string()));
+                collect(&Value::Mapping(document), &mut readable);
+            }
+        }
+
+        readable
+    }
+}
+
+/// Encrypts the values within `value` in place, adding every value to the `mac` as SOPS does.
+/// Returns how many values were encrypted.
+///
+/// # Arguments
+/// - `value` - The value to encrypt.
+/// - `path` - Keys leading to `value`. List items share the path of their list.
+/// - `selection` - Which values to encrypt.
+/// - `data_key` - Key of the document.
+/// - `mac` - Digest of the values visited so far.
+fn encrypt_tree(
+    value: &mut Value,
+    path: &mut Vec<String>,
+    selection: &Selection,
+    data_key: &[u8],
+    mac: &mut Sha512,
+) -> Result<usize, Error> {
+    let (plaintext, mac_bytes, value_type) = match value {
+        Value::Mapping(mapping) => {
+            let mut encrypted = 0;
+            for (key, value) in mapping.iter_mut() {
+                path.push(mapping_key(key)?);
+                encrypted += encrypt_tree(value, path, selection, data_key, mac)?;
+                path.pop();
+            }
+            return Ok(encrypted);
+        }
+        Value::Sequence(values) => {
+            let mut encrypted = 0;
+            for value in values.iter_mut() {
+                encrypted += encrypt_tree(value, path, selection, data_key, mac)?;
+            }
+            return Ok(encrypted);
+        }
+        // SOPS neither encrypts nor authenticates nulls
+        Value::Null => return Ok(0),
+        Value::String(string) => (string.clone(), string.clone(), "str"),
+        // booleans are authenticated in their Python spelling
+        Value::Bool(boolean) => (
+            boolean.to_string(),
+            if *boolean { "True" } else { "False" }.to_string(),
+            "bool",
+        ),
+        Value::Number(number) => match number.as_f64() {
+            Some(float) if number.is_f64() => (float.to_string(), float.to_string(), "float"),
+            _ => (number.to_string(), number.to_string(), "int"),
+        },
+    };
+
+    mac.update(mac_bytes.as_bytes());
+
+    if is_encrypted(path, selection) && !plaintext.is_empty() {
+        let additional_data = format!("{}:", path.join(":"));
+        *value = Value::String(encrypt_value(
+            data_key,
+            &plaintext,
+            &additional_data,
+            value_type,
+        )?);
+        return Ok(1);
+    }
+
+    Ok(0)
+}
+
+/// Whether a value at `path` is encrypted.
+fn is_encrypted(path: &[String], selection: &Selection) -> bool {
+    match selection {
+        Selection::EncryptedRegex(regex) => path.iter().any(|key| regex.is_match(key)),
+        Selection::UnencryptedSuffix => !path.iter().any(|key| key.ends_with(UNENCRYPTED_SUFFIX)),
+    }
+}
+
+/// Returns the `kind` and `metadata.name` of a document, to name it in errors.
+fn document_name(document: &Mapping) -> String {
+    let value = Value::Mapping(document.clone());
+    format!(
+        "{} {}",
+        value["kind"].as_str().unwrap_or("<no kind>"),
+        value["metadata"]["name"].as_str().unwrap_or("<no name>")
+    )
+}
+
+/// Returns the key of a map entry, which SOPS requires to be a string.
+fn mapping_key(key: &Value) -> Result<String, Error> {
+    match key {
+        Value::String(key) => Ok(key.clone()),
+        _ => Err(Error::EncryptionError(format!(
+            "map key {:?} is not a string",
+            key
+        ))),
+    }
+}
+
+/// Encrypts a value as SOPS does: with AES-256-GCM under the document's data key, authenticated
+/// with `additional_data`, and formatted as `ENC[AES256_GCM,data:...,iv:...,tag:...,type:...]`.
+fn encrypt_value(
+    data_key: &[u8],
+    plaintext: &str,
+    additional_data: &str,
+    value_type: &str,
+) -> Result<String, Error> {
+    let cipher = SopsCipher::new_from_slice(data_key)
+        .map_err(|_| Error::EncryptionError("invalid data key".to_string()))?;
+
+    let mut iv = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut iv);
+
+    let mut data = plaintext.as_bytes().to_vec();
+    let tag = cipher
+        .encrypt_in_place_detached((&iv).into(), additional_data.as_bytes(), &mut data)
+        .map_err(|_| Error::EncryptionError("AES-GCM encryption failed".to_string()))?;
+
+    Ok(format!(
+        "ENC[AES256_GCM,data:{},iv:{},tag:{},type:{}]",
+        base64::encode(&data),
+        base64::encode(iv),
+        base64::encode(tag),
+        value_type
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::{AeadInPlace, NewAead};
+    use age::armor::ArmoredReader;
+    use age::x25519::Identity;
+    use regex::Regex;
+    use secrecy::ExposeSecret;
+    use serde::Deserialize;
+    use serde_yaml::Value;
+    use sha2::{Digest, Sha512};
+    use std::convert::TryInto;
+    use std::io::Read;
+    use std::path::Path;
+    use std::process::Command;
+
+    use super::{SopsCipher, SopsEncryptor, SopsMetadata};
+    use crate::utils::config::DEFAULT_ENCRYPTED_REGEX;
+    use crate::workflows::encryption::Encryptor;
+
+    /// Decrypts a value the way SOPS does.
+    fn decrypt_value(data_key: &[u8], value: &str, additional_data: &str) -> Value {
+        let format =
+            Regex::new(r"^ENC\[AES256_GCM,data:(.*),iv:(.*),tag:(.*),type:(str|int|float|bool)\]$")
+                .unwrap();
+        let captures = format.captures(value).unwrap();
+
+        let mut data = base64::decode(&captures[1]).unwrap();
+        let iv: [u8; 32] = base64::decode(&captures[2]).unwrap().try_into().unwrap();
+        let tag: [u8; 16] = base64::decode(&captures[3]).unwrap().try_into().unwrap();
+
+        SopsCipher::new_from_slice(data_key)
+            .unwrap()
+            .decrypt_in_place_detached(
+                (&iv).into(),
+                additional_data.as_bytes(),
+                &mut data,
+                (&tag).into(),
+            )
+            .unwrap();
+
+        let plaintext = String::from_utf8(data).unwrap();
+        match &captures[4] {
+            "int" => Value::Number(plaintext.parse::<i64>().unwrap().into()),
+            "float" => Value::Number(plaintext.parse::<f64>().unwrap().into()),
+            "bool" => Value::Bool(plaintext.parse().unwrap()),
+            _ => Value::String(plaintext),
+        }
+    }
+
+    /// Bytes SOPS adds to the MAC for a value.
+    fn mac_bytes(value: &Value) -> String {
+        match value {
+            Value::String(string) => string.clone(),
+            Value::Bool(true) => "True".to_string(),
+            Value::Bool(false) => "False".to_string(),
+            Value::Number(number) if number.is_f64() => number.as_f64().unwrap().to_string(),
+            other => serde_yaml::to_string(other).unwrap()[4..]
+                .trim()
+                .to_string(),
+        }
+    }
+
+    /// Decrypts a SOPS document in place and checks its MAC, as `sops --decrypt` would.
+    fn decrypt_document(identity: &Identity, document: &mut Value) {
+        let mapping = document.as_mapping_mut().unwrap();
+        let metadata: SopsMetadata =
+            serde_yaml::from_value(mapping.remove(&Value::String("sops".to_string())).unwrap())
+                .unwrap();
+
+        let decryptor = match age::Decryptor::new(ArmoredReader::new(
+            metadata.age[0].enc.as_bytes(),
+        ))
+        .unwrap()
+        {
+            age::Decryptor::Recipients(decryptor) => decryptor,
+            _ => panic!("not encrypted to recipients"),
+        };
+        let mut data_key = Vec::new();
+        decryptor
+            .decrypt(std::iter::once(identity as &dyn age::Identity))
+            .unwrap()
+            .read_to_end(&mut data_key)
+            .unwrap();
+
+        fn decrypt(value: &mut Value, path: &mut Vec<String>, data_key: &[u8], mac: &mut Sha512) {
+            match value {
+                Value::Mapping(mapping) => {
+                    for (key, value) in mapping.iter_mut() {
+                        path.push(key.as_str().unwrap().to_string());
+                        decrypt(value, path, data_key, mac);
+                        path.pop();
+                    }
+                }
+                Value::String(string) if string.starts_with("ENC[") => {
+                    let additional_data = format!("{}:", path.join(":"));
+                    *value = decrypt_value(data_key, string, &additional_data);
+                    mac.update(mac_bytes(value).as_bytes());
+                }
+                other => mac.update(mac_bytes(other).as_bytes()),
+            }
+        }
+
+        let mut mac = Sha512::new();
+        decrypt(document, &mut vec![], &data_key, &mut mac);
+
+        let mac: String = mac
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        assert_eq!(
+            decrypt_value(&data_key, &metadata.mac, &metadata.lastmodified),
+            Value::String(mac)
+        );
+    }
+
+    #[test]
+    fn encrypts_secret_data_in_sops_format() {
+        let identity = Identity::generate();
+        let encryptor =
+            SopsEncryptor::new(&[identity.to_public().to_string()], DEFAULT_ENCRYPTED_REGEX)
+                .unwrap();
+
+        let manifest = "\
+apiVersion: v1
+kind: Secret
+metadata:
+  name: cluster-agent-db
+stringData:
+  connectionString: Server=db;Password=hunter2
+  port: \"5432\"
+---
+apiVersion: v1
+kind: ConfigMap
+metadata:
+  name: cluster-agent
+data:
+  logLevel: info
+";
+        let encrypted = encryptor
+            .encrypt(Path::new("dev/secret.yaml"), manifest.as_bytes(), false)
+            .unwrap();
+        let encrypted = String::from_utf8(encrypted).unwrap();
+
+        assert!(!encrypted.contains("hunter2"));
+        assert!(!encrypted.contains("info"));
+        assert!(encrypted.contains("name: cluster-agent-db"));
+
+        let originals: Vec<Value> = serde_yaml::Deserializer::from_str(manifest)
+            .map(|document| Value::deserialize(document).unwrap())
+            .collect();
+        let documents: Vec<Value> = serde_yaml::Deserializer::from_str(&encrypted)
+            .map(|document| Value::deserialize(document).unwrap())
+            .collect();
+        assert_eq!(documents.len(), 2);
+
+        for (mut document, original) in documents.into_iter().zip(originals) {
+            assert_eq!(document["sops"]["encrypted_regex"], DEFAULT_ENCRYPTED_REGEX);
+            assert!(document["sops"]["age"][0]["enc"]
+                .as_str()
+                .unwrap()
+                .starts_with("-----BEGIN AGE ENCRYPTED FILE-----"));
+
+            decrypt_document(&identity, &mut document);
+            assert_eq!(document, original);
+        }
+    }
+
+    #[test]
+    fn encrypts_every_value_without_regex() {
+        let identity = Identity::generate();
+        let encryptor = SopsEncryptor::new(&[identity.to_public().to_string()], "").unwrap();
+
+        let manifest = "password: hunter2\nreplicas: 2\nratio: 0.5\ndebug: true\nnote_unencrypted: plain\nempty: \"\"\n";
+        let encrypted = encryptor
+            .encrypt(Path::new("values.yaml"), manifest.as_bytes(), false)
+            .unwrap();
+
+        let mut document: Value = serde_yaml::from_slice(&encrypted).unwrap();
+        assert!(document["replicas"]
+            .as_str()
+            .unwrap()
+            .ends_with("type:int]"));
+        assert_eq!(document["note_unencrypted"], "plain");
+        assert_eq!(document["sops"]["unencrypted_suffix"], "_unencrypted");
+
+        decrypt_document(&identity, &mut document);
+        assert_eq!(document, serde_yaml::from_str::<Value>(manifest).unwrap());
+
+        assert!(encryptor
+            .encrypt(Path::new("values.json"), b"{}", false)
+            .is_err());
+    }
+
+    #[test]
+    fn refuses_documents_without_values_to_encrypt() {
+        let identity = Identity::generate();
+        let encryptor =
+            SopsEncryptor::new(&[identity.to_public().to_string()], DEFAULT_ENCRYPTED_REGEX)
+                .unwrap();
+
+        let manifest = "apiVersion: v1\nkind: ConfigMap\nmetadata:\n  name: cluster-agent\nspec:\n  password: hunter2\n";
+        let err = encryptor
+            .encrypt(Path::new("secret.yaml"), manifest.as_bytes(), false)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("ConfigMap cluster-agent has no values to encrypt"));
+
+        // unless they may be left in plaintext
+        let namespace = "apiVersion: v1\nkind: Namespace\nmetadata:\n  name: cluster-agent\n";
+        let manifest = format!(
+            "{}---\napiVersion: v1\nkind: Secret\nmetadata:\n  name: db\ndata:\n  password: aHVudGVyMg==\n",
+            namespace
+        );
+        let encrypted = encryptor
+            .encrypt(Path::new("secret.yaml"), manifest.as_bytes(), true)
+            .unwrap();
+        let documents: Vec<Value> =
+            serde_yaml::Deserializer::from_str(&String::from_utf8(encrypted).unwrap())
+                .map(|document| Value::deserialize(document).unwrap())
+                .collect();
+        assert_eq!(
+            documents[0],
+            serde_yaml::from_str::<Value>(namespace).unwrap()
+        );
+        assert!(documents[1]["data"]["password"]
+            .as_str()
+            .unwrap()
+            .starts_with("ENC["));
+    }
+
+    /// Checks that `sops` itself decrypts the documents of an encrypted file, one at a time as
+    /// Flux does. Needs `sops` 3.7 or later on the `PATH`, and is skipped without it.
+    #[test]
+    fn sops_decrypts_documents() {
+        if Command::new("sops").arg("--version").output().is_err() {
+            eprintln!("skipping sops_decrypts_documents: sops is not on the PATH");
+            return;
+        }
+
+        let identity = Identity::generate();
+        let encryptor =
+            SopsEncryptor::new(&[identity.to_public().to_string()], DEFAULT_ENCRYPTED_REGEX)
+                .unwrap();
+
+        let manifest = "\
+apiVersion: v1
+kind: Secret
+metadata:
+  name: cluster-agent-db
+stringData:
+  connectionString: Server=db;Password=hunter2
+  port: \"5432\"
+---
+apiVersion: v1
+kind: ConfigMap
+metadata:
+  name: cluster-agent
+data:
+  replicas: 2
+  ratio: 0.5
+  debug: true
+";
+        let originals: Vec<Value> = serde_yaml::Deserializer::from_str(manifest)
+            .map(|document| Value::deserialize(document).unwrap())
+            .collect();
+        let dir = tempfile::tempdir().unwrap();
+
+        for partial in [false, true] {
+            let encrypted = encryptor
+                .encrypt(Path::new("secret.yaml"), manifest.as_bytes(), partial)
+                .unwrap();
+            let encrypted = String::from_utf8(encrypted).unwrap();
+
+            for (index, document) in serde_yaml::Deserializer::from_str(&encrypted).enumerate() {
+                let document = Value::deserialize(document).unwrap();
+                let path = dir.path().join(format!("{}.yaml", index));
+                std::fs::write(&path, serde_yaml::to_string(&document).unwrap()).unwrap();
+
+                let output = Command::new("sops")
+                    .args(["--decrypt", "--input-type", "yaml", "--output-type", "yaml"])
+                    .arg(&path)
+                    .env("SOPS_AGE_KEY", identity.to_string().expose_secret())
+                    .output()
+                    .unwrap();
+                assert!(
+                    output.status.success(),
+                    "{}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+
+                let decrypted: Value = serde_yaml::from_slice(&output.stdout).unwrap();
+                assert_eq!(decrypted, originals[index]);
+            }
+        }
+    }
+}
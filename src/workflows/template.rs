@@ -0,0 +1,966 @@
+use git2::build::CheckoutBuilder;
+use git2::{Oid, Repository};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use handlebars::Handlebars;
+use serde_json::{json, Map, Value};
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+
+use crate::models::application::Application;
+use crate::models::assignment::ApplicationAssignment;
+use crate::models::cluster::Cluster;
+use crate::models::environment::ApplicationEnvironment;
+use crate::models::template::ApplicationTemplate;
+use crate::models::values::{ValuesFromKind, ValuesFromSource};
+use crate::utils::error::Error;
+use crate::workflows::cache::{fetch_options, RepoCache};
+use crate::workflows::helpers::{registry, undefined_variables, PARTIALS_DIRECTORY};
+use crate::workflows::manifest::{apply_template_manifest, TEMPLATE_MANIFEST_FILE};
+
+/// Runs `f` with the template repo of `template` checked out at the template's `reference`,
+/// passing it the directory of the template within the repo and the commit it resolved to.
+///
+/// # Arguments
+/// - `cache` - Working copies to check the template repo out in.
+/// - `template` - The template to check out.
+/// - `f` - Function to run with the checked out template.
+pub fn with_template<T, F>(
+    cache: &RepoCache,
+    template: &ApplicationTemplate,
+    f: F,
+) -> Result<T, Error>
+where
+    F: FnOnce(&Path, Oid) -> Result<T, Error>,
+{
+    let template_key = template.spec.reference.clone().unwrap_or_default();
+
+    cache.with_repo(
+        &template.spec.repo,
+        &format!("template {}", template_key),
+        fetch_options(),
+        |template_repo| {
+            let template_commit = checkout_reference(template_repo, template)?;
+
+            let template_path = template_repo.workdir().unwrap().join(&template.spec.path);
+
+            f(&template_path, template_commit)
+        },
+    )
+}
+
+/// Checks out the template repo at the branch, tag or commit given by the template's
+/// `reference`, or at the remote's default branch if there is none.
+/// Returns the commit the template was resolved to.
+pub fn checkout_reference(repo: &Repository, template: &ApplicationTemplate) -> Result<Oid, Error> {
+    let reference = match template.spec.reference.as_deref() {
+        Some(reference) if !reference.is_empty() => reference,
+        _ => "refs/remotes/origin/HEAD",
+    };
+
+    // branches only exist as remote tracking refs in a working copy, so try those first, then
+    // tags, and finally anything else git can parse, such as a (possibly abbreviated) commit SHA.
+    // A full branch ref names the remote tracking ref too: the clone's local branch is stale.
+    let branch = reference.strip_prefix("refs/heads/").unwrap_or(reference);
+    let candidates = [
+        format!("refs/remotes/origin/{}", branch),
+        format!("refs/tags/{}", reference),
+        reference.to_string(),
+    ];
+
+    let commit = candidates
+        .iter()
+        .find_map(|candidate| repo.revparse_single(candidate).ok())
+        .map(|object| object.peel_to_commit())
+        .transpose()?;
+
+    let commit = match commit {
+        Some(commit) => commit,
+        None => {
+            return Err(Error::UserInputError(format!(
+                "reference '{}' not found in template repo {}",
+                reference, template.spec.repo
+            )))
+        }
+    };
+
+    repo.checkout_tree(
+        commit.as_object(),
+        Some(CheckoutBuilder::new().force().remove_untracked(true)),
+    )?;
+    repo.set_head_detached(commit.id())?;
+
+    Ok(commit.id())
+}
+
+/// Key of a `valuesFrom` ConfigMap or Secret the values are taken from if it names none.
+pub const DEFAULT_VALUES_KEY: &str = "values.yaml";
+
+/// Values taken from the ConfigMaps and Secrets in the `valuesFrom` of an application, its
+/// environment and its assignment, resolved by the controller.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ResolvedValues {
+    pub application: Map<String, Value>,
+    pub environment: Map<String, Value>,
+    pub assignment: Map<String, Value>,
+    /// Strings, numbers and booleans taken from Secrets. Rendered manifests that carry them are
+    /// only committed encrypted, see `Encryption::encrypt_rendered`.
+    pub secrets: Vec<Value>,
+}
+
+/// Builds the values a template is rendered with for an assignment: the values of its
+/// application, environment and assignment, each taking precedence over the ones before (see
+/// `merge_values`), deep-merged over the facts of its cluster. At each level, `values` take
+/// precedence over those resolved from `valuesFrom`. Only the values of the application,
+/// environment and assignment are prepared according to the template's `template.yaml` (see
+/// `apply_template_manifest`). Fails if the assignment's `Cluster` resource doesn't exist.
+pub fn template_values(
+    application: &Application,
+    environment: &ApplicationEnvironment,
+    assignment: &ApplicationAssignment,
+    cluster: Option<&Cluster>,
+    resolved: &ResolvedValues,
+    template_path: &Path,
+) -> Result<Value, Error> {
+    let cluster = cluster.ok_or_else(|| {
+        Error::UserInputError(format!(
+            "Cluster {} of ApplicationAssignment {} does not exist",
+            assignment.spec.cluster,
+            assignment.metadata.name.as_deref().unwrap_or_default()
+        ))
+    })?;
+
+    // merge values from Application, ApplicationEnvironment and ApplicationAssignment
+    let mut user_values = Map::new();
+    let values = [
+        (&resolved.application, &application.spec.values),
+        (&resolved.environment, &environment.spec.values),
+        (&resolved.assignment, &assignment.spec.values),
+    ];
+    for (resolved, values) in values.iter() {
+        merge_values(&mut user_values, resolved);
+        if let Some(values) = values {
+            merge_values(&mut user_values, values);
+        }
+    }
+
+    let mut user_values = Value::Object(user_values);
+    apply_template_manifest(template_path, &mut user_values)?;
+
+    let mut template_values = cluster_values(&assignment.spec.cluster, cluster);
+    if let Value::Object(user_values) = &user_values {
+        merge_values(&mut template_values, user_values);
+    }
+
+    Ok(Value::Object(template_values))
+}
+
+/// Deep-merges `overrides` into `values`. Maps present in both are merged key by key, anything
+/// else in `overrides`, lists included, replaces what is in `values`, and an explicit `null`
+/// removes the key.
+pub fn merge_values(values: &mut Map<String, Value>, overrides: &Map<String, Value>) {
+    for (key, value) in overrides.iter() {
+        match (values.get_mut(key), value) {
+            (_, Value::Null) => {
+                values.remove(key);
+            }
+            (Some(Value::Object(values)), Value::Object(overrides)) => {
+                merge_values(values, overrides);
+            }
+            (_, Value::Object(overrides)) => {
+                // nulls in new maps are dropped as well
+                let mut merged = Map::new();
+                merge_values(&mut merged, overrides);
+                values.insert(key.clone(), Value::Object(merged));
+            }
+            _ => {
+                values.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Merges the data of a `valuesFrom` source into `values`: as a string at its `targetPath` if it
+/// has one, otherwise parsed as a YAML map and deep-merged. Values taken from a Secret are added
+/// to `secrets`.
+///
+/// # Arguments
+/// - `values` - Values resolved so far.
+/// - `source` - The `valuesFrom` reference the data was taken from.
+/// - `data` - Data under the source's `valuesKey`.
+/// - `secrets` - Values taken from Secrets so far.
+pub fn add_values_from(
+    values: &mut Map<String, Value>,
+    source: &ValuesFromSource,
+    data: &str,
+    secrets: &mut Vec<Value>,
+) -> Result<(), Error> {
+    let key = source.values_key.as_deref().unwrap_or(DEFAULT_VALUES_KEY);
+
+    let source_values = match &source.target_path {
+        Some(target_path) => {
+            if target_path.split('.').any(str::is_empty) {
+                return Err(Error::UserInputError(format!(
+                    "targetPath '{}' of {:?} {} is not a dotted path",
+                    target_path, source.kind, source.name
+                )));
+            }
+
+            let mut value = Value::String(data.to_string());
+            for segment in target_path.rsplit('.') {
+                let mut map = Map::new();
+                map.insert(segment.to_string(), value);
+                value = Value::Object(map);
+            }
+            value
+        }
+        None => {
+            let parsed: Map<String, Value> = serde_yaml::from_str(data).map_err(|err| {
+                Error::UserInputError(format!(
+                    "key {} of {:?} {} is not a YAML map of values: {}",
+                    key, source.kind, source.name, err
+                ))
+            })?;
+            Value::Object(parsed)
+        }
+    };
+
+    if source.kind == ValuesFromKind::Secret {
+        collect_scalars(&source_values, secrets);
+    }
+    if let Value::Object(source_values) = source_values {
+        merge_values(values, &source_values);
+    }
+
+    Ok(())
+}
+
+/// Adds the strings, numbers and booleans within `value` to `scalars`.
+fn collect_scalars(value: &Value, scalars: &mut Vec<Value>) {
+    match value {
+        Value::Array(values) => values
+            .iter()
+            .for_each(|value| collect_scalars(value, scalars)),
+        Value::Object(values) => values
+            .values()
+            .for_each(|value| collect_scalars(value, scalars)),
+        Value::Null => {}
+        scalar => scalars.push(scalar.clone()),
+    }
+}
+
+/// Template values describing a cluster:
+/// - `clusterName` - Name of the `Cluster` resource.
+/// - `cluster` - Its `name`, `labels` and the facts `cloud`, `region`, `zone` and
+///   `kubernetesVersion` that are known.
+/// - `cloud` and `cloudRegion` - Shorthands for `cluster.cloud` and `cluster.region`.
+fn cluster_values(cluster_name: &str, cluster: &Cluster) -> Map<String, Value> {
+    let facts = cluster.spec.facts.clone().unwrap_or_default();
+    let labels = &cluster.spec.labels;
+    let fact = |fact: Option<String>, label: &str| fact.or_else(|| labels.get(label).cloned());
+
+    let mut cluster_values = Map::new();
+    cluster_values.insert("name".to_string(), json!(cluster_name));
+    cluster_values.insert("labels".to_string(), json!(labels));
+
+    let facts = [
+        ("cloud", fact(facts.cloud, "cloud")),
+        ("region", fact(facts.region, "region")),
+        ("zone", fact(facts.zone, "zone")),
+        ("kubernetesVersion", facts.kubernetes_version),
+    ];
+    for (name, value) in facts.iter() {
+        if let Some(value) = value {
+            cluster_values.insert(name.to_string(), json!(value));
+        }
+    }
+
+    let mut values = Map::new();
+    values.insert("clusterName".to_string(), json!(cluster_name));
+    for (shorthand, name) in [("cloud", "cloud"), ("cloudRegion", "region")].iter() {
+        if let Some(value) = cluster_values.get(*name) {
+            values.insert(shorthand.to_string(), value.clone());
+        }
+    }
+    values.insert("cluster".to_string(), Value::Object(cluster_values));
+
+    values
+}
+
+/// File at the root of a template listing files that are copied as is rather than rendered, one
+/// glob per line as in a `.gitignore`: globs without a `/` match at any depth, and globs matching
+/// a directory match everything in it. Lines starting with `#` are comments.
+pub const TEMPLATE_IGNORE_FILE: &str = ".templateignore";
+
+/// A file of a template and where it is rendered to.
+struct TemplateFile {
+    /// Path of the file within the checked out template repo.
+    source: PathBuf,
+    /// Name the file is registered under as a template, unless it is copied as is.
+    name: Option<String>,
+    /// Where the file is rendered to, relative to the repo root.
+    output: PathBuf,
+}
+
+/// Renders every file under `template_path` with `values` into `root_relative_path` under
+/// `repo_root_path`, skipping dotted directories, the `_partials` directory and the template's
+/// `template.yaml`. Returns the rendered paths, relative to `repo_root_path`. See
+/// `helpers::registry` for the helpers and partials available to templates.
+///
+/// Binary files, ie. files that are not UTF-8 text or contain NUL bytes, and files matching the
+/// template's `.templateignore` are copied byte for byte instead of rendered. Rendered files
+/// keep the mode of their template, so that executable files are staged as such.
+///
+/// In `strict` mode, undefined variables fail the render rather than rendering empty. Every
+/// file is checked before any is rendered, so that the error lists all undefined variables, with
+/// the file and line referencing them.
+pub fn render(
+    template_path: &Path,
+    repo_root_path: &Path,
+    root_relative_path: &Path,
+    values: &Value,
+    strict: bool,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut handlebars = registry(template_path, strict)?;
+    let ignored = template_ignore(template_path)?;
+
+    let mut files = Vec::new();
+    register_directory(
+        &mut handlebars,
+        &ignored,
+        template_path,
+        Path::new(""),
+        repo_root_path,
+        root_relative_path,
+        &mut files,
+    )?;
+
+    if strict {
+        let undefined: Vec<String> = files
+            .iter()
+            .filter_map(|file| file.name.as_ref())
+            .flat_map(|name| undefined_variables(&handlebars, name, values))
+            .map(|variable| variable.to_string())
+            .collect();
+        if !undefined.is_empty() {
+            return Err(Error::RenderError {
+                source: handlebars::RenderError::new(format!(
+                    "undefined variables in strict mode: {}",
+                    undefined.join(", ")
+                )),
+            });
+        }
+    }
+
+    let mut paths = Vec::new();
+    for file in files {
+        let output_path = repo_root_path.join(&file.output);
+
+        match &file.name {
+            Some(name) => {
+                let rendered_file = match handlebars.render(name, values) {
+                    Ok(rendered_file) => rendered_file,
+                    Err(err) => return Err(Error::RenderError { source: err }),
+                };
+
+                std::fs::write(&output_path, rendered_file.as_bytes())?;
+                std::fs::set_permissions(
+                    &output_path,
+                    std::fs::metadata(&file.source)?.permissions(),
+                )?;
+            }
+            // copies permissions as well
+            None => {
+                std::fs::copy(&file.source, &output_path)?;
+            }
+        }
+
+        paths.push(file.output);
+    }
+
+    Ok(paths)
+}
+
+/// Reads the globs of the template's `.templateignore`, if it has one.
+fn template_ignore(template_path: &Path) -> Result<GlobSet, Error> {
+    let ignore_path = template_path.join(TEMPLATE_IGNORE_FILE);
+    if !ignore_path.is_file() {
+        return Ok(GlobSet::empty());
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for line in std::fs::read_to_string(ignore_path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let pattern = line.trim_end_matches('/');
+        let pattern = match pattern.strip_prefix('/') {
+            Some(anchored) => anchored.to_string(),
+            None if pattern.contains('/') => pattern.to_string(),
+            None => format!("**/{}", pattern),
+        };
+
+        for pattern in [pattern.clone(), format!("{}/**", pattern)] {
+            let glob = Glob::new(&pattern).map_err(|err| {
+                Error::UserInputError(format!(
+                    "invalid pattern '{}' in {}: {}",
+                    line, TEMPLATE_IGNORE_FILE, err
+                ))
+            })?;
+            builder.add(glob);
+        }
+    }
+
+    builder
+        .build()
+        .map_err(|err| Error::UserInputError(format!("invalid {}: {}", TEMPLATE_IGNORE_FILE, err)))
+}
+
+/// Registers the text files of one directory of a template by their path, and lists every file
+/// with the path it is rendered to, creating the directories it is rendered to. See `render`.
+///
+/// # Arguments
+/// - `handlebars` - Registry of the template.
+/// - `ignored` - Files that are copied as is, relative to `template_path`.
+/// - `template_path` - Root directory of the template.
+/// - `template_relative_path` - The directory to register, relative to `template_path`.
+/// - `repo_root_path`, `root_relative_path` - Where to render the directory to.
+/// - `files` - The files of the template so far.
+fn register_directory(
+    handlebars: &mut Handlebars,
+    ignored: &GlobSet,
+    template_path: &Path,
+    template_relative_path: &Path,
+    repo_root_path: &Path,
+    root_relative_path: &Path,
+    files: &mut Vec<TemplateFile>,
+) -> Result<(), Error> {
+    let output_path = repo_root_path.join(root_relative_path);
+    create_dir_all(&output_path)?;
+
+    let entries = std::fs::read_dir(template_path.join(template_relative_path))?;
+
+    for entry_result in entries {
+        let entry = entry_result?;
+        let file_type = entry.file_type()?;
+        let is_dotted_file_name = entry.file_name().to_str().unwrap().starts_with('.');
+        let is_root = template_relative_path.as_os_str().is_empty();
+        let is_partials = is_root && entry.file_name() == PARTIALS_DIRECTORY;
+        let is_template_ignore = is_root && entry.file_name() == TEMPLATE_IGNORE_FILE;
+        let is_template_manifest = is_root && entry.file_name() == TEMPLATE_MANIFEST_FILE;
+
+        let entry_template_path = entry.path();
+        let entry_template_relative_path = template_relative_path.join(entry.file_name());
+
+        let output_relative_path = Path::new(root_relative_path).join(entry.file_name());
+
+        if file_type.is_dir() {
+            if !is_dotted_file_name && !is_partials {
+                register_directory(
+                    handlebars,
+                    ignored,
+                    template_path,
+                    &entry_template_relative_path,
+                    repo_root_path,
+                    &output_relative_path,
+                    files,
+                )?;
+            }
+        } else if !is_template_ignore && !is_template_manifest {
+            let contents = std::fs::read(&entry_template_path)?;
+            let text = match String::from_utf8(contents) {
+                Ok(text) if !text.contains('\0') => Some(text),
+                _ => None,
+            };
+
+            let name = match text {
+                Some(text) if !ignored.is_match(&entry_template_relative_path) => {
+                    // registered by path, so that errors name the file
+                    let name = entry_template_relative_path.to_string_lossy().to_string();
+                    handlebars
+                        .register_template_string(&name, text)
+                        .map_err(|err| Error::RenderError { source: err.into() })?;
+                    Some(name)
+                }
+                _ => None,
+            };
+
+            files.push(TemplateFile {
+                source: entry_template_path,
+                name,
+                output: output_relative_path,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use git2::{FetchOptions, Repository};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    use crate::models::application::{Application, ApplicationSpec};
+    use crate::models::assignment::{ApplicationAssignment, ApplicationAssignmentSpec};
+    use crate::models::cluster::{Cluster, ClusterFacts, ClusterSpec};
+    use crate::models::environment::{ApplicationEnvironment, ApplicationEnvironmentSpec};
+    use crate::models::template::{ApplicationTemplate, ApplicationTemplateSpec};
+    use crate::models::values::{ValuesFromKind, ValuesFromSource};
+    use crate::workflows::cache::RepoCache;
+    use crate::workflows::testing::commit_file;
+
+    use super::{
+        add_values_from, checkout_reference, merge_values, render, template_values, ResolvedValues,
+    };
+
+    #[test]
+    fn can_render_application() {
+        let values = json!({ "CLUSTER_NAME": "my-cluster" });
+
+        let template_path = Path::new("./fixtures/template");
+        let repo_root_path = Path::new("./fixtures/");
+        let root_relative_path = Path::new("applications/my-cluster");
+        let output_path = repo_root_path.join(root_relative_path);
+
+        std::fs::create_dir_all(output_path).unwrap();
+
+        let paths = render(
+            template_path,
+            repo_root_path,
+            root_relative_path,
+            &values,
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(paths.len(), 2);
+    }
+
+    #[test]
+    fn strict_render_reports_every_undefined_variable() {
+        let template_dir = tempdir().unwrap();
+        std::fs::create_dir_all(template_dir.path().join("dev")).unwrap();
+        std::fs::write(
+            template_dir.path().join("dev/deployment.yaml"),
+            "name: {{CLUSTER_NAM}}\nregion: {{cloudRegion}}",
+        )
+        .unwrap();
+        std::fs::write(
+            template_dir.path().join("namespace.yaml"),
+            "name: {{namespace}}",
+        )
+        .unwrap();
+
+        let output_dir = tempdir().unwrap();
+        let values = json!({ "CLUSTER_NAME": "my-cluster" });
+        let render = |strict| {
+            render(
+                template_dir.path(),
+                output_dir.path(),
+                Path::new("my-cluster"),
+                &values,
+                strict,
+            )
+        };
+
+        let err = render(true).unwrap_err().to_string();
+        for undefined in [
+            "dev/deployment.yaml:1: CLUSTER_NAM",
+            "dev/deployment.yaml:2: cloudRegion",
+            "namespace.yaml:1: namespace",
+        ] {
+            assert!(err.contains(undefined), "{} not in {}", undefined, err);
+        }
+        // nothing is rendered unless every variable is defined
+        assert!(!output_dir.path().join("my-cluster/namespace.yaml").exists());
+
+        assert_eq!(render(false).unwrap().len(), 2);
+        assert_eq!(
+            std::fs::read_to_string(output_dir.path().join("my-cluster/namespace.yaml")).unwrap(),
+            "name: "
+        );
+    }
+
+    #[test]
+    fn copies_binary_and_ignored_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let template_dir = tempdir().unwrap();
+        let template_path = template_dir.path();
+        std::fs::create_dir_all(template_path.join("crds")).unwrap();
+        std::fs::create_dir_all(template_path.join("bin")).unwrap();
+        std::fs::write(
+            template_path.join(".templateignore"),
+            "# not templates\ncrds/\n*.tpl\n",
+        )
+        .unwrap();
+        std::fs::write(template_path.join("crds/crd.yaml"), "pattern: '{{x}}'").unwrap();
+        std::fs::write(template_path.join("bin/notes.tpl"), "{{x}}").unwrap();
+        std::fs::write(template_path.join("bin/run.sh"), "echo {{name}}").unwrap();
+        std::fs::set_permissions(
+            template_path.join("bin/run.sh"),
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        let binary = [0x89, b'P', b'N', b'G', 0x00, 0xff, 0xfe];
+        std::fs::write(template_path.join("logo.png"), binary).unwrap();
+
+        let repo_dir = tempdir().unwrap();
+        let repo = Repository::init(repo_dir.path()).unwrap();
+        let mut paths = render(
+            template_path,
+            repo_dir.path(),
+            Path::new("my-cluster"),
+            &json!({ "name": "my-cluster" }),
+            true,
+        )
+        .unwrap();
+        paths.sort();
+
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("my-cluster/bin/notes.tpl"),
+                Path::new("my-cluster/bin/run.sh"),
+                Path::new("my-cluster/crds/crd.yaml"),
+                Path::new("my-cluster/logo.png"),
+            ]
+        );
+
+        let output = |path: &str| std::fs::read(repo_dir.path().join(path)).unwrap();
+        assert_eq!(output("my-cluster/bin/run.sh"), b"echo my-cluster");
+        assert_eq!(output("my-cluster/bin/notes.tpl"), b"{{x}}");
+        assert_eq!(output("my-cluster/crds/crd.yaml"), b"pattern: '{{x}}'");
+        assert_eq!(output("my-cluster/logo.png"), binary);
+
+        // the executable bit makes it into the index
+        let mut index = repo.index().unwrap();
+        for path in paths.iter() {
+            index.add_path(path).unwrap();
+        }
+        let mode = |path: &str| index.get_path(Path::new(path), 0).unwrap().mode;
+        assert_eq!(mode("my-cluster/bin/run.sh"), 0o100755);
+        assert_eq!(mode("my-cluster/logo.png"), 0o100644);
+    }
+
+    #[test]
+    fn checks_out_template_reference() {
+        let cache_dir = tempdir().unwrap();
+        let cache = RepoCache::new(cache_dir.path().join("repos"), 8).unwrap();
+
+        let origin_dir = tempdir().unwrap();
+        let origin = Repository::init(origin_dir.path()).unwrap();
+        let first = commit_file(&origin, "templates/release.yaml", "version: 1");
+        origin
+            .tag_lightweight("v1", &origin.find_object(first, None).unwrap(), false)
+            .unwrap();
+        let second = commit_file(&origin, "templates/release.yaml", "version: 2");
+
+        let mut template = ApplicationTemplate::new(
+            "template",
+            ApplicationTemplateSpec {
+                repo: origin_dir.path().to_str().unwrap().to_string(),
+                reference: None,
+                path: "templates".to_string(),
+                strict: None,
+            },
+        );
+
+        let expectations = [
+            (None, second, "version: 2"),
+            (Some("v1".to_string()), first, "version: 1"),
+            (Some(first.to_string()), first, "version: 1"),
+        ];
+
+        for (reference, commit, contents) in expectations {
+            template.spec.reference = reference;
+
+            // the same working copy is reused for every reference
+            let (resolved, rendered) = cache
+                .with_repo(
+                    &template.spec.repo,
+                    "template",
+                    FetchOptions::new(),
+                    |repo| {
+                        let resolved = checkout_reference(repo, &template)?;
+                        let path = repo.workdir().unwrap().join("templates/release.yaml");
+                        Ok((resolved, std::fs::read_to_string(path)?))
+                    },
+                )
+                .unwrap();
+
+            assert_eq!(resolved, commit);
+            assert_eq!(rendered, contents);
+        }
+
+        // new commits on the default branch are picked up by the cached working copy
+        let third = commit_file(&origin, "templates/release.yaml", "version: 3");
+        template.spec.reference = None;
+        let resolved = cache
+            .with_repo(
+                &template.spec.repo,
+                "template",
+                FetchOptions::new(),
+                |repo| checkout_reference(repo, &template),
+            )
+            .unwrap();
+        assert_eq!(resolved, third);
+
+        // and so are new commits on branches, whether named by their full ref or not
+        let branch = origin.head().unwrap().shorthand().unwrap().to_string();
+        for reference in [format!("refs/heads/{}", branch), branch] {
+            template.spec.reference = Some(reference);
+            let checked_out = |cache: &RepoCache| {
+                cache
+                    .with_repo(
+                        &template.spec.repo,
+                        "template",
+                        FetchOptions::new(),
+                        |repo| checkout_reference(repo, &template),
+                    )
+                    .unwrap()
+            };
+
+            checked_out(&cache);
+            let moved = commit_file(&origin, "templates/release.yaml", "version: moved");
+            assert_eq!(checked_out(&cache), moved);
+        }
+
+        template.spec.reference = Some("does-not-exist".to_string());
+        assert!(cache
+            .with_repo(
+                &template.spec.repo,
+                "template",
+                FetchOptions::new(),
+                |repo| { checkout_reference(repo, &template) }
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn exposes_cluster_facts_as_values() {
+        let values = json!({ "cloud": "aws", "ring": "main" });
+
+        let application = Application::new(
+            "cluster-agent",
+            ApplicationSpec {
+                template: "cluster-agent".to_string(),
+                values: values.as_object().cloned(),
+                values_from: None,
+                reconciler: None,
+                flux: None,
+                argocd: None,
+            },
+        );
+        let environment = ApplicationEnvironment::new(
+            "cluster-agent-dev",
+            ApplicationEnvironmentSpec {
+                application: "cluster-agent".to_string(),
+                environment: "dev".to_string(),
+                selector: None,
+                clusters: None,
+                spread_by: None,
+                values: None,
+                values_from: None,
+                flux: None,
+                argocd: None,
+            },
+        );
+        let mut assignment = ApplicationAssignment::new(
+            "azure-eastus2-1-cluster-agent-dev",
+            ApplicationAssignmentSpec {
+                cluster: "azure-eastus2-1".to_string(),
+                environment: "cluster-agent-dev".to_string(),
+                values: None,
+                values_from: None,
+            },
+        );
+
+        let mut labels = HashMap::new();
+        labels.insert("cloud".to_string(), "azure".to_string());
+        labels.insert("region".to_string(), "eastus2".to_string());
+        let cluster = Cluster::new(
+            "azure-eastus2-1",
+            ClusterSpec {
+                name: "azure-eastus2-1".to_string(),
+                labels,
+                environments: vec![],
+                facts: Some(ClusterFacts {
+                    zone: Some("2".to_string()),
+                    kubernetes_version: Some("1.21.2".to_string()),
+                    ..ClusterFacts::default()
+                }),
+                gitops: None,
+                kubeconfig: None,
+            },
+        );
+
+        // the schema only covers the values of the application, environment and assignment
+        let template_dir = tempdir().unwrap();
+        std::fs::write(
+            template_dir.path().join("template.yaml"),
+            "\
+schema:
+  type: object
+  additionalProperties: false
+  properties:
+    cloud:
+      enum: [aws, azure]
+    ring:
+      type: string
+",
+        )
+        .unwrap();
+
+        let values = template_values(
+            &application,
+            &environment,
+            &assignment,
+            Some(&cluster),
+            &ResolvedValues::default(),
+            template_dir.path(),
+        )
+        .unwrap();
+
+        assert_eq!(values["clusterName"], "azure-eastus2-1");
+        assert_eq!(values["cloudRegion"], "eastus2");
+        assert_eq!(values["cluster"]["cloud"], "azure");
+        assert_eq!(values["cluster"]["zone"], "2");
+        assert_eq!(values["cluster"]["kubernetesVersion"], "1.21.2");
+        assert_eq!(values["cluster"]["labels"]["region"], "eastus2");
+        // values of the application take precedence over the cluster's
+        assert_eq!(values["cloud"], "aws");
+        assert_eq!(values["ring"], "main");
+
+        let mut resolved = ResolvedValues::default();
+        resolved
+            .assignment
+            .insert("cloud".to_string(), json!("gcp"));
+        let err = template_values(
+            &application,
+            &environment,
+            &assignment,
+            Some(&cluster),
+            &resolved,
+            template_dir.path(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("cloud: \"gcp\" is not one of"));
+
+        assignment.spec.cluster = "missing".to_string();
+        assert!(template_values(
+            &application,
+            &environment,
+            &assignment,
+            None,
+            &ResolvedValues::default(),
+            template_dir.path(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn deep_merges_values() {
+        let application = json!({
+            "replicas": 1,
+            "image": { "repository": "cluster-agent", "tag": "1.0" },
+            "env": [{ "name": "LOG_LEVEL", "value": "info" }],
+            "debug": true,
+        });
+        let environment = json!({
+            "replicas": 3,
+            "image": { "tag": "1.1" },
+            "debug": null,
+        });
+        let assignment = json!({
+            "env": [{ "name": "LOG_LEVEL", "value": "debug" }],
+            "resources": { "limits": { "cpu": "500m", "memory": null } },
+        });
+
+        let mut values = application.as_object().cloned().unwrap();
+        merge_values(&mut values, environment.as_object().unwrap());
+        merge_values(&mut values, assignment.as_object().unwrap());
+
+        assert_eq!(
+            serde_json::Value::Object(values),
+            json!({
+                "replicas": 3,
+                "image": { "repository": "cluster-agent", "tag": "1.1" },
+                "env": [{ "name": "LOG_LEVEL", "value": "debug" }],
+                "resources": { "limits": { "cpu": "500m" } },
+            })
+        );
+    }
+
+    #[test]
+    fn adds_values_from_config_maps_and_secrets() {
+        let config_map = ValuesFromSource {
+            kind: ValuesFromKind::ConfigMap,
+            name: "cluster-agent".to_string(),
+            values_key: None,
+            target_path: None,
+            optional: false,
+        };
+        let secret = ValuesFromSource {
+            kind: ValuesFromKind::Secret,
+            name: "cluster-agent-db".to_string(),
+            values_key: Some("connectionString".to_string()),
+            target_path: Some("database.connectionString".to_string()),
+            optional: false,
+        };
+
+        let mut values = json!({ "database": { "name": "agent" } })
+            .as_object()
+            .cloned()
+            .unwrap();
+        let mut secrets = Vec::new();
+
+        add_values_from(
+            &mut values,
+            &config_map,
+            "replicas: 2\nimage:\n  tag: \"1.2\"\n",
+            &mut secrets,
+        )
+        .unwrap();
+        add_values_from(
+            &mut values,
+            &secret,
+            "Server=db;Password=hunter2",
+            &mut secrets,
+        )
+        .unwrap();
+
+        assert_eq!(
+            serde_json::Value::Object(values.clone()),
+            json!({
+                "replicas": 2,
+                "image": { "tag": "1.2" },
+                "database": { "name": "agent", "connectionString": "Server=db;Password=hunter2" },
+            })
+        );
+        // only values taken from the Secret are sensitive
+        assert_eq!(secrets, vec![json!("Server=db;Password=hunter2")]);
+
+        let mut secrets = Vec::new();
+        let secret = ValuesFromSource {
+            values_key: None,
+            target_path: None,
+            ..secret
+        };
+        add_values_from(
+            &mut values,
+            &secret,
+            "database:\n  port: 5432\n  tls: true\n  users: [admin]\n  host: ~\n",
+            &mut secrets,
+        )
+        .unwrap();
+        assert_eq!(secrets, vec![json!(5432), json!(true), json!("admin")]);
+
+        assert!(add_values_from(&mut values, &config_map, "- not a map", &mut secrets).is_err());
+    }
+}
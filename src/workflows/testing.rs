@@ -0,0 +1,24 @@
+use git2::{Oid, Repository, Signature};
+use std::path::Path;
+
+/// Writes `contents` to `path` in the working tree of `repo` and commits it on HEAD.
+pub fn commit_file(repo: &Repository, path: &str, contents: &str) -> Oid {
+    let workdir = repo.workdir().unwrap();
+    std::fs::create_dir_all(workdir.join(path).parent().unwrap()).unwrap();
+    std::fs::write(workdir.join(path), contents).unwrap();
+
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new(path)).unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    index.write().unwrap();
+
+    let signature = Signature::now("test", "test@example.com").unwrap();
+    let parents = match repo.head() {
+        Ok(head) => vec![head.peel_to_commit().unwrap()],
+        Err(_) => vec![],
+    };
+    let parents: Vec<_> = parents.iter().collect();
+
+    repo.commit(Some("HEAD"), &signature, &signature, path, &tree, &parents)
+        .unwrap()
+}
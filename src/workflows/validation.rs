@@ -0,0 +1,363 @@
+use k8s_openapi::api::apps::v1::{DaemonSet, Deployment, StatefulSet};
+use k8s_openapi::api::autoscaling::v1::HorizontalPodAutoscaler;
+use k8s_openapi::api::batch::v1::{CronJob, Job};
+use k8s_openapi::api::core::v1::{
+    ConfigMap, Namespace, PersistentVolumeClaim, Secret, Service, ServiceAccount,
+};
+use k8s_openapi::api::networking::v1::{Ingress, NetworkPolicy};
+use k8s_openapi::api::policy::v1::PodDisruptionBudget;
+use k8s_openapi::api::rbac::v1::{ClusterRole, ClusterRoleBinding, Role, RoleBinding};
+use k8s_openapi::Resource;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+use crate::utils::config::ValidationConfig;
+use crate::utils::error::Error;
+
+/// API group of kustomize's own config files, eg. `kustomization.yaml`, which are built by
+/// kustomize rather than applied as objects.
+const KUSTOMIZE_GROUP: &str = "kustomize.config.k8s.io";
+
+/// Whether a YAML document is a kustomize config file, such as a `Kustomization` or a
+/// `Component`.
+pub fn is_kustomize_config(document: &serde_yaml::Value) -> bool {
+    matches!(
+        document.get("apiVersion").and_then(serde_yaml::Value::as_str),
+        Some(api_version) if api_version.split('/').next() == Some(KUSTOMIZE_GROUP)
+    )
+}
+
+/// Whether a YAML document is meant to be a Kubernetes object: it has an `apiVersion` or a
+/// `kind`, and isn't a kustomize config file. Values files and JSON patches, eg., aren't.
+pub fn is_object(document: &serde_yaml::Value) -> bool {
+    let has_type = document.get("apiVersion").is_some() || document.get("kind").is_some();
+    has_type && !is_kustomize_config(document)
+}
+
+/// Checks a document against the schema of its kind, returning why it doesn't match.
+type SchemaCheck = fn(&Value) -> Result<(), String>;
+
+/// Checks that the objects in the rendered YAML files are Kubernetes manifests: every document
+/// with an `apiVersion` or a `kind` has to have both, and a `metadata.name`. kustomize config
+/// files, eg. `kustomization.yaml`, and other documents that aren't objects, such as values
+/// files or JSON patches, are not checked. With `schemas` enabled in the
+/// config, documents of the core kinds in `schema_checks` are checked against their schemas as
+/// well. Every problem is reported in one error, and nothing is committed if there are any.
+///
+/// # Arguments
+/// - `repo_root_path` - Directory the files were rendered into.
+/// - `paths` - The rendered files, relative to `repo_root_path`. Other than `.yaml` and `.yml`
+///   files are not checked.
+/// - `config` - Which checks to make.
+pub fn validate_rendered(
+    repo_root_path: &Path,
+    paths: &[PathBuf],
+    config: &ValidationConfig,
+) -> Result<(), Error> {
+    let mut problems = Vec::new();
+
+    for path in paths {
+        let is_yaml = matches!(
+            path.extension().and_then(|extension| extension.to_str()),
+            Some("yaml") | Some("yml")
+        );
+        if !is_yaml {
+            continue;
+        }
+
+        let manifest = std::fs::read_to_string(repo_root_path.join(path))?;
+        problems.extend(
+            validate_manifest(&manifest, config)
+                .into_iter()
+                .map(|problem| format!("{}: {}", path.display(), problem)),
+        );
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::ValidationError(problems.join("\n")))
+    }
+}
+
+/// Returns the problems of the documents in a YAML stream, prefixed with the (1-based) number of
+/// the document.
+fn validate_manifest(manifest: &str, config: &ValidationConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    for (index, document) in serde_yaml::Deserializer::from_str(manifest).enumerate() {
+        let document = match serde_yaml::Value::deserialize(document) {
+            // empty documents, eg. of a template that renders nothing for a cluster
+            Ok(serde_yaml::Value::Null) => continue,
+            Ok(document) => document,
+            Err(err) => {
+                problems.push(format!("document {}: invalid YAML: {}", index + 1, err));
+                // the rest of the stream can't be parsed after a syntax error
+                break;
+            }
+        };
+
+        if let Err(problem) = validate_document(&document, config) {
+            problems.push(format!("document {}: {}", index + 1, problem));
+        }
+    }
+
+    problems
+}
+
+/// Checks one document, see `validate_rendered`.
+fn validate_document(
+    document: &serde_yaml::Value,
+    config: &ValidationConfig,
+) -> Result<(), String> {
+    if !is_object(document) {
+        return Ok(());
+    }
+
+    let document: Value = serde_json::to_value(document)
+        .map_err(|err| format!("can't be represented as JSON: {}", err))?;
+
+    let field = |path: &[&str]| {
+        path.iter()
+            .try_fold(&document, |value, key| value.get(key))
+            .and_then(Value::as_str)
+            .filter(|value| !value.is_empty())
+    };
+    let api_version = field(&["apiVersion"]).ok_or("missing apiVersion")?;
+    let kind = field(&["kind"]).ok_or("missing kind")?;
+    let name =
+        field(&["metadata", "name"]).ok_or_else(|| format!("{} is missing metadata.name", kind))?;
+
+    if config.schemas {
+        let check = schema_checks()
+            .iter()
+            .find(|(check_api_version, check_kind, _)| {
+                *check_api_version == api_version && *check_kind == kind
+            })
+            .map(|(_, _, check)| *check);
+
+        if let Some(check) = check {
+            check(&document).map_err(|problem| format!("{} {}: {}", kind, name, problem))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The kinds whose schemas are bundled, by `apiVersion` and `kind`.
+fn schema_checks() -> Vec<(&'static str, &'static str, SchemaCheck)> {
+    fn schema<K: Resource + DeserializeOwned + Serialize>(
+    ) -> (&'static str, &'static str, SchemaCheck) {
+        (K::API_VERSION, K::KIND, check_schema::<K>)
+    }
+
+    vec![
+        schema::<ConfigMap>(),
+        schema::<Secret>(),
+        schema::<Service>(),
+        schema::<ServiceAccount>(),
+        schema::<Namespace>(),
+        schema::<PersistentVolumeClaim>(),
+        schema::<Deployment>(),
+        schema::<StatefulSet>(),
+        schema::<DaemonSet>(),
+        schema::<Job>(),
+        schema::<CronJob>(),
+        schema::<Ingress>(),
+        schema::<NetworkPolicy>(),
+        schema::<PodDisruptionBudget>(),
+        schema::<HorizontalPodAutoscaler>(),
+        schema::<Role>(),
+        schema::<RoleBinding>(),
+        schema::<ClusterRole>(),
+        schema::<ClusterRoleBinding>(),
+    ]
+}
+
+/// Checks a document against the schema of `K`, which `k8s-openapi` generates from the
+/// Kubernetes OpenAPI definitions: it has to deserialize into `K`, and must not have fields `K`
+/// doesn't know, which deserializing ignores. Note that quantities, eg. `cpu: "1"`, have to be
+/// strings to deserialize.
+fn check_schema<K: DeserializeOwned + Serialize>(document: &Value) -> Result<(), String> {
+    let resource: K = serde_json::from_value(document.clone()).map_err(|err| err.to_string())?;
+    // resources always serialize
+    let known = serde_json::to_value(resource).unwrap();
+
+    let mut unknown = Vec::new();
+    unknown_fields(document, &known, "", &mut unknown);
+
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("unknown fields {}", unknown.join(", ")))
+    }
+}
+
+/// Adds the paths of the fields of `value` that `known` lacks to `unknown`. Null fields are
+/// ignored, as deserializing drops them too.
+fn unknown_fields(value: &Value, known: &Value, path: &str, unknown: &mut Vec<String>) {
+    match (value, known) {
+        (Value::Object(fields), Value::Object(known_fields)) => {
+            for (key, field) in fields {
+                let field_path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                match known_fields.get(key) {
+                    Some(known_field) => unknown_fields(field, known_field, &field_path, unknown),
+                    None if !field.is_null() => unknown.push(field_path),
+                    None => {}
+                }
+            }
+        }
+        (Value::Array(items), Value::Array(known_items)) => {
+            for (index, (item, known_item)) in items.iter().zip(known_items).enumerate() {
+                unknown_fields(item, known_item, &format!("{}[{}]", path, index), unknown);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::path::{Path, PathBuf};
+    use tempfile::tempdir;
+
+    use super::validate_rendered;
+    use crate::utils::config::ValidationConfig;
+    use crate::workflows::template::render;
+
+    #[test]
+    fn validates_rendered_manifests() {
+        let repo_dir = tempdir().unwrap();
+        let write = |path: &str, contents: &str| {
+            std::fs::write(repo_dir.path().join(path), contents).unwrap();
+            PathBuf::from(path)
+        };
+
+        let valid = write(
+            "deployment.yaml",
+            "\
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: cluster-agent
+  creationTimestamp: null
+spec:
+  selector:
+    matchLabels:
+      app: cluster-agent
+  template:
+    metadata:
+      labels:
+        app: cluster-agent
+    spec:
+      containers:
+      - name: cluster-agent
+        image: cluster-agent:1.0
+        resources:
+          limits:
+            cpu: 500m
+---
+# nothing rendered
+---
+apiVersion: source.toolkit.fluxcd.io/v1beta1
+kind: GitRepository
+metadata:
+  name: cluster-agent
+spec:
+  anything: goes
+",
+        );
+        let readme = write("README.md", "not: [yaml");
+        // values files and JSON patches aren't objects
+        let config = write(
+            "config.yaml",
+            "replicas: 2\n---\n- op: replace\n  path: /spec/replicas\n  value: 3\n",
+        );
+        let schemas = ValidationConfig { schemas: true };
+
+        validate_rendered(repo_dir.path(), &[valid.clone(), readme, config], &schemas).unwrap();
+
+        let invalid = write(
+            "invalid.yaml",
+            "\
+apiVersion: v1
+kind: ConfigMap
+metadata:
+  namespace: default
+---
+kind: Secret
+",
+        );
+        let broken = write("broken.yml", "apiVersion: v1\nkind: [ConfigMap\n");
+        let typo = write(
+            "typo.yaml",
+            "\
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: cluster-agent
+spec:
+  replicas: two
+---
+apiVersion: v1
+kind: Service
+metadata:
+  name: cluster-agent
+spec:
+  port:
+  - port: 80
+",
+        );
+
+        let err = validate_rendered(
+            repo_dir.path(),
+            &[valid.clone(), invalid.clone(), broken.clone()],
+            &ValidationConfig::default(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("invalid.yaml: document 1: ConfigMap is missing metadata.name"));
+        assert!(err.contains("invalid.yaml: document 2: missing apiVersion"));
+        assert!(err.contains("broken.yml: document 1: invalid YAML"));
+
+        // schemas are only checked if enabled
+        validate_rendered(
+            repo_dir.path(),
+            std::slice::from_ref(&typo),
+            &ValidationConfig::default(),
+        )
+        .unwrap();
+
+        let err = validate_rendered(repo_dir.path(), &[valid, typo], &schemas)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("typo.yaml: document 1: Deployment cluster-agent: invalid type"));
+        assert!(
+            err.contains("typo.yaml: document 2: Service cluster-agent: unknown fields spec.port")
+        );
+        assert!(!err.contains("deployment.yaml"));
+    }
+
+    #[test]
+    fn validates_kustomize_templates() {
+        let repo_dir = tempdir().unwrap();
+        let values = json!({ "CLUSTER_NAME": "ours" });
+        let paths = render(
+            Path::new("fixtures/template"),
+            repo_dir.path(),
+            Path::new("dev"),
+            &values,
+            false,
+        )
+        .unwrap();
+
+        validate_rendered(repo_dir.path(), &paths, &ValidationConfig { schemas: true }).unwrap();
+    }
+}
@@ -1,15 +1,89 @@
-/*
-use kube::Error;
+use async_trait::async_trait;
+use git2::Oid;
 
 use crate::models::application::Application;
 use crate::models::assignment::ApplicationAssignment;
+use crate::models::cluster::Cluster;
+use crate::models::environment::ApplicationEnvironment;
+use crate::models::template::ApplicationTemplate;
+use crate::utils::error::Error;
+use crate::workflows::review::PendingReview;
+use crate::workflows::template::ResolvedValues;
 
-// TODO: traits don't support async yet :(
-pub trait Workflow {
-    fn create_deployment(
-        &self,
-        application: &Application,
-        application_assignment: &ApplicationAssignment,
-    ) -> Result<(), Error>;
+/// Reconciler used for applications that don't name one.
+pub const DEFAULT_RECONCILER: &str = "gitops";
+
+/// Everything an `ApplicationAssignment` is deployed from.
+#[derive(Clone)]
+pub struct Deployment {
+    pub application: Application,
+    pub template: ApplicationTemplate,
+    pub environment: ApplicationEnvironment,
+    pub assignment: ApplicationAssignment,
+    /// The `Cluster` resource assigned to, if one exists.
+    pub cluster: Option<Cluster>,
+    /// Values taken from the `valuesFrom` ConfigMaps and Secrets. Only resolved for deployments
+    /// that are rendered.
+    pub resolved_values: ResolvedValues,
+}
+
+/// Outcome of deploying an `ApplicationAssignment`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DeploymentResult {
+    /// Commit of the template repo that was rendered.
+    pub template_commit: Oid,
+    /// Commit in the GitOps repo that contains the rendered assignment, for backends that commit
+    /// to one.
+    pub commit: Option<Oid>,
+    /// Whether this deployment changed anything. When the rendered output matches what is already
+    /// in the GitOps repo nothing is committed, and `commit` is the existing tip.
+    pub changed: bool,
+    /// Set in pull request mode while `commit` waits for review on its own branch.
+    pub review: Option<PendingReview>,
+    /// Objects applied to the cluster, for backends that apply them directly. Passed back to the
+    /// backend through the assignment's status, so it can check they are still in place.
+    pub inventory: Option<Vec<String>>,
+}
+
+/// Where a previously deployed `ApplicationAssignment` stands, as seen by its backend.
+#[derive(Debug, PartialEq, Clone)]
+pub enum DeploymentStatus {
+    /// The rendered assignment is in place.
+    Deployed,
+    /// The rendered assignment is waiting for review on `branch`.
+    PendingReview { branch: String },
+    /// The rendered assignment is gone, eg. removed by hand, and has to be deployed again.
+    Missing,
+}
+
+/// Runs `f` on the blocking thread pool and waits for it, so that blocking work, such as git
+/// fetches and pushes, doesn't stall the other tasks of the runtime, like the controllers and
+/// their reflectors.
+pub async fn blocking<T, F>(f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+}
+
+/// A backend that reconciles `ApplicationAssignment`s, such as committing rendered templates to a
+/// GitOps repo. Applications pick their backend by name through their `reconciler` field.
+#[async_trait]
+pub trait Workflow: Send + Sync {
+    /// Deploys an assignment for the first time.
+    async fn create(&self, deployment: &Deployment) -> Result<DeploymentResult, Error>;
+
+    /// Deploys an assignment that was deployed before, eg. after it or one of the resources it is
+    /// deployed from changed.
+    async fn update(&self, deployment: &Deployment) -> Result<DeploymentResult, Error>;
+
+    /// Removes everything deployed for an assignment. Returns the pending review of the removal
+    /// if it has to be reviewed first.
+    async fn delete(&self, deployment: &Deployment) -> Result<Option<PendingReview>, Error>;
+
+    /// Checks whether what was deployed for an assignment is still in place.
+    async fn status(&self, deployment: &Deployment) -> Result<DeploymentStatus, Error>;
 }
-*/